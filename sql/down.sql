@@ -1,5 +1,32 @@
+DROP TABLE oidc_login;
+DROP TABLE user_identity;
+DROP TABLE recovery_code;
+DROP TABLE failed_login;
+DROP TABLE user_token;
+DROP TABLE live_update;
+DROP TABLE webhook_delivery;
+DROP TABLE webhook_subscription;
+DROP TABLE webhook;
+DROP TABLE notification_preference;
+DROP TABLE comment_mention;
+DROP TABLE comment;
+DROP TABLE poll_answer;
+DROP TABLE poll_option;
+DROP TABLE poll;
+DROP TABLE date_vote;
+DROP TABLE date_slot;
+DROP TABLE date_poll;
+DROP TABLE announcement_receipt;
+DROP TABLE notification;
+DROP TABLE announcement;
+DROP TABLE invitation;
+DROP TABLE template_requirement;
+DROP TABLE template;
+DROP TABLE preference;
+DROP TABLE assignment;
 DROP TABLE fullfillment;
 DROP TABLE requirement;
+DROP TABLE category;
 DROP TABLE participant;
 DROP TABLE event;
 DROP TABLE user;
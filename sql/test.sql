@@ -1,21 +1,58 @@
+DROP TABLE oidc_login;
+DROP TABLE user_identity;
+DROP TABLE recovery_code;
+DROP TABLE failed_login;
+DROP TABLE user_token;
+DROP TABLE live_update;
+DROP TABLE webhook_delivery;
+DROP TABLE webhook_subscription;
+DROP TABLE webhook;
+DROP TABLE notification_preference;
+DROP TABLE comment_mention;
+DROP TABLE comment;
+DROP TABLE poll_answer;
+DROP TABLE poll_option;
+DROP TABLE poll;
+DROP TABLE date_vote;
+DROP TABLE date_slot;
+DROP TABLE date_poll;
+DROP TABLE announcement_receipt;
+DROP TABLE notification;
+DROP TABLE announcement;
+DROP TABLE invitation;
+DROP TABLE template_requirement;
+DROP TABLE template;
+DROP TABLE preference;
+DROP TABLE assignment;
 DROP TABLE fullfillment;
 DROP TABLE requirement;
+DROP TABLE category;
 DROP TABLE participant;
 DROP TABLE event;
 DROP TABLE user;
 
 CREATE TABLE IF NOT EXISTS user (
     id INTEGER PRIMARY KEY,
-    username TEXT NOT NULL UNIQUE,
+    username TEXT NOT NULL UNIQUE COLLATE NOCASE,
     password TEXT NOT NULL,
-    salt TEXT NOT NULL
+    salt TEXT NOT NULL,
+    password_scheme TEXT NOT NULL DEFAULT 'client_sha256',
+    email TEXT,
+    email_notifications BOOLEAN NOT NULL DEFAULT TRUE,
+    email_verified_at INTEGER,
+    sessions_revoked_at INTEGER,
+    failed_logins INTEGER NOT NULL DEFAULT 0,
+    last_failed_login_at INTEGER,
+    totp_secret TEXT,
+    totp_enabled_at INTEGER,
+    totp_last_step INTEGER
 );
 
 CREATE TABLE IF NOT EXISTS event (
     id INTEGER PRIMARY KEY,
     name TEXT NOT NULL,
     description TEXT,
-    time INTEGER NOT NULL,
+    time INTEGER,
     creator INTEGER NOT NULL,
     FOREIGN KEY(creator) REFERENCES user(id)
 );
@@ -23,28 +60,316 @@ CREATE TABLE IF NOT EXISTS event (
 CREATE TABLE IF NOT EXISTS participant (
     user INTEGER NOT NULL,
     event INTEGER NOT NULL,
+    share INTEGER NOT NULL DEFAULT 1,
     PRIMARY KEY(user, event),
     FOREIGN KEY(event) REFERENCES event(id),
     FOREIGN KEY(user) REFERENCES user(id)
 );
 
+CREATE TABLE IF NOT EXISTS category (
+    id INTEGER PRIMARY KEY,
+    event INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    FOREIGN KEY(event) REFERENCES event(id)
+);
+
 CREATE TABLE IF NOT EXISTS requirement (
     id INTEGER PRIMARY KEY,
     name TEXT NOT NULL,
     description TEXT,
     event INTEGER NOT NULL,
     size INTEGER NOT NULL DEFAULT 1,
-    FOREIGN KEY(event) REFERENCES event(id)
+    position INTEGER NOT NULL DEFAULT 0,
+    category INTEGER,
+    priority TEXT NOT NULL DEFAULT 'must_have',
+    deadline INTEGER,
+    reminded_at INTEGER,
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(category) REFERENCES category(id)
 );
 
 CREATE TABLE IF NOT EXISTS fullfillment (
     user INTEGER NOT NULL,
     requirement INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pledged',
+    note TEXT,
+    pledged_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    confirmed_at INTEGER,
+    delivered_at INTEGER,
+    cancelled_at INTEGER,
+    cost_amount INTEGER,
+    cost_currency TEXT,
     PRIMARY KEY(user, requirement),
     FOREIGN KEY(user) REFERENCES user(id),
     FOREIGN KEY(requirement) REFERENCES requirement(id)
 );
 
+CREATE TABLE IF NOT EXISTS assignment (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    requirement INTEGER NOT NULL,
+    assigned_by INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    answered_at INTEGER,
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(requirement) REFERENCES requirement(id),
+    FOREIGN KEY(assigned_by) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS preference (
+    user INTEGER NOT NULL,
+    requirement INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    PRIMARY KEY(user, requirement),
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(requirement) REFERENCES requirement(id)
+);
+
+CREATE TABLE IF NOT EXISTS template (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    creator INTEGER NOT NULL,
+    FOREIGN KEY(creator) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS template_requirement (
+    id INTEGER PRIMARY KEY,
+    template INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT,
+    size INTEGER NOT NULL DEFAULT 1,
+    FOREIGN KEY(template) REFERENCES template(id)
+);
+
+CREATE TABLE IF NOT EXISTS invitation (
+    user INTEGER NOT NULL,
+    event INTEGER NOT NULL,
+    invited_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY(user, event),
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(invited_by) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS notification (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    event INTEGER,
+    requirement INTEGER,
+    announcement INTEGER,
+    created_at INTEGER NOT NULL,
+    read_at INTEGER,
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(requirement) REFERENCES requirement(id),
+    FOREIGN KEY(announcement) REFERENCES announcement(id)
+);
+
+CREATE TABLE IF NOT EXISTS announcement (
+    id INTEGER PRIMARY KEY,
+    event INTEGER NOT NULL,
+    author INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(author) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS announcement_receipt (
+    announcement INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    read_at INTEGER,
+    PRIMARY KEY(announcement, user),
+    FOREIGN KEY(announcement) REFERENCES announcement(id),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS notification_preference (
+    user INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    PRIMARY KEY(user, kind),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS date_poll (
+    id INTEGER PRIMARY KEY,
+    event INTEGER NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    finalized_slot INTEGER,
+    FOREIGN KEY(event) REFERENCES event(id)
+);
+
+CREATE TABLE IF NOT EXISTS date_slot (
+    id INTEGER PRIMARY KEY,
+    poll INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    FOREIGN KEY(poll) REFERENCES date_poll(id)
+);
+
+CREATE TABLE IF NOT EXISTS date_vote (
+    slot INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    answer TEXT NOT NULL,
+    PRIMARY KEY(slot, user),
+    FOREIGN KEY(slot) REFERENCES date_slot(id),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS poll (
+    id INTEGER PRIMARY KEY,
+    event INTEGER NOT NULL,
+    question TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    anonymous BOOLEAN NOT NULL DEFAULT FALSE,
+    closes_at INTEGER,
+    closed_at INTEGER,
+    created_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(created_by) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS poll_option (
+    id INTEGER PRIMARY KEY,
+    poll INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    FOREIGN KEY(poll) REFERENCES poll(id)
+);
+
+CREATE TABLE IF NOT EXISTS poll_answer (
+    id INTEGER PRIMARY KEY,
+    poll INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    option INTEGER,
+    text TEXT,
+    FOREIGN KEY(poll) REFERENCES poll(id),
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(option) REFERENCES poll_option(id)
+);
+
+CREATE TABLE IF NOT EXISTS comment (
+    id INTEGER PRIMARY KEY,
+    event INTEGER NOT NULL,
+    requirement INTEGER,
+    parent INTEGER,
+    user INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    edited_at INTEGER,
+    deleted_at INTEGER,
+    FOREIGN KEY(event) REFERENCES event(id),
+    FOREIGN KEY(requirement) REFERENCES requirement(id),
+    FOREIGN KEY(parent) REFERENCES comment(id),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS comment_mention (
+    comment INTEGER NOT NULL,
+    user INTEGER NOT NULL,
+    PRIMARY KEY(comment, user),
+    FOREIGN KEY(comment) REFERENCES comment(id),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS webhook (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    event INTEGER,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    failures INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(user) REFERENCES user(id),
+    FOREIGN KEY(event) REFERENCES event(id)
+);
+
+CREATE TABLE IF NOT EXISTS webhook_subscription (
+    webhook INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    PRIMARY KEY(webhook, kind),
+    FOREIGN KEY(webhook) REFERENCES webhook(id)
+);
+
+CREATE TABLE IF NOT EXISTS webhook_delivery (
+    id INTEGER PRIMARY KEY,
+    webhook INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    status_code INTEGER,
+    error TEXT,
+    created_at INTEGER NOT NULL,
+    delivered_at INTEGER,
+    FOREIGN KEY(webhook) REFERENCES webhook(id)
+);
+
+CREATE TABLE IF NOT EXISTS live_update (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    data TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(event) REFERENCES event(id)
+);
+
+CREATE TABLE IF NOT EXISTS user_token (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS failed_login (
+    id INTEGER PRIMARY KEY,
+    ip TEXT NOT NULL,
+    username TEXT NOT NULL COLLATE NOCASE,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS recovery_code (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at INTEGER,
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS user_identity (
+    id INTEGER PRIMARY KEY,
+    user INTEGER NOT NULL,
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    email TEXT,
+    created_at INTEGER NOT NULL,
+    UNIQUE(provider, subject),
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+CREATE TABLE IF NOT EXISTS oidc_login (
+    id INTEGER PRIMARY KEY,
+    state TEXT NOT NULL UNIQUE,
+    provider TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    link_user INTEGER,
+    browser_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(link_user) REFERENCES user(id)
+);
+
 INSERT INTO user (id, username, password, salt) VALUES (1, 'username1', 'd332ef80281d79e3dd4c5f0ea7e782fc689842e70cf606b84e8bb9fb676626e', 'kE(mL@^0');
 INSERT INTO user (id, username, password, salt) VALUES (2, 'username2', '11c3538c6236c9e61554df8c32662206c4220e8aa3da8dfbb384fea880e', 'OO4tO7pB');
 INSERT INTO user (id, username, password, salt) VALUES (3, 'username3', 'db6a21ccba5441fad1eecb44a8f3ff73f16dd4ba3c57aa1ef8cc168642ec0e1', 'E)Qpt2ry');
@@ -63,5 +388,5 @@ INSERT INTO requirement (id, name, description, event, size) VALUES (1, "req1",
 INSERT INTO requirement (id, name, description, event, size) VALUES (2, "req2", "req2-desc", 1, 1);
 INSERT INTO requirement (id, name, description, event, size) VALUES (3, "req3", "req3-desc", 2, 1);
 INSERT INTO requirement (id, name, description, event, size) VALUES (4, "req1", "req4-desc", 4, 3);
-INSERT INTO fullfillment (user, requirement) VALUES (4, 1);
-INSERT INTO fullfillment (user, requirement) VALUES (2, 3);
+INSERT INTO fullfillment (user, requirement, pledged_at) VALUES (4, 1, 1664841600);
+INSERT INTO fullfillment (user, requirement, pledged_at) VALUES (2, 3, 1664841600);
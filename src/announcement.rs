@@ -0,0 +1,260 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_action_authorization, event_member_authorization}, user::User, notification::{NotifierState, NotificationKind, insert_db_notification}};
+
+#[derive(Serialize)]
+pub struct Announcement {
+  id: i64,
+  event: i64,
+  author: User,
+  body: String,
+  created_at: i64,
+  /// only filled out for the organizer
+  receipts: Option<Vec<Receipt>>,
+}
+
+#[derive(Serialize)]
+pub struct Receipt {
+  user: User,
+  read_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAnnouncement {
+  body: String,
+}
+
+async fn get_db_announcements(pool: &DbState, event_id: i64, with_receipts: bool) -> Result<Vec<Announcement>, AppError> {
+  let mut announcements: Vec<Announcement> = sqlx::query!(
+      r#"
+  SELECT announcement.id as "id!", announcement.body, announcement.created_at, user.id as "user_id!", user.username
+  FROM announcement
+  JOIN user ON announcement.author = user.id
+  WHERE announcement.event = ?1
+  ORDER BY announcement.created_at DESC, announcement.id DESC
+      "#,
+      event_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|a| Announcement {
+      id: a.id,
+      event: event_id,
+      author: User {
+        id: a.user_id,
+        username: a.username,
+      },
+      body: a.body,
+      created_at: a.created_at,
+      receipts: if with_receipts { Some(vec![]) } else { None },
+    })
+    .collect();
+
+  if with_receipts {
+    // receipts are kept apart from the notifications, so they don't depend on the notification preferences
+    let receipts = sqlx::query!(
+        r#"
+  SELECT announcement_receipt.announcement, announcement_receipt.read_at, user.id as "user_id!", user.username
+  FROM announcement_receipt
+  JOIN user ON announcement_receipt.user = user.id
+  WHERE announcement_receipt.announcement in (SELECT id FROM announcement WHERE event = ?1)
+  ORDER BY user.id
+        "#,
+        event_id
+      )
+      .fetch_all(pool)
+      .await?;
+    for r in receipts {
+      if let Some(receipts) = announcements.iter_mut().find(|a| a.id == r.announcement).and_then(|a| a.receipts.as_mut()) {
+        receipts.push(Receipt {
+          user: User {
+            id: r.user_id,
+            username: r.username,
+          },
+          read_at: r.read_at,
+        });
+      }
+    }
+  }
+
+  Ok(announcements)
+}
+
+pub async fn create(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<CreateAnnouncement>,
+  Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Announcement>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot post announcement for event that user doesn't own").await?;
+  if payload.body.trim().is_empty() {
+    return Err(AppError::BadRequest(String::from("announcement cannot be empty")));
+  }
+
+  let created_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO announcement ( event, author, body, created_at )
+  VALUES ( ?1, ?2, ?3, ?4 )
+      "#,
+      event_id, auth_userid, payload.body, created_at
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+
+  let participants = sqlx::query!("SELECT user FROM participant WHERE event = ?1 AND user != ?2 ORDER BY user", event_id, auth_userid)
+    .fetch_all(&mut tx)
+    .await?;
+  let mut notifications = vec![];
+  for p in participants {
+    let _ = sqlx::query!("INSERT INTO announcement_receipt ( announcement, user ) VALUES ( ?1, ?2 )", id, p.user)
+      .execute(&mut tx)
+      .await?;
+    notifications.extend(insert_db_notification(&mut tx, p.user, NotificationKind::Announcement, payload.body.clone(), Some(event_id), None, Some(id)).await?);
+  }
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  let announcement = get_db_announcements(&pool, event_id, true)
+    .await?
+    .into_iter()
+    .find(|a| a.id == id)
+    .ok_or_else(|| AppError::NotFound(format!("Cannot find announcement: {id}")))?;
+  Ok((StatusCode::CREATED, Json(announcement)))
+}
+
+pub async fn all(
+  Path(event_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Announcement>>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get announcements of event that user doesn't take part in").await?;
+  let organizer = event_action_authorization(&pool, event_id, auth_userid, "").await.is_ok();
+
+  let announcements = get_db_announcements(&pool, event_id, organizer).await?;
+  Ok((StatusCode::OK, Json(announcements)))
+}
+
+pub async fn read(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  let read_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let updated = sqlx::query!(
+      r#"
+  UPDATE announcement_receipt SET read_at = COALESCE(read_at, ?3)
+  WHERE announcement = ?1 AND user = ?2
+      "#,
+      id, auth_userid, read_at
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+  if updated == 0 {
+    return Err(AppError::NotFound(format!("Cannot find announcement: {id}")));
+  }
+  let _ = sqlx::query!(
+      r#"
+  UPDATE notification SET read_at = COALESCE(read_at, ?3)
+  WHERE announcement = ?1 AND user = ?2
+      "#,
+      id, auth_userid, read_at
+    )
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  #[tokio::test]
+  async fn create() {
+    let (app, pool) = setup_with_data().await;
+    let body_json = json!({
+      "body": "location changed to the park",
+    });
+
+    let response = test_api(app, "/event/1/announcements", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await.unwrap();
+    assert_eq!(response["receipts"], json!([
+      { "user": { "id": 2, "username": "username2" }, "read_at": null },
+      { "user": { "id": 3, "username": "username3" }, "read_at": null }
+    ]));
+
+    let results = sqlx::query!("select user from notification where kind = 'announcement' and announcement = 1")
+      .fetch_all(&pool)
+      .await
+      .unwrap();
+    assert_eq!(results.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn create_for_another() {
+    let (app, _) = setup_with_data().await;
+    let body_json = json!({
+      "body": "location changed to the park",
+    });
+
+    let _ = test_api(app, "/event/1/announcements", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+  }
+
+  #[tokio::test]
+  async fn read() {
+    let (app, _) = setup_with_data().await;
+    let body_json = json!({
+      "body": "location changed to the park",
+    });
+    let _ = test_api(app.clone(), "/event/1/announcements", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+
+    let _ = test_api(app.clone(), "/announcement/1/read", http::Method::POST, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+    let response = test_api(app.clone(), "/event/1/announcements", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    assert!(response[0]["receipts"][0]["read_at"].is_i64());
+    assert!(response[0]["receipts"][1]["read_at"].is_null());
+
+    let response = test_api(app, "/event/1/announcements", http::Method::GET, None, StatusCode::OK, Some(("3", "username3"))).await.unwrap();
+    assert!(response[0]["receipts"].is_null());
+  }
+
+  #[tokio::test]
+  async fn read_with_disabled_notifications() {
+    let (app, pool) = setup_with_data().await;
+    sqlx::query("INSERT INTO notification_preference (user, kind, enabled) VALUES (2, 'announcement', FALSE)")
+      .execute(&pool)
+      .await
+      .unwrap();
+    let body_json = json!({
+      "body": "location changed to the park",
+    });
+
+    let response = test_api(app.clone(), "/event/1/announcements", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await.unwrap();
+    assert_eq!(response["receipts"].as_array().unwrap().len(), 2);
+
+    let _ = test_api(app.clone(), "/announcement/1/read", http::Method::POST, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+    let response = test_api(app, "/event/1/announcements", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    assert_eq!(response[0]["receipts"][0]["user"]["id"], 2);
+    assert!(response[0]["receipts"][0]["read_at"].is_i64());
+  }
+}
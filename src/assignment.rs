@@ -0,0 +1,414 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use chrono::{Utc, Duration};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, db_modeling, utils::AppReponse, auth::{UserAuth, user_action_authorization, requirement_action_authorization}, user::User, fullfillment, webhook::WebhookQueue, live::LiveUpdates};
+
+pub const DEFAULT_EXPIRATION_HOURS: i64 = 72;
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum AssignmentStatus {
+  Pending,
+  Accepted,
+  Declined,
+  Expired,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAssignment {
+  requirement: i64,
+  user: i64,
+  expires_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AnswerAssignment {
+  status: AssignmentStatus,
+}
+
+#[derive(Serialize)]
+pub struct Assignment {
+  id: i64,
+  requirement: i64,
+  user: i64,
+  assigned_by: i64,
+  status: AssignmentStatus,
+  created_at: i64,
+  expires_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct PendingAssignment {
+  id: i64,
+  requirement: i64,
+  requirement_name: String,
+  event: i64,
+  assigned_by: User,
+  created_at: i64,
+  expires_at: i64,
+}
+
+pub async fn expire_db_assignments(pool: &DbState) -> Result<(), AppError> {
+  let now = Utc::now().timestamp();
+  let _ = sqlx::query!(
+      r#"
+  UPDATE assignment SET status = 'expired', answered_at = ?1
+  WHERE status = 'pending' AND expires_at <= ?1
+      "#,
+      now
+    )
+    .execute(pool)
+    .await?;
+
+  Ok(())
+}
+
+pub async fn create(
+  Json(payload): Json<CreateAssignment>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Assignment>> {
+  let CreateAssignment { requirement, user, expires_at } = payload;
+  requirement_action_authorization(&pool, requirement, auth_userid, "cannot assign requirement for event that user doesn't own").await?;
+
+  let participant = sqlx::query!(
+      r#"
+  SELECT user FROM participant
+  WHERE user = ?1 AND event = (SELECT event FROM requirement WHERE id = ?2)
+      "#,
+      user, requirement
+    )
+    .fetch_optional(&pool)
+    .await?;
+  if participant.is_none() {
+    return Err(AppError::BadRequest(format!("User {user} is not participant of the event")));
+  }
+
+  expire_db_assignments(&pool).await?;
+  let pending = sqlx::query!(
+      r#"
+  SELECT id FROM assignment
+  WHERE user = ?1 AND requirement = ?2 AND status = 'pending'
+      "#,
+      user, requirement
+    )
+    .fetch_optional(&pool)
+    .await?;
+  if pending.is_some() {
+    return Err(AppError::BadRequest(format!("User {user} already has pending assignment for requirement: {requirement}")));
+  }
+
+  let created_at = Utc::now().timestamp();
+  let expires_at = expires_at.unwrap_or_else(|| (Utc::now() + Duration::hours(DEFAULT_EXPIRATION_HOURS)).timestamp());
+  if expires_at <= created_at {
+    return Err(AppError::BadRequest(String::from("expiration must be in the future")));
+  }
+
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO assignment ( requirement, user, assigned_by, created_at, expires_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
+      "#,
+      requirement, user, auth_userid, created_at, expires_at
+    )
+    .execute(&pool)
+    .await?
+    .last_insert_rowid();
+
+  let assignment = Assignment {
+    id,
+    requirement,
+    user,
+    assigned_by: auth_userid,
+    status: AssignmentStatus::Pending,
+    created_at,
+    expires_at,
+  };
+
+  Ok((StatusCode::CREATED, Json(assignment)))
+}
+
+pub async fn pending(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<PendingAssignment>>> {
+  user_action_authorization(id, auth_userid, "cannot get assignments of another user")?;
+  expire_db_assignments(&pool).await?;
+
+  let assignments = sqlx::query!(
+      r#"
+  SELECT assignment.id as "id!", assignment.requirement, requirement.name, requirement.event,
+    assignment.assigned_by, user.username, assignment.created_at, assignment.expires_at
+  FROM assignment
+  JOIN requirement ON assignment.requirement = requirement.id
+  JOIN user ON assignment.assigned_by = user.id
+  WHERE assignment.user = ?1 AND assignment.status = 'pending'
+  ORDER BY assignment.expires_at
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|a| PendingAssignment {
+      id: a.id,
+      requirement: a.requirement,
+      requirement_name: a.name,
+      event: a.event,
+      assigned_by: User {
+        id: a.assigned_by,
+        username: a.username,
+      },
+      created_at: a.created_at,
+      expires_at: a.expires_at,
+    })
+    .collect();
+
+  Ok((StatusCode::OK, Json(assignments)))
+}
+
+pub async fn answer(
+  Path(id): Path<i64>,
+  Json(payload): Json<AnswerAssignment>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  expire_db_assignments(&pool).await?;
+
+  let assignment = sqlx::query!(
+      r#"
+  SELECT user, requirement, status as "status: AssignmentStatus" FROM assignment
+  WHERE id = ?1
+      "#,
+      id
+    )
+    .fetch_optional(&pool)
+    .await?;
+  let assignment = match assignment {
+    Some(a) => a,
+    None => return Err(AppError::NotFound(format!("Cannot find assignment: {id}"))),
+  };
+  user_action_authorization(assignment.user, auth_userid, "cannot answer assignment of another user")?;
+
+  if assignment.status != AssignmentStatus::Pending {
+    return Err(AppError::BadRequest(format!("assignment is already {:?}", assignment.status)));
+  }
+  if !matches!(payload.status, AssignmentStatus::Accepted | AssignmentStatus::Declined) {
+    return Err(AppError::BadRequest(String::from("assignment can be only accepted or declined")));
+  }
+
+  let mut tx = pool.begin().await?;
+  let mut pledged = vec![];
+  if payload.status == AssignmentStatus::Accepted {
+    let pledged_at = db_modeling::insert_db_fullfillment(&mut tx, assignment.requirement, assignment.user, &None, &None).await?;
+    pledged.push((assignment.requirement, assignment.user, pledged_at));
+  }
+
+  let answered_at = Utc::now().timestamp();
+  let _ = sqlx::query!(
+      r#"
+  UPDATE assignment SET status = ?2, answered_at = ?3
+  WHERE id = ?1
+      "#,
+      id, payload.status, answered_at
+    )
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+  fullfillment::dispatch_pledged(&pool, &webhooks, &live, &pledged).await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  async fn insert_assignment(pool: &DbState, user: i64, requirement: i64, expires_at: i64) -> i64 {
+    sqlx::query("INSERT INTO assignment (user, requirement, assigned_by, created_at, expires_at) VALUES (?1, ?2, 1, 1664841600, ?3)")
+      .bind(user)
+      .bind(requirement)
+      .bind(expires_at)
+      .execute(pool)
+      .await
+      .unwrap()
+      .last_insert_rowid()
+  }
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "requirement": 2,
+        "user": 3,
+      });
+
+      let response = test_api(app, "/assignment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response["status"], "pending");
+      assert_eq!(response["assigned_by"], 1);
+
+      let results = sqlx::query!("select user, requirement from assignment")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].user, 3);
+      assert_eq!(results[0].requirement, 2);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "requirement": 2,
+        "user": 3,
+      });
+
+      let _ = test_api(app, "/assignment", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+
+    #[tokio::test]
+    async fn not_participant() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "requirement": 2,
+        "user": 6,
+      });
+
+      let _ = test_api(app, "/assignment", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+  }
+
+  mod pending {
+    use super::*;
+
+    #[tokio::test]
+    async fn without_expired() {
+      let (app, pool) = setup_with_data().await;
+      let in_future = (Utc::now() + Duration::hours(1)).timestamp();
+      let valid = insert_assignment(&pool, 3, 2, in_future).await;
+      let expired = insert_assignment(&pool, 3, 1, 1664928000).await;
+
+      let response = test_api(app, "/user/3/assignments", http::Method::GET, None, StatusCode::OK, Some(("3", "username3"))).await.unwrap();
+      let response = response.as_array().unwrap();
+      assert_eq!(response.len(), 1);
+      assert_eq!(response[0]["id"], valid);
+      assert_eq!(response[0]["requirement_name"], "req2");
+      assert_eq!(response[0]["assigned_by"]["username"], "username1");
+
+      let result = sqlx::query!("select status from assignment where id = ?1", expired)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "expired");
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let _ = test_api(app, "/user/3/assignments", http::Method::GET, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod answer {
+    use super::*;
+
+    #[tokio::test]
+    async fn accept() {
+      let (app, pool) = setup_with_data().await;
+      let in_future = (Utc::now() + Duration::hours(1)).timestamp();
+      let id = insert_assignment(&pool, 3, 2, in_future).await;
+      let body_json = json!({
+        "status": "accepted",
+      });
+
+      let _ = test_api(app, &format!("/assignment/{id}"), http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("3", "username3"))).await;
+
+      let result = sqlx::query!("select status from fullfillment where user = 3 and requirement = 2")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "pledged");
+      let result = sqlx::query!("select status, answered_at from assignment where id = ?1", id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "accepted");
+      assert!(result.answered_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn decline() {
+      let (app, pool) = setup_with_data().await;
+      let in_future = (Utc::now() + Duration::hours(1)).timestamp();
+      let id = insert_assignment(&pool, 3, 2, in_future).await;
+      let body_json = json!({
+        "status": "declined",
+      });
+
+      let _ = test_api(app, &format!("/assignment/{id}"), http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("3", "username3"))).await;
+
+      let results = sqlx::query!("select user from fullfillment where requirement = 2")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn accept_full() {
+      let (app, pool) = setup_with_data().await;
+      let in_future = (Utc::now() + Duration::hours(1)).timestamp();
+      let id = insert_assignment(&pool, 3, 3, in_future).await;
+      let body_json = json!({
+        "status": "accepted",
+      });
+
+      let _ = test_api(app, &format!("/assignment/{id}"), http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("3", "username3"))).await;
+
+      let result = sqlx::query!("select status from assignment where id = ?1", id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "pending");
+    }
+
+    #[tokio::test]
+    async fn expired() {
+      let (app, pool) = setup_with_data().await;
+      let id = insert_assignment(&pool, 3, 2, 1664928000).await;
+      let body_json = json!({
+        "status": "accepted",
+      });
+
+      let _ = test_api(app, &format!("/assignment/{id}"), http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("3", "username3"))).await;
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, pool) = setup_with_data().await;
+      let in_future = (Utc::now() + Duration::hours(1)).timestamp();
+      let id = insert_assignment(&pool, 3, 2, in_future).await;
+      let body_json = json!({
+        "status": "accepted",
+      });
+
+      let _ = test_api(app, &format!("/assignment/{id}"), http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+    }
+  }
+}
@@ -1,17 +1,17 @@
 use axum::{async_trait, extract::{FromRequest, RequestParts}, Json};
 use chrono::{Utc, Duration};
-use hyper::StatusCode;
-use jsonwebtoken::{EncodingKey, Header, encode, decode, DecodingKey, Validation};
-use rand::Rng;
+use hyper::{StatusCode, HeaderMap, Method, header};
+use rand::{Rng, distributions::Alphanumeric};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use std::{env, collections::HashMap};
-use reqwest;
 
-use crate::{error::AppError, DbState, utils::AppReponse};
+use crate::{error::AppError, DbState, utils::AppReponse, jwt_keys, session::{self, CookieConfig}};
 
 pub const ISSUER: &str = "zmtwc";
 
+pub const TOKEN_HOURS: i64 = 24;
+
 pub struct UserAuth(pub i64);
 
 #[async_trait]
@@ -22,21 +22,76 @@ where
     type Rejection = AppError;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-      let token = req.headers()
-        .get("X-JWT-Token")
-        .and_then(|header| header.to_str().ok());
+      let token = session_token(req.method(), req.headers(), &CookieConfig::from_env())?.map(String::from);
+      let pool = req.extensions().get::<DbState>().cloned();
 
       if let Some(token) = token {
-        if let Some(claims) = validate_and_decode(token) {
-          return Ok(UserAuth(claims.sub.parse::<i64>().unwrap()));
-        } else {
-          return Err(AppError::Unauthorized("Invalid JWT token".to_owned()))
-        }
+        return authenticate(pool.as_ref(), &token).await.map(UserAuth);
+      }
+      Err(AppError::Unauthorized("`Authorization: Bearer` header is missing".to_owned()))
+    }
+}
+
+/// Signed in user if the request has a session token, requests with an invalid token are still rejected.
+pub struct OptionalUserAuth(pub Option<i64>);
+
+#[async_trait]
+impl<B> FromRequest<B> for OptionalUserAuth
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+      let token = session_token(req.method(), req.headers(), &CookieConfig::from_env())?.map(String::from);
+      let pool = req.extensions().get::<DbState>().cloned();
+
+      match token {
+        Some(token) => authenticate(pool.as_ref(), &token).await.map(|id| OptionalUserAuth(Some(id))),
+        None => Ok(OptionalUserAuth(None)),
       }
-      Err(AppError::Unauthorized("`X-JWT-Token` header is missing".to_owned()))
     }
 }
 
+/// Token from the `Authorization: Bearer` header, the older `X-JWT-Token` header or the session cookie,
+/// requests authenticated by the cookie have to pass the CSRF check.
+fn session_token<'a>(method: &Method, headers: &'a HeaderMap, cookies: &CookieConfig) -> Result<Option<&'a str>, AppError> {
+  // other schemes, e.g. basic auth of a proxy in front, are left to the next sources
+  let bearer = headers
+    .get(header::AUTHORIZATION)
+    .and_then(|a| a.to_str().ok())
+    .and_then(|a| a.split_once(' '))
+    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+    .map(|(_, token)| token.trim());
+  if let Some(token) = bearer {
+    return Ok(Some(token));
+  }
+  if let Some(token) = headers.get("X-JWT-Token").and_then(|header| header.to_str().ok()) {
+    return Ok(Some(token));
+  }
+  if cookies.enabled {
+    if let Some(token) = session::cookie(headers, session::SESSION_COOKIE) {
+      session::check_csrf(method, headers)?;
+      return Ok(Some(token));
+    }
+  }
+  Ok(None)
+}
+
+async fn authenticate(pool: Option<&DbState>, token: &str) -> Result<i64, AppError> {
+  let claims = match validate_and_decode(token) {
+    Some(claims) => claims,
+    None => return Err(AppError::Unauthorized("Invalid JWT token".to_owned())),
+  };
+  let user_id = claims.sub.parse::<i64>().unwrap();
+  if let Some(pool) = pool {
+    // tokens from before the millisecond claim was added only know the second they were issued
+    let issued_at = claims.iat_ms.unwrap_or(claims.iat * 1000);
+    sessions_authorization(pool, user_id, issued_at).await?;
+  }
+  Ok(user_id)
+}
+
 #[derive(Deserialize)]
 pub struct CaptchaRequest {
   token: String
@@ -52,9 +107,16 @@ struct CaptchaResponse {
 pub async fn verify_captcha(
   Json(payload): Json<CaptchaRequest>,
 ) -> AppReponse<()> {
+  check_captcha(&payload.token).await?;
+
+  Ok((StatusCode::OK, ()))
+}
+
+pub async fn check_captcha(token: &str) -> Result<(), AppError> {
+  let secret = env::var("CAPTCHA_SECRET_KEY").map_err(|_| AppError::Server(String::from("captcha key must be set")))?;
   let mut params = HashMap::new();
-  params.insert("secret", env::var("CAPTCHA_SECRET_KEY").expect("captcha key must be set"));
-  params.insert("response", payload.token);
+  params.insert("secret", secret);
+  params.insert("response", String::from(token));
 
   let client = reqwest::Client::new();
 
@@ -71,7 +133,7 @@ pub async fn verify_captcha(
     return Err(AppError::BadRequest(format!("captcha verification failed: {}", response_body.error_codes.unwrap_or(vec!["Unknown error".to_owned()]).join(", "))));
   }
 
-  Ok((StatusCode::OK, ()))
+  Ok(())
 }
 
 
@@ -83,18 +145,21 @@ struct Claims {
   username: String,
   exp: i64,
   iat: i64,
+  /// issue time in milliseconds, tokens issued in the same second as a revocation are told apart by it
+  iat_ms: Option<i64>,
 }
 
 impl Claims {
   pub fn new(sub: String, username: String) -> Self {
     let iat = Utc::now();
-    let exp = iat + Duration::hours(24);
+    let exp = iat + Duration::hours(TOKEN_HOURS);
 
     Self {
       iss: String::from(ISSUER),
       sub,
       username,
       iat: iat.timestamp(),
+      iat_ms: Some(iat.timestamp_millis()),
       exp: exp.timestamp(),
     }
   }
@@ -117,6 +182,81 @@ pub fn generate_salt() -> String {
   salt
 }
 
+/// Random token for links sent by email, only its hash is stored.
+pub fn generate_token() -> String {
+  rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(32)
+    .map(char::from)
+    .collect()
+}
+
+pub fn hash_token(token: &str) -> String {
+  Sha256::digest(token)
+    .into_iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/// How the stored hash was derived from the password the user typed.
+#[derive(sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+pub enum PasswordScheme {
+  /// salted hash of the password itself
+  Raw,
+  /// salted hash of the SHA-256 hex digest, which older clients send instead of the password
+  ClientSha256,
+}
+
+/// Salted password hash of a user as stored in the `user` table.
+pub struct StoredPassword {
+  pub scheme: PasswordScheme,
+  pub hash: String,
+  pub salt: String,
+}
+
+impl StoredPassword {
+  /// A digest sent by an older client is stored as such while pre-hashed passwords are accepted,
+  /// the account then keeps working with both older and newer clients.
+  pub fn new(password: &str, accept_prehashed: bool) -> Self {
+    let scheme = if accept_prehashed && is_prehashed(password) { PasswordScheme::ClientSha256 } else { PasswordScheme::Raw };
+    let salt = generate_salt();
+    Self { scheme, hash: get_salted_password(password, &salt), salt }
+  }
+
+  pub fn verify(&self, password: &str, accept_prehashed: bool) -> bool {
+    match self.scheme {
+      PasswordScheme::Raw => get_salted_password(password, &self.salt) == self.hash,
+      PasswordScheme::ClientSha256 => {
+        get_salted_password(&prehash_password(password), &self.salt) == self.hash
+          || (accept_prehashed && get_salted_password(password, &self.salt) == self.hash)
+      },
+    }
+  }
+
+  /// Once pre-hashed passwords are no longer accepted, a legacy hash is replaced after the next successful login.
+  pub fn needs_upgrade(&self, accept_prehashed: bool) -> bool {
+    self.scheme == PasswordScheme::ClientSha256 && !accept_prehashed
+  }
+}
+
+/// Whether clients may still send the SHA-256 digest instead of the password, `ACCEPT_PREHASHED_PASSWORDS` is on by default.
+pub fn accept_prehashed_passwords() -> bool {
+  env::var("ACCEPT_PREHASHED_PASSWORDS")
+    .map(|a| !matches!(a.to_lowercase().as_str(), "false" | "0" | "off"))
+    .unwrap_or(true)
+}
+
+/// Looks like the SHA-256 hex digest older clients compute from the password.
+pub fn is_prehashed(password: &str) -> bool {
+  password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The digest older clients send, lowercase hex of the SHA-256 of the password.
+pub fn prehash_password(password: &str) -> String {
+  hash_token(password)
+}
+
 pub fn get_salted_password(password: &str, salt: &str) -> String {
   let hash = Sha256::new()
     .chain_update(password)
@@ -132,17 +272,15 @@ pub fn get_salted_password(password: &str, salt: &str) -> String {
 
 pub fn generate_jwt(user_id: &str, username: &str) -> String {
   let claims = Claims::new(String::from(user_id), String::from(username));
-  let secret = get_secret();
-  encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref())).expect("jwt token to be generated")
+  jwt_keys::keys().sign(&claims).expect("jwt token to be generated")
 }
 
 #[allow(dead_code)]
 fn validate_and_decode(token: &str) -> Option<Claims> {
-  let token = decode::<Claims>(token, &DecodingKey::from_secret(get_secret().as_ref()), &Validation::default());
-  let claims = token.and_then(|td| Ok(td.claims));
+  let claims = jwt_keys::keys().verify::<Claims>(token);
 
   match claims {
-    Ok(claims) => {
+    Some(claims) => {
       if claims.iss == *ISSUER {
         Some(claims)
       } else {
@@ -153,8 +291,77 @@ fn validate_and_decode(token: &str) -> Option<Claims> {
   }
 }
 
-pub fn get_secret() -> String {
-  env::var("JWT_SECRET").expect("JWT_SECRET must be set")
+/// Rejects tokens issued before the sessions of the user were revoked, e.g. by a password reset.
+/// Both times are in milliseconds.
+async fn sessions_authorization(pool: &DbState, user_id: i64, issued_at: i64) -> Result<(), AppError> {
+  let user = sqlx::query!("SELECT sessions_revoked_at FROM user WHERE id = ?1", user_id)
+    .fetch_optional(pool)
+    .await?;
+  match user.and_then(|u| u.sessions_revoked_at) {
+    Some(revoked_at) if issued_at < revoked_at => Err(AppError::Unauthorized(String::from("session has been revoked"))),
+    _ => Ok(()),
+  }
+}
+
+/// Actions that can be denied to accounts without a verified email address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Restriction {
+  CreateEvent,
+  JoinEvent,
+  Comment,
+  Webhook,
+}
+
+impl Restriction {
+  fn parse(name: &str) -> Option<Self> {
+    match name {
+      "create_event" => Some(Restriction::CreateEvent),
+      "join_event" => Some(Restriction::JoinEvent),
+      "comment" => Some(Restriction::Comment),
+      "webhook" => Some(Restriction::Webhook),
+      _ => None,
+    }
+  }
+}
+
+/// Parses a comma separated list like `create_event,webhook`, unknown names are ignored.
+pub fn parse_restrictions(value: &str) -> Vec<Restriction> {
+  value
+    .split(',')
+    .map(str::trim)
+    .filter(|name| !name.is_empty())
+    .filter_map(|name| {
+      let restriction = Restriction::parse(name);
+      if restriction.is_none() {
+        tracing::warn!("unknown restriction for unverified accounts: {name}");
+      }
+      restriction
+    })
+    .collect()
+}
+
+/// Restrictions of unverified accounts configured in `UNVERIFIED_RESTRICTIONS`, none by default.
+pub fn unverified_restrictions() -> Vec<Restriction> {
+  env::var("UNVERIFIED_RESTRICTIONS")
+    .map(|r| parse_restrictions(&r))
+    .unwrap_or_default()
+}
+
+pub async fn verified_authorization(pool: &DbState, auth_id: i64, action: Restriction) -> Result<(), AppError> {
+  restriction_authorization(pool, auth_id, action, &unverified_restrictions()).await
+}
+
+pub async fn restriction_authorization(pool: &DbState, auth_id: i64, action: Restriction, restrictions: &[Restriction]) -> Result<(), AppError> {
+  if !restrictions.contains(&action) {
+    return Ok(());
+  }
+  let user = sqlx::query!(r#"SELECT email_verified_at IS NOT NULL as "verified!: bool" FROM user WHERE id = ?1"#, auth_id)
+    .fetch_optional(pool)
+    .await?;
+  match user {
+    Some(u) if u.verified => Ok(()),
+    _ => Err(AppError::Forbidden(String::from("email address of user has to be verified first"))),
+  }
 }
 
 pub fn user_action_authorization(user_id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
@@ -174,6 +381,23 @@ pub async fn event_action_authorization(pool: &DbState, event_id: i64, auth_id:
   Ok(())
 }
 
+pub async fn event_member_authorization(pool: &DbState, event_id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
+  let member = sqlx::query!(
+      r#"
+  SELECT creator = ?2 OR EXISTS (SELECT 1 FROM participant WHERE event = ?1 AND user = ?2) as "member!: bool"
+  FROM event WHERE id = ?1
+      "#,
+      event_id, auth_id
+    )
+    .fetch_optional(pool)
+    .await?;
+  match member {
+    Some(m) if m.member => Ok(()),
+    Some(_) => Err(AppError::Forbidden(String::from(msg))),
+    None => Err(AppError::NotFound(format!("Cannot find event: {event_id}"))),
+  }
+}
+
 pub async fn requirement_action_authorization(pool: &DbState, req_id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
   let event_to_update = sqlx::query!("SELECT creator FROM event WHERE id = (SELECT event FROM requirement where id = ?1)", req_id)
     .fetch_one(pool)
@@ -183,3 +407,127 @@ pub async fn requirement_action_authorization(pool: &DbState, req_id: i64, auth_
   }
   Ok(())
 }
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use axum::{Router, body::Body, http::{Request, HeaderValue}};
+  use tower::ServiceExt;
+  use crate::utils::test::setup_with_data;
+
+  fn request_headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+      map.append(*name, HeaderValue::from_str(value).unwrap());
+    }
+    map
+  }
+
+  async fn status(app: Router, method: Method, uri: &str, headers: &[(&'static str, &str)]) -> StatusCode {
+    let mut req = Request::builder()
+      .method(method)
+      .uri(uri);
+    *req.headers_mut().unwrap() = request_headers(headers);
+    app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap().status()
+  }
+
+  #[test]
+  fn token_sources() {
+    let cookies = CookieConfig { enabled: true, ..CookieConfig::default() };
+    let token = |method: Method, headers: &[(&'static str, &str)], cookies: &CookieConfig| {
+      session_token(&method, &request_headers(headers), cookies).map(|t| t.map(String::from))
+    };
+
+    assert!(matches!(token(Method::GET, &[("authorization", "Bearer abc")], &cookies), Ok(Some(t)) if t == "abc"));
+    assert!(matches!(token(Method::GET, &[("authorization", "bearer abc")], &cookies), Ok(Some(t)) if t == "abc"));
+    assert!(matches!(token(Method::GET, &[("authorization", "Basic abc")], &cookies), Ok(None)));
+    assert!(matches!(token(Method::GET, &[("authorization", "Basic abc"), ("x-jwt-token", "def")], &cookies), Ok(Some(t)) if t == "def"));
+    assert!(matches!(token(Method::GET, &[("authorization", "Basic abc"), ("cookie", "session=def")], &cookies), Ok(Some(t)) if t == "def"));
+    assert!(matches!(token(Method::GET, &[("x-jwt-token", "abc")], &cookies), Ok(Some(t)) if t == "abc"));
+    assert!(matches!(token(Method::GET, &[], &cookies), Ok(None)));
+
+    // cookies only when enabled, and with the csrf token for changes
+    assert!(matches!(token(Method::GET, &[("cookie", "session=abc")], &cookies), Ok(Some(t)) if t == "abc"));
+    assert!(matches!(token(Method::GET, &[("cookie", "session=abc")], &CookieConfig::default()), Ok(None)));
+    assert!(matches!(token(Method::POST, &[("cookie", "session=abc")], &cookies), Err(AppError::Forbidden(_))));
+    let headers = [("cookie", "session=abc; csrf_token=def"), ("x-csrf-token", "def")];
+    assert!(matches!(token(Method::POST, &headers, &cookies), Ok(Some(t)) if t == "abc"));
+    // headers are not sent by other sites, so they need no csrf token
+    assert!(matches!(token(Method::POST, &[("authorization", "Bearer abc"), ("cookie", "session=def")], &cookies), Ok(Some(t)) if t == "abc"));
+  }
+
+  #[tokio::test]
+  async fn bearer() {
+    let (app, _) = setup_with_data().await;
+    let token = generate_jwt("1", "username1");
+
+    let bearer = format!("Bearer {token}");
+    assert_eq!(status(app.clone(), Method::GET, "/user/1/notifications", &[("authorization", &bearer)]).await, StatusCode::OK);
+    assert_eq!(status(app.clone(), Method::GET, "/user/1/notifications", &[("x-jwt-token", &token)]).await, StatusCode::OK);
+    assert_eq!(status(app.clone(), Method::GET, "/user/1/notifications", &[("authorization", "Bearer invalid")]).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(status(app, Method::GET, "/user/1/notifications", &[]).await, StatusCode::UNAUTHORIZED);
+  }
+
+  #[tokio::test]
+  async fn optional() {
+    let (app, _) = setup_with_data().await;
+
+    assert_eq!(status(app.clone(), Method::GET, "/event/1", &[]).await, StatusCode::OK);
+    assert_eq!(status(app, Method::GET, "/event/1", &[("authorization", "Bearer invalid")]).await, StatusCode::UNAUTHORIZED);
+  }
+
+  #[test]
+  fn restrictions() {
+    assert_eq!(parse_restrictions("create_event, webhook,unknown,"), vec![Restriction::CreateEvent, Restriction::Webhook]);
+    assert!(parse_restrictions("").is_empty());
+  }
+
+  #[test]
+  fn passwords() {
+    let raw = StoredPassword::new("correct horse", true);
+    assert_eq!(raw.scheme, PasswordScheme::Raw);
+    assert!(raw.verify("correct horse", true));
+    assert!(!raw.verify(&prehash_password("correct horse"), true));
+
+    let legacy = StoredPassword::new(&prehash_password("correct horse"), true);
+    assert_eq!(legacy.scheme, PasswordScheme::ClientSha256);
+    assert!(legacy.verify("correct horse", true));
+    assert!(legacy.verify(&prehash_password("correct horse"), true));
+    assert!(!legacy.needs_upgrade(true));
+    // older clients stop working once the transition is over
+    assert!(legacy.verify("correct horse", false));
+    assert!(!legacy.verify(&prehash_password("correct horse"), false));
+    assert!(legacy.needs_upgrade(false));
+
+    assert_eq!(StoredPassword::new(&prehash_password("correct horse"), false).scheme, PasswordScheme::Raw);
+  }
+
+  #[tokio::test]
+  async fn unverified() {
+    let (_, pool) = setup_with_data().await;
+    sqlx::query("UPDATE user SET email = 'user2@example.com', email_verified_at = 1664841600 WHERE id = 2")
+      .execute(&pool)
+      .await
+      .unwrap();
+    let restrictions = [Restriction::CreateEvent];
+
+    assert!(restriction_authorization(&pool, 1, Restriction::CreateEvent, &restrictions).await.is_err());
+    assert!(restriction_authorization(&pool, 1, Restriction::Comment, &restrictions).await.is_ok());
+    assert!(restriction_authorization(&pool, 2, Restriction::CreateEvent, &restrictions).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn revoked_sessions() {
+    let (_, pool) = setup_with_data().await;
+    sqlx::query("UPDATE user SET sessions_revoked_at = 1664841600500 WHERE id = 1")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    // issued earlier in the same second
+    assert!(sessions_authorization(&pool, 1, 1664841600499).await.is_err());
+    assert!(sessions_authorization(&pool, 1, 1664841600500).await.is_ok());
+    assert!(sessions_authorization(&pool, 2, 1664841600499).await.is_ok());
+  }
+}
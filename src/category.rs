@@ -0,0 +1,157 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_action_authorization}};
+
+#[derive(Serialize)]
+pub struct Category {
+  id: i64,
+  event: i64,
+  name: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateCategory {
+  event: i64,
+  name: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateCategory {
+  name: String,
+}
+
+async fn category_action_authorization(pool: &DbState, id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
+  let category = sqlx::query!("SELECT event FROM category WHERE id = ?1", id)
+    .fetch_optional(pool)
+    .await?;
+  match category {
+    Some(c) => event_action_authorization(pool, c.event, auth_id, msg).await,
+    None => Err(AppError::NotFound(format!("Cannot find category: {id}"))),
+  }
+}
+
+pub async fn create(
+  Json(payload): Json<CreateCategory>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Category>> {
+  let CreateCategory { event, name } = payload;
+  event_action_authorization(&pool, event, auth_userid, "cannot create category for event that user doesn't own").await?;
+
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO category ( event, name )
+  VALUES ( ?1, ?2 )
+      "#,
+      event, name
+    )
+    .execute(&pool)
+    .await?
+    .last_insert_rowid();
+
+  Ok((StatusCode::CREATED, Json(Category { id, event, name })))
+}
+
+pub async fn update(
+  Path(id): Path<i64>,
+  Json(payload): Json<UpdateCategory>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  category_action_authorization(&pool, id, auth_userid, "cannot change category for event that user doesn't own").await?;
+
+  let _ = sqlx::query!("UPDATE category SET name = ?2 WHERE id = ?1", id, payload.name)
+    .execute(&pool)
+    .await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn delete(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  category_action_authorization(&pool, id, auth_userid, "cannot delete category for event that user doesn't own").await?;
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE requirement SET category = NULL WHERE category = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM category WHERE id = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "event": 1,
+        "name": "Food",
+      });
+      let expected_response = json!({
+        "id": 1,
+        "event": 1,
+        "name": "Food",
+      });
+
+      let response = test_api(app, "/category", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "event": 1,
+        "name": "Food",
+      });
+
+      let _ = test_api(app, "/category", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod delete {
+    use super::*;
+
+    #[tokio::test]
+    async fn with_requirements() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO category (id, event, name) VALUES (1, 1, 'Food')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      sqlx::query("UPDATE requirement SET category = 1 WHERE id = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let _ = test_api(app, "/category/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select category from requirement where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.category, None);
+    }
+  }
+}
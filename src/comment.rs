@@ -0,0 +1,565 @@
+use axum::{
+  Json, Extension, extract::{Path, Query},
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_member_authorization, verified_authorization, Restriction}, user::User, notification::{NotifierState, Notification, NotificationKind, insert_db_notification}};
+
+#[derive(Serialize)]
+pub struct Comment {
+  id: i64,
+  event: i64,
+  requirement: Option<i64>,
+  parent: Option<i64>,
+  user: User,
+  body: Option<String>,
+  mentions: Vec<User>,
+  created_at: i64,
+  edited_at: Option<i64>,
+  deleted: bool,
+  replies: Vec<Comment>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateComment {
+  body: String,
+  requirement: Option<i64>,
+  parent: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateComment {
+  body: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct CommentSearchParam {
+  requirement: Option<i64>,
+  page: Option<u32>,
+  pageSize: Option<u32>,
+}
+
+/// Collects the distinct `@username` mentions of a comment body.
+fn parse_mentions(body: &str) -> Vec<String> {
+  let mut mentions: Vec<String> = vec![];
+  for (i, _) in body.match_indices('@') {
+    let preceded_by_word = body[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+    if preceded_by_word {
+      continue;
+    }
+    let username: String = body[i + 1..]
+      .chars()
+      .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+      .collect();
+    let username = username.trim_end_matches('.');
+    if !username.is_empty() && !mentions.iter().any(|m| m == username) {
+      mentions.push(String::from(username));
+    }
+  }
+  mentions
+}
+
+/// Stores the mentioned organizer and participants of the event and creates a notification for each of them.
+/// Users already mentioned before an edit are not notified again.
+async fn insert_db_mentions(conn: &mut SqliteConnection, comment: i64, event: i64, author: i64, body: &str) -> Result<Vec<Notification>, AppError> {
+  let previous: Vec<i64> = sqlx::query!("SELECT user FROM comment_mention WHERE comment = ?1", comment)
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|m| m.user)
+    .collect();
+  let _ = sqlx::query!("DELETE FROM comment_mention WHERE comment = ?1", comment)
+    .execute(&mut *conn)
+    .await?;
+
+  let mut notifications = vec![];
+  for username in parse_mentions(body) {
+    let user = sqlx::query!(
+      r#"SELECT id as "id!" FROM user
+        WHERE username = ?1
+          AND ( id = ( SELECT creator FROM event WHERE id = ?2 ) OR id IN ( SELECT user FROM participant WHERE event = ?2 ) )"#,
+      username,
+      event
+    )
+      .fetch_optional(&mut *conn)
+      .await?;
+    if let Some(u) = user {
+      let _ = sqlx::query!("INSERT INTO comment_mention ( comment, user ) VALUES ( ?1, ?2 )", comment, u.id)
+        .execute(&mut *conn)
+        .await?;
+      if u.id != author && !previous.contains(&u.id) {
+        let message = String::from("You have been mentioned in a comment");
+        notifications.extend(insert_db_notification(&mut *conn, u.id, NotificationKind::CommentMention, message, Some(event), None, None).await?);
+      }
+    }
+  }
+  Ok(notifications)
+}
+
+async fn get_db_comment_event(pool: &DbState, id: i64) -> Result<(i64, i64, i64), AppError> {
+  let comment = sqlx::query!(
+      r#"
+  SELECT comment.event, comment.user, event.creator FROM comment
+  JOIN event ON comment.event = event.id
+  WHERE comment.id = ?1 AND comment.deleted_at IS NULL
+      "#,
+      id
+    )
+    .fetch_optional(pool)
+    .await?;
+  match comment {
+    Some(c) => Ok((c.event, c.user, c.creator)),
+    None => Err(AppError::NotFound(format!("Cannot find comment: {id}"))),
+  }
+}
+
+fn attach_replies(parent: Option<i64>, comments: &mut Vec<Comment>) -> Vec<Comment> {
+  let (children, rest): (Vec<Comment>, Vec<Comment>) = std::mem::take(comments).into_iter().partition(|c| c.parent == parent);
+  *comments = rest;
+  children
+    .into_iter()
+    .map(|mut c| {
+      c.replies = attach_replies(Some(c.id), comments);
+      c
+    })
+    .collect()
+}
+
+pub async fn all(
+  Path(event_id): Path<i64>,
+  Query(params): Query<CommentSearchParam>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Comment>>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get comments of event that user doesn't take part in").await?;
+
+  let (limit, offset) = match (params.page, params.pageSize) {
+    (Some(page), Some(page_size)) => (page_size as i64, (page.max(1) - 1) as i64 * page_size as i64),
+    _ => (-1, 0),
+  };
+
+  // pages are made of top level comments, replies are always listed with their thread
+  let mut comments: Vec<Comment> = sqlx::query!(
+      r#"
+  WITH RECURSIVE thread(id) AS (
+    SELECT id FROM (
+      SELECT id FROM comment
+      WHERE event = ?1 AND parent IS NULL AND (?2 IS NULL OR requirement = ?2)
+      ORDER BY created_at, id
+      LIMIT ?3 OFFSET ?4
+    )
+    UNION
+    SELECT comment.id FROM comment JOIN thread ON comment.parent = thread.id
+  )
+  SELECT comment.id as "id!", comment.requirement, comment.parent, comment.body, comment.created_at, comment.edited_at, comment.deleted_at,
+    user.id as "user_id!", user.username
+  FROM comment
+  JOIN user ON comment.user = user.id
+  WHERE comment.id in (SELECT id FROM thread)
+  ORDER BY comment.created_at, comment.id
+      "#,
+      event_id, params.requirement, limit, offset
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|c| Comment {
+      id: c.id,
+      event: event_id,
+      requirement: c.requirement,
+      parent: c.parent,
+      user: User {
+        id: c.user_id,
+        username: c.username,
+      },
+      body: if c.deleted_at.is_some() { None } else { Some(c.body) },
+      mentions: vec![],
+      created_at: c.created_at,
+      edited_at: c.edited_at,
+      deleted: c.deleted_at.is_some(),
+      replies: vec![],
+    })
+    .collect();
+
+  let mentions = sqlx::query!(
+      r#"
+  SELECT comment_mention.comment, user.id as "id!", user.username FROM comment_mention
+  JOIN user ON comment_mention.user = user.id
+  WHERE comment_mention.comment in (SELECT id FROM comment WHERE event = ?1)
+  ORDER BY user.id
+      "#,
+      event_id
+    )
+    .fetch_all(&pool)
+    .await?;
+  for m in mentions {
+    if let Some(c) = comments.iter_mut().find(|c| c.id == m.comment && !c.deleted) {
+      c.mentions.push(User { id: m.id, username: m.username });
+    }
+  }
+
+  let threads = attach_replies(None, &mut comments);
+  Ok((StatusCode::OK, Json(threads)))
+}
+
+pub async fn create(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<CreateComment>,
+  Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Comment>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot comment on event that user doesn't take part in").await?;
+  verified_authorization(&pool, auth_userid, Restriction::Comment).await?;
+  let CreateComment { body, requirement, parent } = payload;
+  if body.trim().is_empty() {
+    return Err(AppError::BadRequest(String::from("comment cannot be empty")));
+  }
+  if let Some(requirement) = requirement {
+    let r = sqlx::query!("SELECT event FROM requirement WHERE id = ?1", requirement)
+      .fetch_optional(&pool)
+      .await?;
+    if r.map(|r| r.event) != Some(event_id) {
+      return Err(AppError::BadRequest(format!("requirement {requirement} doesn't belong to event {event_id}")));
+    }
+  }
+  // a reply belongs to the same requirement as the comment it answers
+  let requirement = match parent {
+    Some(parent) => {
+      let p = sqlx::query!("SELECT event, requirement FROM comment WHERE id = ?1", parent)
+        .fetch_optional(&pool)
+        .await?;
+      match p {
+        Some(p) if p.event == event_id => p.requirement,
+        _ => return Err(AppError::BadRequest(format!("comment {parent} doesn't belong to event {event_id}"))),
+      }
+    },
+    None => requirement,
+  };
+
+  let created_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO comment ( event, requirement, parent, user, body, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+      "#,
+      event_id, requirement, parent, auth_userid, body, created_at
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+  let notifications = insert_db_mentions(&mut tx, id, event_id, auth_userid, &body).await?;
+  let mentions = sqlx::query_as!(User,
+      r#"
+  SELECT user.id as "id!", user.username FROM comment_mention
+  JOIN user ON comment_mention.user = user.id
+  WHERE comment_mention.comment = ?1
+  ORDER BY user.id
+      "#,
+      id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+  let user = sqlx::query_as!(User, "SELECT id, username FROM user WHERE id = ?1", auth_userid)
+    .fetch_one(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  let comment = Comment {
+    id,
+    event: event_id,
+    requirement,
+    parent,
+    user,
+    body: Some(body),
+    mentions,
+    created_at,
+    edited_at: None,
+    deleted: false,
+    replies: vec![],
+  };
+  Ok((StatusCode::CREATED, Json(comment)))
+}
+
+pub async fn update(
+  Path(id): Path<i64>,
+  Json(payload): Json<UpdateComment>,
+  Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  let (event_id, author, _) = get_db_comment_event(&pool, id).await?;
+  if author != auth_userid {
+    return Err(AppError::Forbidden(String::from("cannot edit comment of another user")));
+  }
+  if payload.body.trim().is_empty() {
+    return Err(AppError::BadRequest(String::from("comment cannot be empty")));
+  }
+
+  let edited_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE comment SET body = ?2, edited_at = ?3 WHERE id = ?1", id, payload.body, edited_at)
+    .execute(&mut tx)
+    .await?;
+  let notifications = insert_db_mentions(&mut tx, id, event_id, auth_userid, &payload.body).await?;
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn delete(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  let (_, author, organizer) = get_db_comment_event(&pool, id).await?;
+  if author != auth_userid && organizer != auth_userid {
+    return Err(AppError::Forbidden(String::from("cannot delete comment of another user")));
+  }
+
+  // the comment is kept as a placeholder so that its replies stay in the thread
+  let deleted_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE comment SET body = '', deleted_at = ?2 WHERE id = ?1", id, deleted_at)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM comment_mention WHERE comment = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  async fn insert_comments(pool: &DbState) {
+    sqlx::query(r#"INSERT INTO comment (id, event, requirement, parent, user, body, created_at) VALUES
+      (1, 1, NULL, NULL, 2, "who brings the grill?", 1664841600),
+      (2, 1, NULL, 1, 1, "@username3 has one", 1664841700),
+      (3, 1, 1, NULL, 3, "req1 is too much", 1664841800)"#)
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  #[test]
+  fn mentions() {
+    assert_eq!(parse_mentions("@alice and @bob_1, ask @alice."), vec!["alice", "bob_1"]);
+    assert_eq!(parse_mentions("mail@example.com @ nobody"), Vec::<String>::new());
+  }
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn with_mention() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "body": "@username3 can you bring @unknown?",
+      });
+
+      let mut response = test_api(app, "/event/1/comments", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("2", "username2"))).await.unwrap();
+      assert!(response.as_object_mut().unwrap().remove("created_at").unwrap().is_i64());
+      assert_eq!(response, json!({
+        "id": 1,
+        "event": 1,
+        "requirement": null,
+        "parent": null,
+        "user": { "id": 2, "username": "username2" },
+        "body": "@username3 can you bring @unknown?",
+        "mentions": [{ "id": 3, "username": "username3" }],
+        "edited_at": null,
+        "deleted": false,
+        "replies": []
+      }));
+
+      let results = sqlx::query!("select user from notification where kind = 'comment_mention'")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].user, 3);
+    }
+
+    #[tokio::test]
+    async fn mention_outsider() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "body": "@username1 and @username5",
+      });
+
+      let response = test_api(app, "/event/1/comments", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["mentions"], json!([{ "id": 1, "username": "username1" }]));
+
+      let results = sqlx::query!("select user from notification where kind = 'comment_mention'")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].user, 1);
+    }
+
+    #[tokio::test]
+    async fn reply() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+      let body_json = json!({
+        "body": "me too",
+        "parent": 3,
+      });
+
+      let response = test_api(app, "/event/1/comments", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["requirement"], 1);
+    }
+
+    #[tokio::test]
+    async fn as_outsider() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "body": "hello",
+      });
+
+      let _ = test_api(app, "/event/1/comments", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("5", "username5"))).await;
+    }
+  }
+
+  mod get {
+    use super::*;
+
+    #[tokio::test]
+    async fn threads() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+
+      let response = test_api(app, "/event/1/comments", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      let threads = response.as_array().unwrap();
+      assert_eq!(threads.len(), 2);
+      assert_eq!(threads[0]["replies"][0]["id"], 2);
+      assert_eq!(threads[1]["id"], 3);
+    }
+
+    #[tokio::test]
+    async fn paginated() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+
+      let response = test_api(app, "/event/1/comments?page=2&pageSize=1", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      let ids: Vec<i64> = response.as_array().unwrap().iter().map(|c| c["id"].as_i64().unwrap()).collect();
+      assert_eq!(ids, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn of_requirement() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+
+      let response = test_api(app, "/event/1/comments?requirement=1", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response.as_array().unwrap().len(), 1);
+    }
+  }
+
+  mod update {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+      let body_json = json!({
+        "body": "who brings the big grill?",
+      });
+
+      let _ = test_api(app, "/comment/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+      let result = sqlx::query!("select body, edited_at from comment where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.body, "who brings the big grill?");
+      assert!(result.edited_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+      let body_json = json!({
+        "body": "not mine",
+      });
+
+      let _ = test_api(app, "/comment/1", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn new_mentions() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+      sqlx::query("INSERT INTO comment_mention (comment, user) VALUES (2, 3)")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "body": "@username3 and @username2 have one",
+      });
+
+      let _ = test_api(app, "/comment/2", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user from notification where kind = 'comment_mention'")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].user, 2);
+      let mentions = sqlx::query!("select user from comment_mention where comment = 2 order by user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(mentions.iter().map(|m| m.user).collect::<Vec<i64>>(), vec![2, 3]);
+    }
+  }
+
+  mod delete {
+    use super::*;
+
+    #[tokio::test]
+    async fn by_organizer() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+
+      let _ = test_api(app.clone(), "/comment/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let response = test_api(app, "/event/1/comments", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response[0]["deleted"], true);
+      assert_eq!(response[0]["body"], json!(null));
+      assert_eq!(response[0]["replies"][0]["id"], 2);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, pool) = setup_with_data().await;
+      insert_comments(&pool).await;
+
+      let _ = test_api(app, "/comment/1", http::Method::DELETE, None, StatusCode::FORBIDDEN, Some(("3", "username3"))).await;
+    }
+  }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashSet, env, fs, sync::OnceLock};
+
+use crate::{DbState, error::AppError, auth::{accept_prehashed_passwords, is_prehashed, prehash_password}};
+
+/// Bundled list of the most common passwords, used unless `BREACHED_PASSWORDS_FILE` is set.
+const COMMON_PASSWORDS: &str = include_str!("../data/common_passwords.txt");
+pub const USERNAME_SYMBOLS: &[char] = &[' ', '_', '-', '.'];
+
+/// Rules for usernames and passwords chosen by users.
+#[derive(Clone, Debug)]
+pub struct CredentialPolicy {
+  pub username_min_length: usize,
+  pub username_max_length: usize,
+  pub password_min_length: usize,
+  pub password_max_length: usize,
+  /// see [`accept_prehashed_passwords`]
+  pub accept_prehashed: bool,
+}
+
+impl Default for CredentialPolicy {
+  fn default() -> Self {
+    Self {
+      username_min_length: 3,
+      username_max_length: 32,
+      password_min_length: 8,
+      password_max_length: 128,
+      accept_prehashed: true,
+    }
+  }
+}
+
+impl CredentialPolicy {
+  pub fn from_env() -> Self {
+    let default = Self::default();
+    Self {
+      username_min_length: env::var("USERNAME_MIN_LENGTH").ok().and_then(|l| l.parse().ok()).unwrap_or(default.username_min_length),
+      username_max_length: env::var("USERNAME_MAX_LENGTH").ok().and_then(|l| l.parse().ok()).unwrap_or(default.username_max_length),
+      password_min_length: env::var("PASSWORD_MIN_LENGTH").ok().and_then(|l| l.parse().ok()).unwrap_or(default.password_min_length),
+      password_max_length: env::var("PASSWORD_MAX_LENGTH").ok().and_then(|l| l.parse().ok()).unwrap_or(default.password_max_length),
+      accept_prehashed: accept_prehashed_passwords(),
+    }
+  }
+
+  /// Usernames consist of letters, digits, spaces, `_`, `-` and `.`, and don't start or end with a space.
+  pub fn validate_username(&self, username: &str) -> Result<(), AppError> {
+    let length = username.chars().count();
+    if length < self.username_min_length || length > self.username_max_length {
+      return Err(AppError::BadRequest(format!(
+        "username must be between {} and {} characters long", self.username_min_length, self.username_max_length
+      )));
+    }
+    if let Some(c) = username.chars().find(|c| !c.is_alphanumeric() && !USERNAME_SYMBOLS.contains(c)) {
+      return Err(AppError::BadRequest(format!("username cannot contain '{c}', only letters, digits, spaces, '_', '-' and '.' are allowed")));
+    }
+    if username.starts_with(' ') || username.ends_with(' ') {
+      return Err(AppError::BadRequest(String::from("username cannot start or end with a space")));
+    }
+    Ok(())
+  }
+
+  /// The length of a digest sent by an older client says nothing about the password,
+  /// so only the breached-password check applies to it, against the digests of the listed passwords.
+  pub fn validate_password(&self, password: &str) -> Result<(), AppError> {
+    let breached = breached_passwords();
+    if self.accept_prehashed && is_prehashed(password) {
+      if breached.digests.contains(&password.to_lowercase()) {
+        return Err(breached_error());
+      }
+      return Ok(());
+    }
+
+    let length = password.chars().count();
+    if length < self.password_min_length || length > self.password_max_length {
+      return Err(AppError::BadRequest(format!(
+        "password must be between {} and {} characters long", self.password_min_length, self.password_max_length
+      )));
+    }
+    if breached.plain.contains(&password.to_lowercase()) {
+      return Err(breached_error());
+    }
+    Ok(())
+  }
+}
+
+struct BreachedPasswords {
+  plain: HashSet<String>,
+  digests: HashSet<String>,
+}
+
+/// Loaded once from `BREACHED_PASSWORDS_FILE` (one password per line), or from the bundled list.
+fn breached_passwords() -> &'static BreachedPasswords {
+  static BREACHED: OnceLock<BreachedPasswords> = OnceLock::new();
+  BREACHED.get_or_init(|| {
+    let list = match env::var("BREACHED_PASSWORDS_FILE") {
+      Ok(path) => fs::read_to_string(&path).unwrap_or_else(|e| {
+        tracing::error!("cannot read breached passwords from {path}: {e}");
+        String::from(COMMON_PASSWORDS)
+      }),
+      Err(_) => String::from(COMMON_PASSWORDS),
+    };
+    let plain: HashSet<String> = list
+      .lines()
+      .map(|l| l.trim())
+      .filter(|l| !l.is_empty())
+      .map(|l| l.to_lowercase())
+      .collect();
+    let digests = list
+      .lines()
+      .map(|l| l.trim())
+      .filter(|l| !l.is_empty())
+      .map(prehash_password)
+      .collect();
+    BreachedPasswords { plain, digests }
+  })
+}
+
+fn breached_error() -> AppError {
+  AppError::BadRequest(String::from("password is too common, it appears in a list of breached passwords"))
+}
+
+/// Usernames are unique regardless of letter case, `except` is the user being renamed.
+pub async fn username_available(pool: &DbState, username: &str, except: Option<i64>) -> Result<(), AppError> {
+  let taken = sqlx::query!(
+      r#"
+  SELECT id FROM user
+  WHERE username = ?1 COLLATE NOCASE AND (?2 IS NULL OR id != ?2)
+      "#,
+      username, except
+    )
+    .fetch_optional(pool)
+    .await?;
+
+  match taken {
+    Some(_) => Err(taken_error()),
+    None => Ok(()),
+  }
+}
+
+/// Maps the unique constraint failure of a concurrent registration to the same error as [`username_available`].
+pub fn unique_username(e: sqlx::Error) -> AppError {
+  match &e {
+    sqlx::Error::Database(db) if db.message().contains("UNIQUE constraint failed: user.username") => taken_error(),
+    _ => e.into(),
+  }
+}
+
+fn taken_error() -> AppError {
+  AppError::BadRequest(String::from("username is already taken"))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn message(result: Result<(), AppError>) -> String {
+    match result {
+      Err(AppError::BadRequest(msg)) => msg,
+      Err(_) => panic!("unexpected error"),
+      Ok(_) => panic!("expected an error"),
+    }
+  }
+
+  #[test]
+  fn username() {
+    let policy = CredentialPolicy::default();
+    assert!(policy.validate_username("Janko Hrasko").is_ok());
+    assert!(policy.validate_username("jozef.mak_2-b").is_ok());
+    assert!(policy.validate_username("Žofia").is_ok());
+    assert_eq!(message(policy.validate_username("ab")), "username must be between 3 and 32 characters long");
+    assert!(policy.validate_username(&"a".repeat(33)).is_err());
+    assert!(message(policy.validate_username("jano@example")).contains("'@'"));
+    assert!(policy.validate_username(" jano").is_err());
+  }
+
+  #[test]
+  fn password() {
+    let policy = CredentialPolicy { password_min_length: 10, ..CredentialPolicy::default() };
+    assert!(policy.validate_password("long enough password").is_ok());
+    assert_eq!(message(policy.validate_password("short")), "password must be between 10 and 128 characters long");
+    assert!(policy.validate_password("Password123").is_err());
+    // digests are checked against the list, but not for length
+    assert!(policy.validate_password(&prehash_password("short")).is_ok());
+    assert!(policy.validate_password(&prehash_password("password")).is_err());
+    assert!(policy.validate_password(&prehash_password("password").to_uppercase()).is_err());
+
+    // without pre-hashed passwords a digest is checked as any other password
+    let policy = CredentialPolicy { accept_prehashed: false, ..CredentialPolicy::default() };
+    assert!(policy.validate_password(&prehash_password("password")).is_ok());
+  }
+}
@@ -0,0 +1,420 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_action_authorization, event_member_authorization}, user::User, notification::{NotifierState, NotificationKind, insert_db_notification}, webhook::{WebhookQueue, WebhookEventType}, live::LiveUpdates};
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum VoteAnswer {
+  Yes,
+  Maybe,
+  No,
+}
+
+#[derive(Serialize)]
+pub struct DatePoll {
+  id: i64,
+  event: i64,
+  finalized_slot: Option<i64>,
+  best_slot: Option<i64>,
+  slots: Vec<DateSlot>,
+}
+
+#[derive(Serialize)]
+pub struct DateSlot {
+  id: i64,
+  time: i64,
+  yes: i64,
+  maybe: i64,
+  no: i64,
+  votes: Vec<DateVote>,
+}
+
+#[derive(Serialize)]
+pub struct DateVote {
+  user: User,
+  answer: VoteAnswer,
+}
+
+#[derive(Deserialize)]
+pub struct CreateDatePoll {
+  slots: Vec<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct Vote {
+  slot: i64,
+  answer: VoteAnswer,
+}
+
+#[derive(Deserialize)]
+pub struct Votes {
+  votes: Vec<Vote>,
+}
+
+#[derive(Deserialize)]
+pub struct FinalizeDatePoll {
+  slot: i64,
+}
+
+/// The best slot has the most yes votes with maybe counting half,
+/// ties go to more yes votes and then to the earlier time.
+fn best_slot(slots: &[DateSlot]) -> Option<i64> {
+  slots
+    .iter()
+    .filter(|s| s.yes + s.maybe > 0)
+    .max_by(|a, b| {
+      (2 * a.yes + a.maybe).cmp(&(2 * b.yes + b.maybe))
+        .then(a.yes.cmp(&b.yes))
+        .then(b.time.cmp(&a.time))
+    })
+    .map(|s| s.id)
+}
+
+async fn get_db_date_poll(pool: &DbState, event_id: i64) -> Result<DatePoll, AppError> {
+  let poll = sqlx::query!(r#"SELECT id as "id!", finalized_slot FROM date_poll WHERE event = ?1"#, event_id)
+    .fetch_optional(pool)
+    .await?;
+  let poll = match poll {
+    Some(p) => p,
+    None => return Err(AppError::NotFound(format!("Cannot find date poll of event: {event_id}"))),
+  };
+
+  let mut slots: Vec<DateSlot> = sqlx::query!(r#"SELECT id as "id!", time FROM date_slot WHERE poll = ?1 ORDER BY time, id"#, poll.id)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|s| DateSlot {
+      id: s.id,
+      time: s.time,
+      yes: 0,
+      maybe: 0,
+      no: 0,
+      votes: vec![],
+    })
+    .collect();
+
+  let votes = sqlx::query!(
+      r#"
+  SELECT date_vote.slot, user.id as "id!", user.username, date_vote.answer as "answer: VoteAnswer"
+  FROM date_vote
+  JOIN user ON date_vote.user = user.id
+  WHERE date_vote.slot in (SELECT id FROM date_slot WHERE poll = ?1)
+  ORDER BY user.id
+      "#,
+      poll.id
+    )
+    .fetch_all(pool)
+    .await?;
+  for v in votes {
+    if let Some(s) = slots.iter_mut().find(|s| s.id == v.slot) {
+      match v.answer {
+        VoteAnswer::Yes => s.yes += 1,
+        VoteAnswer::Maybe => s.maybe += 1,
+        VoteAnswer::No => s.no += 1,
+      }
+      s.votes.push(DateVote {
+        user: User {
+          id: v.id,
+          username: v.username,
+        },
+        answer: v.answer,
+      });
+    }
+  }
+
+  Ok(DatePoll {
+    id: poll.id,
+    event: event_id,
+    finalized_slot: poll.finalized_slot,
+    best_slot: best_slot(&slots),
+    slots,
+  })
+}
+
+pub async fn create(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<CreateDatePoll>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<DatePoll>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot create date poll for event that user doesn't own").await?;
+  let mut slots = payload.slots;
+  slots.sort_unstable();
+  slots.dedup();
+  if slots.is_empty() {
+    return Err(AppError::BadRequest(String::from("date poll needs at least one slot")));
+  }
+
+  let existing = sqlx::query!("SELECT id FROM date_poll WHERE event = ?1", event_id)
+    .fetch_optional(&pool)
+    .await?;
+  if existing.is_some() {
+    return Err(AppError::BadRequest(format!("event {event_id} already has a date poll")));
+  }
+  // the poll decides the time of the event, so it cannot be fixed already
+  let event = sqlx::query!("SELECT time FROM event WHERE id = ?1", event_id)
+    .fetch_one(&pool)
+    .await?;
+  if event.time.is_some() {
+    return Err(AppError::BadRequest(format!("time of event {event_id} is already fixed")));
+  }
+
+  let created_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!("INSERT INTO date_poll ( event, created_at ) VALUES ( ?1, ?2 )", event_id, created_at)
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+  for time in slots {
+    let _ = sqlx::query!("INSERT INTO date_slot ( poll, time ) VALUES ( ?1, ?2 )", id, time)
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  Ok((StatusCode::CREATED, Json(poll)))
+}
+
+pub async fn get(
+  Path(event_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<DatePoll>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get date poll of event that user doesn't take part in").await?;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  Ok((StatusCode::OK, Json(poll)))
+}
+
+pub async fn vote(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<Votes>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<DatePoll>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot vote in date poll of event that user doesn't take part in").await?;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  if poll.finalized_slot.is_some() {
+    return Err(AppError::BadRequest(String::from("date poll is already finalized")));
+  }
+  if let Some(v) = payload.votes.iter().find(|v| !poll.slots.iter().any(|s| s.id == v.slot)) {
+    return Err(AppError::BadRequest(format!("slot {} doesn't belong to date poll of event {event_id}", v.slot)));
+  }
+
+  let mut tx = pool.begin().await?;
+  for Vote { slot, answer } in payload.votes {
+    let _ = sqlx::query!(
+        r#"
+  INSERT INTO date_vote ( slot, user, answer )
+  VALUES ( ?1, ?2, ?3 )
+  ON CONFLICT(slot, user) DO UPDATE SET answer = ?3
+        "#,
+        slot, auth_userid, answer
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  Ok((StatusCode::OK, Json(poll)))
+}
+
+pub async fn finalize(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<FinalizeDatePoll>,
+  Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<DatePoll>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot finalize date poll for event that user doesn't own").await?;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  if poll.finalized_slot.is_some() {
+    return Err(AppError::BadRequest(String::from("date poll is already finalized")));
+  }
+  let slot = match poll.slots.iter().find(|s| s.id == payload.slot) {
+    Some(s) => s,
+    None => return Err(AppError::BadRequest(format!("slot {} doesn't belong to date poll of event {event_id}", payload.slot))),
+  };
+
+  let mut voters: Vec<i64> = poll.slots.iter().flat_map(|s| s.votes.iter().map(|v| v.user.id)).collect();
+  voters.sort_unstable();
+  voters.dedup();
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE event SET time = ?2 WHERE id = ?1", event_id, slot.time)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("UPDATE date_poll SET finalized_slot = ?2 WHERE id = ?1", poll.id, slot.id)
+    .execute(&mut tx)
+    .await?;
+  let event = sqlx::query!("SELECT name, description, time FROM event WHERE id = ?1", event_id)
+    .fetch_one(&mut tx)
+    .await?;
+  let message = format!("The date of event '{}' has been set", event.name);
+  let mut notifications = vec![];
+  for user in voters.into_iter().filter(|u| *u != auth_userid) {
+    notifications.extend(insert_db_notification(&mut tx, user, NotificationKind::EventTimeFinalized, message.clone(), Some(event_id), None, None).await?);
+  }
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+  let data = json!({
+    "id": event_id,
+    "name": event.name,
+    "description": event.description,
+    "time": event.time,
+  });
+  live.publish(event_id, WebhookEventType::EventUpdated, data.clone()).await;
+  webhooks.dispatch(event_id, WebhookEventType::EventUpdated, data).await;
+
+  let poll = get_db_date_poll(&pool, event_id).await?;
+  Ok((StatusCode::OK, Json(poll)))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  async fn without_time(pool: &DbState) {
+    sqlx::query("UPDATE event SET time = NULL WHERE id = 1")
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  async fn insert_poll(pool: &DbState) {
+    without_time(pool).await;
+    sqlx::query("INSERT INTO date_poll (id, event, created_at) VALUES (1, 1, 1664841600)")
+      .execute(pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO date_slot (id, poll, time) VALUES (1, 1, 1665014400), (2, 1, 1665100800)")
+      .execute(pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO date_vote (slot, user, answer) VALUES (1, 2, 'maybe'), (2, 2, 'yes'), (1, 3, 'yes'), (2, 3, 'no')")
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn create() {
+    let (app, pool) = setup_with_data().await;
+    without_time(&pool).await;
+    let body_json = json!({
+      "slots": [1665100800, 1665014400],
+    });
+    let expected_response = json!({
+      "id": 1,
+      "event": 1,
+      "finalized_slot": null,
+      "best_slot": null,
+      "slots": [
+        { "id": 1, "time": 1665014400, "yes": 0, "maybe": 0, "no": 0, "votes": [] },
+        { "id": 2, "time": 1665100800, "yes": 0, "maybe": 0, "no": 0, "votes": [] }
+      ]
+    });
+
+    let response = test_api(app, "/event/1/datepoll", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+    assert_eq!(response, Some(expected_response));
+  }
+
+  #[tokio::test]
+  async fn create_with_time() {
+    let (app, _) = setup_with_data().await;
+    let body_json = json!({
+      "slots": [1665014400],
+    });
+
+    let _ = test_api(app, "/event/1/datepoll", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+  }
+
+  #[tokio::test]
+  async fn create_for_another() {
+    let (app, _) = setup_with_data().await;
+    let body_json = json!({
+      "slots": [1665014400],
+    });
+
+    let _ = test_api(app, "/event/1/datepoll", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+  }
+
+  #[tokio::test]
+  async fn results() {
+    let (app, pool) = setup_with_data().await;
+    insert_poll(&pool).await;
+
+    let response = test_api(app, "/event/1/datepoll", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+    assert_eq!(response["best_slot"], 1);
+    assert_eq!(response["slots"][0]["yes"], 1);
+    assert_eq!(response["slots"][0]["maybe"], 1);
+    assert_eq!(response["slots"][1]["no"], 1);
+  }
+
+  #[tokio::test]
+  async fn vote() {
+    let (app, pool) = setup_with_data().await;
+    insert_poll(&pool).await;
+    let body_json = json!({
+      "votes": [{ "slot": 2, "answer": "yes" }],
+    });
+
+    let response = test_api(app, "/event/1/datepoll/votes", http::Method::PUT, Some(body_json), StatusCode::OK, Some(("3", "username3"))).await.unwrap();
+    assert_eq!(response["best_slot"], 2);
+  }
+
+  #[tokio::test]
+  async fn vote_as_outsider() {
+    let (app, pool) = setup_with_data().await;
+    insert_poll(&pool).await;
+    let body_json = json!({
+      "votes": [{ "slot": 1, "answer": "yes" }],
+    });
+
+    let _ = test_api(app, "/event/1/datepoll/votes", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("5", "username5"))).await;
+  }
+
+  #[tokio::test]
+  async fn finalize() {
+    let (app, pool) = setup_with_data().await;
+    insert_poll(&pool).await;
+    let body_json = json!({
+      "slot": 2,
+    });
+
+    let response = test_api(app, "/event/1/datepoll/finalize", http::Method::POST, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    assert_eq!(response["finalized_slot"], 2);
+
+    let result = sqlx::query!("select time from event where id = 1")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(result.time, Some(1665100800));
+
+    let results = sqlx::query!("select user from notification where kind = 'event_time_finalized' order by user")
+      .fetch_all(&pool)
+      .await
+      .unwrap();
+    assert_eq!(results.iter().map(|n| n.user).collect::<Vec<_>>(), vec![2, 3]);
+  }
+}
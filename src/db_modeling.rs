@@ -1,6 +1,8 @@
 use axum::Extension;
+use chrono::Utc;
+use sqlx::SqliteConnection;
 
-use crate::{DbState, error};
+use crate::{DbState, error::{self, AppError}, requirement::Priority, expense::Cost, notification::{Notification, NotificationKind, insert_db_notification, insert_db_participant_notifications}};
 
 pub async fn database_up(
   Extension(pool): Extension<DbState>
@@ -31,7 +33,266 @@ pub trait Updatable {
 }
 
 
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_db_requirement(conn: &mut SqliteConnection, name: &str, description: &Option<String>, event: i64, size: i64, category: Option<i64>, priority: Priority, deadline: Option<i64>) -> Result<i64, error::AppError> {
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO requirement ( name, description, event, size, position, category, priority, deadline )
+  VALUES ( ?1, ?2, ?3, ?4, (SELECT COALESCE(MAX(position), 0) + 1 FROM requirement WHERE event = ?3), ?5, ?6, ?7 )
+      "#,
+      name, description, event, size, category, priority, deadline
+    )
+    .execute(&mut *conn)
+    .await?
+    .last_insert_rowid();
+
+  Ok(id)
+}
+
+/// Removes the latest fullfillments over the size, returns the users who lost them and their notifications.
+pub async fn trim_db_fullfillments(conn: &mut SqliteConnection, requirement: i64, size: i64) -> Result<(Vec<i64>, Vec<Notification>), error::AppError> {
+  let mut fullfillments = sqlx::query!("SELECT requirement, user FROM fullfillment WHERE requirement = ?1 AND status != 'cancelled' ORDER BY pledged_at", requirement)
+    .fetch_all(&mut *conn)
+    .await?;
+  let extra_fullfillments: Vec<_> = fullfillments.drain((size.clamp(0, fullfillments.len() as i64) as usize)..).collect();
+  if extra_fullfillments.is_empty() {
+    return Ok((vec![], vec![]));
+  }
+  let r = sqlx::query!("SELECT name, event FROM requirement WHERE id = ?1", requirement)
+    .fetch_one(&mut *conn)
+    .await?;
+
+  let mut evicted = vec![];
+  let mut notifications = vec![];
+  for ef in extra_fullfillments {
+    let _ = sqlx::query!(
+      r#"DELETE FROM fullfillment WHERE requirement = ?1 AND user = ?2"#,
+      ef.requirement, ef.user
+    )
+    .execute(&mut *conn)
+    .await?;
+    let message = format!("You no longer fullfill requirement '{}' because it needs fewer people now", r.name);
+    notifications.extend(insert_db_notification(&mut *conn, ef.user, NotificationKind::RequirementEvicted, message, Some(r.event), Some(requirement), None).await?);
+    evicted.push(ef.user);
+  }
+
+  Ok((evicted, notifications))
+}
+
+pub async fn insert_db_fullfillment(conn: &mut SqliteConnection, requirement: i64, user: i64, note: &Option<String>, cost: &Option<Cost>) -> Result<i64, error::AppError> {
+  let maximum = sqlx::query!(
+    r#"
+SELECT size FROM requirement WHERE id = ?1
+    "#,
+    requirement
+  )
+  .fetch_optional(&mut *conn)
+  .await?;
+
+  if maximum.is_none() {
+    return Err(AppError::NotFound(format!("Cannot find requirement: {requirement}")))
+  }
+  let maximum = maximum.unwrap();
+  let existing = sqlx::query!(
+    r#"
+SELECT count(1) as size FROM fullfillment WHERE requirement = ?1 AND status != 'cancelled'
+    "#,
+    requirement
+  )
+  .fetch_one(&mut *conn)
+  .await?;
+
+  if existing.size as i64 >= maximum.size {
+    return Err(AppError::BadRequest(format!("Maximum number of user for this requirement exeeded: {requirement}")))
+  }
+
+  // a cancelled fullfillment can be pledged again by the same user
+  let pledged_at = Utc::now().timestamp();
+  let (cost_amount, cost_currency) = match cost {
+    Some(c) => (Some(c.amount), Some(c.currency.as_str())),
+    None => (None, None),
+  };
+  let inserted = sqlx::query!(
+      r#"
+  INSERT INTO fullfillment ( requirement, user, note, pledged_at, cost_amount, cost_currency )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+  ON CONFLICT(user, requirement) DO UPDATE
+  SET status = 'pledged', note = ?3, pledged_at = ?4, confirmed_at = NULL, delivered_at = NULL, cancelled_at = NULL, cost_amount = ?5, cost_currency = ?6
+  WHERE status = 'cancelled'
+      "#,
+      requirement, user, note, pledged_at, cost_amount, cost_currency
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+  if inserted == 0 {
+    return Err(AppError::BadRequest(format!("User already fullfills requirement: {requirement}")))
+  }
+
+  Ok(pledged_at)
+}
+
 pub async fn delete_db_user(pool: &DbState, id: i64) -> Result<(), error::AppError> {
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM notification
+WHERE user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM notification_preference
+WHERE user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM user_token
+WHERE user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM recovery_code
+WHERE user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM user_identity
+WHERE user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM oidc_login
+WHERE link_user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+UPDATE notification SET event = NULL, requirement = NULL, announcement = NULL
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM live_update
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook_delivery
+WHERE webhook in (SELECT id FROM webhook WHERE user = ?1 OR event IN (SELECT id FROM event WHERE event.creator = ?1))
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook_subscription
+WHERE webhook in (SELECT id FROM webhook WHERE user = ?1 OR event IN (SELECT id FROM event WHERE event.creator = ?1))
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook
+WHERE user = ?1 OR event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM announcement_receipt
+WHERE user = ?1 OR announcement in (SELECT id FROM announcement WHERE event in (SELECT id FROM event WHERE event.creator = ?1))
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM announcement
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM assignment
+WHERE requirement in
+  (SELECT id FROM requirement WHERE requirement.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+OR user = ?1
+OR assigned_by = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM preference
+WHERE requirement in
+  (SELECT id FROM requirement WHERE requirement.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+OR user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
   let _ = sqlx::query!(
     r#"
 DELETE FROM fullfillment
@@ -56,6 +317,16 @@ WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
   .execute(pool)
   .await?;
 
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM category
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
   let _ = sqlx::query!(
     r#"
 DELETE FROM participant
@@ -67,6 +338,128 @@ OR user = ?1
   .execute(pool)
   .await?;
 
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM comment_mention
+WHERE comment in
+  (SELECT id FROM comment WHERE comment.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+OR user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+UPDATE comment SET body = '', deleted_at = strftime('%s', 'now')
+WHERE user = ?1 AND deleted_at IS NULL
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM comment
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll_answer
+WHERE poll in
+  (SELECT id FROM poll WHERE poll.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+OR user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll_option
+WHERE poll in
+  (SELECT id FROM poll WHERE poll.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_vote
+WHERE slot in
+  (SELECT id FROM date_slot WHERE date_slot.poll in
+    (SELECT id FROM date_poll WHERE date_poll.event in
+      (SELECT id FROM event WHERE event.creator = ?1)
+    )
+  )
+OR user = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_slot
+WHERE poll in
+  (SELECT id FROM date_poll WHERE date_poll.event in
+    (SELECT id FROM event WHERE event.creator = ?1)
+  )
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_poll
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM invitation
+WHERE event in (SELECT id FROM event WHERE event.creator = ?1)
+OR user = ?1
+OR invited_by = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
   let _ = sqlx::query!(
       r#"
   DELETE FROM event
@@ -78,6 +471,26 @@ OR user = ?1
     .await?;
 
 
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM template_requirement
+WHERE template in (SELECT id FROM template WHERE template.creator = ?1)
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM template
+WHERE creator = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
   let _ = sqlx::query!(
       r#"
   DELETE FROM user
@@ -91,7 +504,103 @@ OR user = ?1
   Ok(())
 }
 
-pub async fn delete_db_event(pool: &DbState, id: i64) -> Result<(), error::AppError> {
+pub async fn delete_db_event(conn: &mut SqliteConnection, id: i64) -> Result<Vec<Notification>, error::AppError> {
+  let event = sqlx::query!("SELECT name, creator FROM event WHERE id = ?1", id)
+    .fetch_one(&mut *conn)
+    .await?;
+  let message = format!("Event '{}' has been deleted", event.name);
+  let notifications = insert_db_participant_notifications(&mut *conn, id, event.creator, NotificationKind::EventDeleted, message).await?;
+
+  let _ = sqlx::query!(
+    r#"
+UPDATE notification SET event = NULL, requirement = NULL, announcement = NULL
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM live_update
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook_delivery
+WHERE webhook in (SELECT id FROM webhook WHERE event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook_subscription
+WHERE webhook in (SELECT id FROM webhook WHERE event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM webhook
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM announcement_receipt
+WHERE announcement in (SELECT id FROM announcement WHERE event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM announcement
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM assignment
+WHERE requirement in (SELECT id FROM requirement WHERE requirement.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM preference
+WHERE requirement in (SELECT id FROM requirement WHERE requirement.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
   let _ = sqlx::query!(
     r#"
 DELETE FROM fullfillment
@@ -99,7 +608,7 @@ WHERE requirement in (SELECT id FROM requirement WHERE requirement.event = ?1)
     "#,
     id
   )
-  .execute(pool)
+  .execute(&mut *conn)
   .await?;
 
   let _ = sqlx::query!(
@@ -109,7 +618,17 @@ WHERE event = ?1
     "#,
     id
   )
-  .execute(pool)
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM category
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
   .await?;
 
   let _ = sqlx::query!(
@@ -119,7 +638,98 @@ WHERE event = ?1
     "#,
     id
   )
-  .execute(pool)
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM comment_mention
+WHERE comment in (SELECT id FROM comment WHERE comment.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM comment
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll_answer
+WHERE poll in (SELECT id FROM poll WHERE poll.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll_option
+WHERE poll in (SELECT id FROM poll WHERE poll.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM poll
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_vote
+WHERE slot in
+  (SELECT id FROM date_slot WHERE date_slot.poll in (SELECT id FROM date_poll WHERE date_poll.event = ?1))
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_slot
+WHERE poll in (SELECT id FROM date_poll WHERE date_poll.event = ?1)
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM date_poll
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM invitation
+WHERE event = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
   .await?;
 
   let _ = sqlx::query!(
@@ -129,13 +739,53 @@ WHERE event = ?1
       "#,
       id
     )
-    .execute(pool)
+    .execute(&mut *conn)
     .await?;
 
-  Ok(())
+  Ok(notifications)
 }
 
-pub async fn delete_db_requirement(pool: &DbState, id: i64) -> Result<(), error::AppError> {
+pub async fn delete_db_requirement(conn: &mut SqliteConnection, id: i64) -> Result<(), error::AppError> {
+  let _ = sqlx::query!(
+    r#"
+UPDATE comment SET requirement = NULL
+WHERE requirement = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+UPDATE notification SET requirement = NULL
+WHERE requirement = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM assignment
+WHERE requirement = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM preference
+WHERE requirement = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
   let _ = sqlx::query!(
     r#"
 DELETE FROM fullfillment
@@ -143,12 +793,36 @@ WHERE requirement = ?1
     "#,
     id
   )
-  .execute(pool)
+  .execute(&mut *conn)
   .await?;
 
   let _ = sqlx::query!(
     r#"
 DELETE FROM requirement
+WHERE id = ?1
+    "#,
+    id
+  )
+  .execute(&mut *conn)
+  .await?;
+
+  Ok(())
+}
+
+pub async fn delete_db_template(pool: &DbState, id: i64) -> Result<(), error::AppError> {
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM template_requirement
+WHERE template = ?1
+    "#,
+    id
+  )
+  .execute(pool)
+  .await?;
+
+  let _ = sqlx::query!(
+    r#"
+DELETE FROM template
 WHERE id = ?1
     "#,
     id
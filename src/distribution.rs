@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+
+use axum::{
+  Json, Extension, extract::Path,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, db_modeling, utils::AppReponse, auth::{UserAuth, user_action_authorization, event_action_authorization}, fullfillment, webhook::WebhookQueue, live::LiveUpdates};
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PreferenceKind {
+  Preferred,
+  Excluded,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePreference {
+  kind: PreferenceKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ProposedFullfillment {
+  requirement: i64,
+  user: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ApplyDistribution {
+  fullfillments: Vec<ProposedFullfillment>,
+}
+
+struct OpenRequirement {
+  id: i64,
+  open: i64,
+}
+
+// greedy: most constrained requirements first, least loaded participant wins, preferred breaks ties
+fn distribute(
+  requirements: &[OpenRequirement],
+  participants: &[i64],
+  loads: &HashMap<i64, i64>,
+  taken: &[(i64, i64)],
+  preferences: &HashMap<(i64, i64), PreferenceKind>,
+) -> Vec<ProposedFullfillment> {
+  let mut loads: HashMap<i64, i64> = participants.iter().map(|p| (*p, *loads.get(p).unwrap_or(&0))).collect();
+  let is_eligible = |requirement: i64, user: i64| {
+    preferences.get(&(user, requirement)) != Some(&PreferenceKind::Excluded) && !taken.contains(&(user, requirement))
+  };
+
+  let mut requirements: Vec<&OpenRequirement> = requirements.iter().filter(|r| r.open > 0).collect();
+  requirements.sort_by_key(|r| (participants.iter().filter(|p| is_eligible(r.id, **p)).count(), r.id));
+
+  let mut proposed = vec![];
+  for requirement in requirements {
+    let mut candidates: Vec<i64> = participants.iter().copied().filter(|p| is_eligible(requirement.id, *p)).collect();
+    for _ in 0..requirement.open {
+      let best = candidates
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, user)| (
+          loads[user],
+          preferences.get(&(**user, requirement.id)) != Some(&PreferenceKind::Preferred),
+          **user,
+        ))
+        .map(|(idx, user)| (idx, *user));
+      if let Some((idx, user)) = best {
+        candidates.remove(idx);
+        *loads.get_mut(&user).unwrap() += 1;
+        proposed.push(ProposedFullfillment { requirement: requirement.id, user });
+      }
+    }
+  }
+
+  proposed
+}
+
+pub async fn update_preference(
+  Path((user_id, requirement_id)): Path<(i64, i64)>,
+  Json(payload): Json<UpdatePreference>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(user_id, auth_userid, "cannot set preference for another user")?;
+
+  let _ = sqlx::query!(
+      r#"
+  INSERT INTO preference ( user, requirement, kind )
+  VALUES ( ?1, ?2, ?3 )
+  ON CONFLICT(user, requirement) DO UPDATE SET kind = ?3
+      "#,
+      user_id, requirement_id, payload.kind
+    )
+    .execute(&pool)
+    .await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn delete_preference(
+  Path((user_id, requirement_id)): Path<(i64, i64)>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(user_id, auth_userid, "cannot remove preference for another user")?;
+
+  let _ = sqlx::query!(
+      r#"
+  DELETE FROM preference
+  WHERE user = ?1 AND requirement = ?2
+      "#,
+      user_id, requirement_id
+    )
+    .execute(&pool)
+    .await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn preview(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<ProposedFullfillment>>> {
+  event_action_authorization(&pool, id, auth_userid, "cannot distribute requirements for event that user doesn't own").await?;
+
+  let requirements: Vec<OpenRequirement> = sqlx::query!(
+      r#"
+  SELECT requirement.id, requirement.size - COUNT(fullfillment.user) as "open!: i64"
+  FROM requirement
+  LEFT JOIN fullfillment ON fullfillment.requirement = requirement.id AND fullfillment.status != 'cancelled'
+  WHERE requirement.event = ?1
+  GROUP BY requirement.id
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| OpenRequirement { id: r.id, open: r.open })
+    .collect();
+
+  let participants: Vec<i64> = sqlx::query!("SELECT user FROM participant WHERE event = ?1 ORDER BY user", id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|p| p.user)
+    .collect();
+
+  let taken: Vec<(i64, i64)> = sqlx::query!(
+      r#"
+  SELECT fullfillment.user, fullfillment.requirement
+  FROM fullfillment
+  JOIN requirement ON fullfillment.requirement = requirement.id
+  WHERE requirement.event = ?1 AND fullfillment.status != 'cancelled'
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|f| (f.user, f.requirement))
+    .collect();
+
+  let mut loads = HashMap::new();
+  for (user, _) in &taken {
+    *loads.entry(*user).or_insert(0) += 1;
+  }
+
+  let preferences: HashMap<(i64, i64), PreferenceKind> = sqlx::query!(
+      r#"
+  SELECT preference.user, preference.requirement, preference.kind as "kind: PreferenceKind"
+  FROM preference
+  JOIN requirement ON preference.requirement = requirement.id
+  WHERE requirement.event = ?1
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|p| ((p.user, p.requirement), p.kind))
+    .collect();
+
+  let proposed = distribute(&requirements, &participants, &loads, &taken, &preferences);
+
+  Ok((StatusCode::OK, Json(proposed)))
+}
+
+pub async fn apply(
+  Path(id): Path<i64>,
+  Json(payload): Json<ApplyDistribution>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  event_action_authorization(&pool, id, auth_userid, "cannot distribute requirements for event that user doesn't own").await?;
+
+  let mut tx = pool.begin().await?;
+  let mut pledged = vec![];
+  for ProposedFullfillment { requirement, user } in payload.fullfillments {
+    let valid = sqlx::query!(
+        r#"
+  SELECT participant.user FROM participant
+  JOIN requirement ON requirement.event = participant.event
+  WHERE participant.event = ?1 AND participant.user = ?2 AND requirement.id = ?3
+        "#,
+        id, user, requirement
+      )
+      .fetch_optional(&mut tx)
+      .await?;
+    if valid.is_none() {
+      return Err(AppError::BadRequest(format!("User {user} cannot fullfill requirement {requirement} of event {id}")));
+    }
+    let excluded = sqlx::query!("SELECT user FROM preference WHERE user = ?1 AND requirement = ?2 AND kind = ?3", user, requirement, PreferenceKind::Excluded)
+      .fetch_optional(&mut tx)
+      .await?;
+    if excluded.is_some() {
+      return Err(AppError::BadRequest(format!("User {user} excluded themselves from requirement {requirement}")));
+    }
+    // fails with a bad request once the requirement is full
+    let pledged_at = db_modeling::insert_db_fullfillment(&mut tx, requirement, user, &None, &None).await?;
+    pledged.push((requirement, user, pledged_at));
+  }
+  tx.commit().await?;
+  fullfillment::dispatch_pledged(&pool, &webhooks, &live, &pledged).await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  mod algorithm {
+    use super::*;
+
+    #[test]
+    fn balances_load() {
+      let requirements = vec![OpenRequirement { id: 1, open: 2 }, OpenRequirement { id: 2, open: 1 }];
+      let loads = HashMap::from([(2, 1)]);
+      let proposed = distribute(&requirements, &[2, 3, 4], &loads, &[], &HashMap::new());
+
+      assert_eq!(proposed, vec![
+        ProposedFullfillment { requirement: 1, user: 3 },
+        ProposedFullfillment { requirement: 1, user: 4 },
+        ProposedFullfillment { requirement: 2, user: 2 },
+      ]);
+    }
+
+    #[test]
+    fn respects_preferences() {
+      let requirements = vec![OpenRequirement { id: 1, open: 1 }, OpenRequirement { id: 2, open: 1 }];
+      let preferences = HashMap::from([
+        ((3, 1), PreferenceKind::Excluded),
+        ((4, 2), PreferenceKind::Preferred),
+      ]);
+      let proposed = distribute(&requirements, &[3, 4], &HashMap::new(), &[], &preferences);
+
+      assert_eq!(proposed, vec![
+        ProposedFullfillment { requirement: 1, user: 4 },
+        ProposedFullfillment { requirement: 2, user: 3 },
+      ]);
+    }
+
+    #[test]
+    fn skips_taken_and_unfillable() {
+      let requirements = vec![OpenRequirement { id: 1, open: 2 }];
+      let preferences = HashMap::from([((4, 1), PreferenceKind::Excluded)]);
+      let proposed = distribute(&requirements, &[3, 4], &HashMap::new(), &[(3, 1)], &preferences);
+
+      assert_eq!(proposed, vec![]);
+    }
+  }
+
+  mod preview {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, _) = setup_with_data().await;
+      let expected_response = json!([
+        { "requirement": 1, "user": 2 },
+        { "requirement": 2, "user": 3 },
+      ]);
+
+      let response = test_api(app, "/event/1/distribution", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn with_preference() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO preference (user, requirement, kind) VALUES (2, 1, 'excluded')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let expected_response = json!([
+        { "requirement": 1, "user": 3 },
+        { "requirement": 2, "user": 2 },
+      ]);
+
+      let response = test_api(app, "/event/1/distribution", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let _ = test_api(app, "/event/1/distribution", http::Method::GET, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod apply {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "fullfillments": [
+          { "requirement": 1, "user": 2 },
+          { "requirement": 2, "user": 3 },
+        ]
+      });
+
+      let _ = test_api(app, "/event/1/distribution", http::Method::POST, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user, requirement from fullfillment where requirement in (1, 2) order by requirement, user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 3);
+      assert_eq!((results[0].user, results[0].requirement), (2, 1));
+      assert_eq!((results[1].user, results[1].requirement), (4, 1));
+      assert_eq!((results[2].user, results[2].requirement), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn all_or_nothing() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "fullfillments": [
+          { "requirement": 2, "user": 2 },
+          { "requirement": 2, "user": 3 },
+        ]
+      });
+
+      let _ = test_api(app, "/event/1/distribution", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user from fullfillment where requirement = 2")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn not_participant() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "fullfillments": [
+          { "requirement": 2, "user": 6 },
+        ]
+      });
+
+      let _ = test_api(app, "/event/1/distribution", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn excluded() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO preference (user, requirement, kind) VALUES (3, 2, 'excluded')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "fullfillments": [
+          { "requirement": 2, "user": 3 },
+        ]
+      });
+
+      let _ = test_api(app, "/event/1/distribution", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user from fullfillment where requirement = 2")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+  }
+
+  mod preference {
+    use super::*;
+
+    #[tokio::test]
+    async fn update_and_delete() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "kind": "excluded",
+      });
+
+      let _ = test_api(app.clone(), "/preference/2/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+      let result = sqlx::query!("select kind from preference where user = 2 and requirement = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.kind, "excluded");
+
+      let _ = test_api(app, "/preference/2/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+      let results = sqlx::query!("select kind from preference")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "kind": "preferred",
+      });
+
+      let _ = test_api(app, "/preference/2/1", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("3", "username3"))).await;
+    }
+  }
+}
@@ -1,4 +1,5 @@
-use axum::{response::{IntoResponse, Response}, http::StatusCode, Json};
+use axum::{response::{IntoResponse, Response}, http::{StatusCode, header}, Json};
+use serde::Serialize;
 use serde_json::json;
 
 pub enum AppError {
@@ -8,11 +9,34 @@ pub enum AppError {
   Unauthorized(String),
   BadRequest(String),
   Forbidden(String),
+  Validation(String, Vec<ItemError>),
+  /// message and seconds after which the client may retry
+  TooManyRequests(String, i64),
+}
+
+#[derive(Serialize, Debug)]
+pub struct ItemError {
+  pub operation: String,
+  pub index: usize,
+  pub error: String,
 }
 
 impl IntoResponse for AppError {
   fn into_response(self) -> Response {
     let (status, error_message) = match self {
+      AppError::Validation(msg, items) => {
+        let body = Json(json!({
+          "error": msg,
+          "items": items,
+        }));
+        return (StatusCode::BAD_REQUEST, body).into_response();
+      },
+      AppError::TooManyRequests(msg, retry_after) => {
+        let body = Json(json!({
+          "error": msg,
+        }));
+        return (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())], body).into_response();
+      },
       AppError::Unauthorized(msg) => {
         (
           StatusCode::UNAUTHORIZED,
@@ -58,6 +82,21 @@ impl IntoResponse for AppError {
   }
 }
 
+impl std::fmt::Display for AppError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AppError::DB(msg)
+      | AppError::Server(msg)
+      | AppError::NotFound(msg)
+      | AppError::Unauthorized(msg)
+      | AppError::BadRequest(msg)
+      | AppError::Forbidden(msg)
+      | AppError::Validation(msg, _)
+      | AppError::TooManyRequests(msg, _) => f.write_str(msg),
+    }
+  }
+}
+
 impl From<axum::Error> for AppError {
   fn from(e: axum::Error) -> Self {
     AppError::Server(e.to_string())
@@ -88,6 +127,24 @@ impl From<serde_json::Error> for AppError {
   }
 }
 
+impl From<lettre::error::Error> for AppError {
+  fn from(e: lettre::error::Error) -> Self {
+    AppError::Server(e.to_string())
+  }
+}
+
+impl From<lettre::transport::smtp::Error> for AppError {
+  fn from(e: lettre::transport::smtp::Error) -> Self {
+    AppError::Server(e.to_string())
+  }
+}
+
+impl From<lettre::address::AddressError> for AppError {
+  fn from(e: lettre::address::AddressError) -> Self {
+    AppError::Server(e.to_string())
+  }
+}
+
 
 // pub struct AppError((StatusCode, String));
 
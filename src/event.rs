@@ -1,10 +1,14 @@
+use std::collections::HashMap;
+
 use axum::{
   Json, Extension, extract::{Path, Query},
 };
+use chrono::Utc;
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{DbState, error::{AppError}, db_modeling::{Updatable, self}, user::User, utils::AppReponse, auth::{UserAuth, event_action_authorization, user_action_authorization}};
+use crate::{DbState, error::{AppError}, db_modeling::{Updatable, self}, user::User, utils::AppReponse, auth::{UserAuth, OptionalUserAuth, event_action_authorization, user_action_authorization, verified_authorization, restriction_authorization, unverified_restrictions, Restriction}, fullfillment::FullfillmentStatus, requirement::Priority, expense::Cost, poll::{Poll, get_db_polls}, notification::{NotifierState, NotificationKind, insert_db_participant_notifications}, mail::{self, MailQueue, Recipient}, webhook::{self, WebhookQueue, WebhookEventType}, live::LiveUpdates};
 
 #[derive(Serialize)]
 pub struct UpdateEventResponse {
@@ -18,7 +22,7 @@ pub struct DbEvent {
   id: i64,
   name: String,
   description: Option<String>,
-  time: i64,
+  time: Option<i64>,
   creator: i64,
   username: String,
 }
@@ -28,7 +32,7 @@ pub struct Event {
   id: i64,
   name: String,
   description: Option<String>,
-  time: i64,
+  time: Option<i64>,
   creator: User,
 }
 
@@ -38,10 +42,13 @@ pub struct EventDetail {
   id: i64,
   name: String,
   description: Option<String>,
-  time: i64,
+  time: Option<i64>,
   participants: Vec<User>,
   requirements: Vec<Requirement>,
+  categories: Vec<Category>,
+  open_requirements: OpenRequirements,
   fullfillments: Vec<Fullfillment>,
+  polls: Vec<Poll>,
   creator: User,
 }
 
@@ -49,6 +56,13 @@ pub struct EventDetail {
 struct Fullfillment {
   requirement: i64,
   user: User,
+  status: FullfillmentStatus,
+  note: Option<String>,
+  pledged_at: i64,
+  confirmed_at: Option<i64>,
+  delivered_at: Option<i64>,
+  cancelled_at: Option<i64>,
+  cost: Option<Cost>,
 }
 
 #[derive(Serialize)]
@@ -57,14 +71,50 @@ struct Requirement {
   name: String,
   description: Option<String>,
   size: i64,
+  category: Option<i64>,
+  priority: Priority,
+  deadline: Option<i64>,
+  progress: RequirementProgress,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct CreateRequirement {
-  id: Option<i64>,
+#[derive(Serialize)]
+struct Category {
+  id: i64,
   name: String,
-  description: Option<String>,
-  size: Option<i64>,
+  requirements: Vec<i64>,
+}
+
+#[derive(Serialize, Default)]
+struct OpenRequirements {
+  must_have: i64,
+  nice_to_have: i64,
+}
+
+#[derive(Serialize, Default)]
+struct RequirementProgress {
+  pledged: i64,
+  confirmed: i64,
+  delivered: i64,
+  open: i64,
+}
+
+impl RequirementProgress {
+  fn new(size: i64, requirement: i64, fullfillments: &[Fullfillment]) -> Self {
+    let mut progress = fullfillments
+      .iter()
+      .filter(|f| f.requirement == requirement)
+      .fold(RequirementProgress::default(), |mut p, f| {
+        match f.status {
+          FullfillmentStatus::Pledged => p.pledged += 1,
+          FullfillmentStatus::Confirmed => p.confirmed += 1,
+          FullfillmentStatus::Delivered => p.delivered += 1,
+          FullfillmentStatus::Cancelled => {},
+        };
+        p
+      });
+    progress.open = (size - progress.pledged - progress.confirmed - progress.delivered).max(0);
+    progress
+  }
 }
 
 pub async fn create(
@@ -74,6 +124,7 @@ pub async fn create(
 ) -> AppReponse<Json<Event>> {
   let CreateEvent { name, description, time, creator } = payload;
   user_action_authorization(creator, auth_userid, "cannot create event as another user")?;
+  verified_authorization(&pool, auth_userid, Restriction::CreateEvent).await?;
 
   let id = sqlx::query!(
       r#"
@@ -114,6 +165,7 @@ pub async fn create(
 pub async fn single(
   Path(id): Path<i64>,
   Extension(pool): Extension<DbState>,
+  OptionalUserAuth(auth): OptionalUserAuth,
 ) -> AppReponse<Json<EventDetail>> {
   let event = sqlx::query_as!(DbEvent,
       r#"
@@ -139,19 +191,10 @@ pub async fn single(
     .fetch_all(&pool)
     .await?;
 
-    let requirements = sqlx::query_as!(Requirement,
-      r#"
-  SELECT id, name, description, size FROM requirement
-  WHERE requirement.event = ?1
-      "#,
-      id
-    )
-    .fetch_all(&pool)
-    .await?;
-
-    let fullfillments = sqlx::query!(
+    let fullfillments: Vec<Fullfillment> = sqlx::query!(
       r#"
-  SELECT user.id,  user.username, requirement
+  SELECT user.id,  user.username, requirement, status as "status: FullfillmentStatus", note,
+    pledged_at, confirmed_at, delivered_at, cancelled_at, cost_amount, cost_currency
   FROM fullfillment
   JOIN user on fullfillment.user = user.id
   WHERE fullfillment.requirement in (
@@ -169,10 +212,65 @@ pub async fn single(
         id: f.id,
         username: f.username
       },
-      requirement: f.requirement
+      requirement: f.requirement,
+      status: f.status,
+      note: f.note,
+      pledged_at: f.pledged_at,
+      confirmed_at: f.confirmed_at,
+      delivered_at: f.delivered_at,
+      cancelled_at: f.cancelled_at,
+      cost: Cost::from_db(f.cost_amount, f.cost_currency),
     })
     .collect();
 
+    let requirements: Vec<Requirement> = sqlx::query!(
+      r#"
+  SELECT id as "id!", name, description, size, category, priority as "priority: Priority", deadline FROM requirement
+  WHERE requirement.event = ?1
+  ORDER BY priority = 'nice_to_have', position, id
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| Requirement {
+      progress: RequirementProgress::new(r.size, r.id, &fullfillments),
+      id: r.id,
+      name: r.name,
+      description: r.description,
+      size: r.size,
+      category: r.category,
+      priority: r.priority,
+      deadline: r.deadline,
+    })
+    .collect();
+
+    let categories = sqlx::query!("SELECT id, name FROM category WHERE event = ?1 ORDER BY id", id)
+      .fetch_all(&pool)
+      .await?
+      .into_iter()
+      .map(|c| Category {
+        requirements: requirements.iter().filter(|r| r.category == Some(c.id)).map(|r| r.id).collect(),
+        id: c.id,
+        name: c.name,
+      })
+      .collect();
+
+    let open_requirements = requirements
+      .iter()
+      .fold(OpenRequirements::default(), |mut o, r| {
+        match r.priority {
+          Priority::MustHave => o.must_have += r.progress.open,
+          Priority::NiceToHave => o.nice_to_have += r.progress.open,
+        };
+        o
+      });
+
+    // polls are only visible to the organizer and participants
+    let member = auth.is_some_and(|auth_userid| auth_userid == d.creator || participants.iter().any(|p| p.id == auth_userid));
+    let polls = if member { get_db_polls(&pool, id, None).await? } else { vec![] };
+
     let event_detail = EventDetail {
       id,
       name: d.name,
@@ -184,7 +282,10 @@ pub async fn single(
       },
       participants,
       requirements,
+      categories,
+      open_requirements,
       fullfillments,
+      polls,
     };
     Ok((StatusCode::OK, Json(event_detail)))
   } else {
@@ -234,6 +335,9 @@ pub async fn update(
   Path(id): Path<i64>,
   Json(payload): Json<UpdateEvent>,
   Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<Json<UpdateEventResponse>> {
   if !payload.validate() {
@@ -242,11 +346,30 @@ pub async fn update(
   event_action_authorization(&pool, id, auth_userid, "cannot change event that user doesn't own").await?;
 
   let sql = format!("UPDATE event SET {} WHERE id = ?1", payload.update_string());
+  let mut tx = pool.begin().await?;
   let _ = sqlx::QueryBuilder::new(sql)
     .build()
     .bind(id)
-    .execute(&pool)
+    .execute(&mut tx)
+    .await?;
+  let event = sqlx::query!("SELECT name, description, time FROM event WHERE id = ?1", id)
+    .fetch_one(&mut tx)
     .await?;
+  let message = format!("Event '{}' has been updated", event.name);
+  let notifications = insert_db_participant_notifications(&mut tx, id, auth_userid, NotificationKind::EventUpdated, message).await?;
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+  let data = json!({
+    "id": id,
+    "name": event.name,
+    "description": event.description,
+    "time": event.time,
+  });
+  live.publish(id, WebhookEventType::EventUpdated, data.clone()).await;
+  webhooks.dispatch(id, WebhookEventType::EventUpdated, data).await;
 
   let response = UpdateEventResponse {
     name: payload.name,
@@ -260,13 +383,148 @@ pub async fn update(
 pub async fn delete(
   Path(id): Path<i64>,
   Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   event_action_authorization(&pool, id, auth_userid, "cannot delete event that user doesn't own").await?;
 
-  db_modeling::delete_db_event(&pool, id)
-    .await
-    .and_then(|r| Ok((StatusCode::NO_CONTENT, r)))
+  let event = sqlx::query!("SELECT name FROM event WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  let mut tx = pool.begin().await?;
+  // stored while the participants are still known, deliveries to the webhooks of the event are removed with them
+  let data = json!({ "id": id, "name": event.name });
+  let deliveries = webhook::insert_db_deliveries(&mut tx, id, WebhookEventType::EventDeleted, data.clone()).await?;
+  let notifications = db_modeling::delete_db_event(&mut tx, id).await?;
+  tx.commit().await?;
+  live.publish_deleted(id, data);
+  webhooks.enqueue_all(deliveries);
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn clone(
+  Path(id): Path<i64>,
+  Json(payload): Json<CloneEvent>,
+  Extension(pool): Extension<DbState>,
+  Extension(mail): Extension<MailQueue>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Event>> {
+  let event = clone_event(&pool, &mail, id, payload, auth_userid, &unverified_restrictions()).await?;
+  Ok((StatusCode::CREATED, Json(event)))
+}
+
+/// Cloning creates a new event, so it is restricted like creating one.
+async fn clone_event(pool: &DbState, mail: &MailQueue, id: i64, payload: CloneEvent, auth_userid: i64, restrictions: &[Restriction]) -> Result<Event, AppError> {
+  restriction_authorization(pool, auth_userid, Restriction::CreateEvent, restrictions).await?;
+  let source = sqlx::query!("SELECT name, description, time, creator FROM event WHERE id = ?1", id)
+    .fetch_optional(pool)
+    .await?;
+  let source = match source {
+    Some(s) => s,
+    None => return Err(AppError::NotFound(format!("{id}"))),
+  };
+  if payload.invite_participants && source.creator != auth_userid {
+    return Err(AppError::Forbidden(String::from("cannot invite participants of event that user doesn't own")));
+  }
+
+  let name = payload.name.unwrap_or(source.name);
+  let description = if payload.exclude.contains(&CloneExclude::Description) { None } else { source.description };
+  let time = Some(payload.time);
+
+  let mut tx = pool.begin().await?;
+  let new_id = sqlx::query!(
+      r#"
+  INSERT INTO event ( name, description, time, creator )
+  VALUES ( ?1, ?2, ?3, ?4 )
+      "#,
+      name, description, time, auth_userid
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+
+  if !payload.exclude.contains(&CloneExclude::Requirements) {
+    let mut categories = HashMap::new();
+    let source_categories = sqlx::query!("SELECT id, name FROM category WHERE event = ?1 ORDER BY id", id)
+      .fetch_all(&mut tx)
+      .await?;
+    for c in source_categories {
+      let category = sqlx::query!("INSERT INTO category ( event, name ) VALUES ( ?1, ?2 )", new_id, c.name)
+        .execute(&mut tx)
+        .await?
+        .last_insert_rowid();
+      categories.insert(c.id, category);
+    }
+
+    let requirements = sqlx::query!(
+        r#"
+  SELECT id as "id!", name, description, size, category, priority as "priority: Priority", deadline FROM requirement
+  WHERE event = ?1
+  ORDER BY position, id
+        "#,
+        id
+      )
+      .fetch_all(&mut tx)
+      .await?;
+    for r in requirements.into_iter().filter(|r| !payload.exclude_requirements.contains(&r.id)) {
+      let category = r.category.and_then(|c| categories.get(&c).copied());
+      // deadlines keep their distance to the event, as far as the source event has a time
+      let deadline = r.deadline.map(|d| source.time.map_or(d, |t| d - t + payload.time));
+      db_modeling::insert_db_requirement(&mut tx, &r.name, &r.description, new_id, r.size, category, r.priority, deadline).await?;
+    }
+  }
+
+  if payload.invite_participants {
+    let created_at = Utc::now().timestamp();
+    let _ = sqlx::query!(
+        r#"
+  INSERT INTO invitation ( user, event, invited_by, created_at )
+  SELECT user, ?2, ?3, ?4 FROM participant
+  WHERE event = ?1 AND user != ?3
+        "#,
+        id, new_id, auth_userid, created_at
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+
+  let user = sqlx::query!("SELECT username FROM user WHERE id = ?1", auth_userid)
+    .fetch_one(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  if payload.invite_participants {
+    let recipients = sqlx::query_as!(Recipient,
+        r#"
+  SELECT user.username, user.email as "email!" FROM invitation
+  JOIN user ON invitation.user = user.id
+  WHERE invitation.event = ?1 AND user.email IS NOT NULL AND user.email_notifications
+        "#,
+        new_id
+      )
+      .fetch_all(pool)
+      .await?;
+    for recipient in recipients {
+      mail.enqueue(mail::templates::invitation(&recipient, &user.username, &name));
+    }
+  }
+
+  Ok(Event {
+    id: new_id,
+    name,
+    description,
+    time,
+    creator: User {
+      id: auth_userid,
+      username: user.username,
+    },
+  })
 }
 
 
@@ -274,10 +532,30 @@ pub async fn delete(
 pub struct CreateEvent {
   name : String,
   description: Option<String>,
-  time: i64,
+  /// left out while the date is still to be decided, e.g. by a date poll
+  time: Option<i64>,
   creator: i64,
 }
 
+#[derive(Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneExclude {
+  Description,
+  Requirements,
+}
+
+#[derive(Deserialize)]
+pub struct CloneEvent {
+  time: i64,
+  name: Option<String>,
+  #[serde(default)]
+  exclude: Vec<CloneExclude>,
+  #[serde(default)]
+  exclude_requirements: Vec<i64>,
+  #[serde(default)]
+  invite_participants: bool,
+}
+
 #[derive(Deserialize)]
 pub struct UpdateEvent {
   name: Option<String>,
@@ -342,6 +620,18 @@ mod test {
         let response = test_api(app, "/event", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
         assert_eq!(response, Some(expected_response));
     }
+
+    #[tokio::test]
+    async fn without_time() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "date to be decided",
+        "creator": 1
+      });
+
+      let response = test_api(app, "/event", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response["time"], json!(null));
+    }
   }
 
   mod get {
@@ -359,16 +649,32 @@ mod test {
           { "id": 3, "username": "username3" }
         ],
         "requirements": [
-          { "id": 1, "name": "req1", "description": "req1-desc", "size": 2 },
-          { "id": 2, "name": "req2", "description": "req2-desc", "size": 1 }
+          {
+            "id": 1, "name": "req1", "description": "req1-desc", "size": 2, "category": null, "priority": "must_have", "deadline": null,
+            "progress": { "pledged": 1, "confirmed": 0, "delivered": 0, "open": 1 }
+          },
+          {
+            "id": 2, "name": "req2", "description": "req2-desc", "size": 1, "category": null, "priority": "must_have", "deadline": null,
+            "progress": { "pledged": 0, "confirmed": 0, "delivered": 0, "open": 1 }
+          }
         ],
+        "categories": [],
+        "open_requirements": { "must_have": 2, "nice_to_have": 0 },
         "fullfillments": [{
           "requirement": 1,
           "user": {
             "id": 4,
             "username": "username4"
-          }
+          },
+          "status": "pledged",
+          "note": null,
+          "pledged_at": 1664841600,
+          "confirmed_at": null,
+          "delivered_at": null,
+          "cancelled_at": null,
+          "cost": null
         }],
+        "polls": [],
         "creator": {
           "id": 1,
           "username": "username1"
@@ -380,6 +686,47 @@ mod test {
       assert_eq!(response, Some(expected_response));
     }
 
+    #[tokio::test]
+    async fn single_grouped() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO category (id, event, name) VALUES (1, 1, 'Food'), (2, 1, 'Drinks')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      sqlx::query("UPDATE requirement SET category = 1, priority = 'nice_to_have' WHERE id = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+      sqlx::query(r#"INSERT INTO requirement (id, name, event, size, position, category) VALUES (5, "req5", 1, 1, 1, 1)"#)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let response = test_api(app, "/event/1", http::Method::GET, None, StatusCode::OK, None).await.unwrap();
+      let ids: Vec<i64> = response["requirements"].as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect();
+      assert_eq!(ids, vec![2, 5, 1]);
+      assert_eq!(response["categories"], json!([
+        { "id": 1, "name": "Food", "requirements": [5, 1] },
+        { "id": 2, "name": "Drinks", "requirements": [] }
+      ]));
+      assert_eq!(response["open_requirements"], json!({ "must_have": 2, "nice_to_have": 1 }));
+    }
+
+    #[tokio::test]
+    async fn single_with_polls() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO poll (id, event, question, kind, created_by, created_at) VALUES (1, 1, 'who needs a ride?', 'text', 1, 1664841600)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let response = test_api(app.clone(), "/event/1", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["polls"].as_array().unwrap().len(), 1);
+
+      let response = test_api(app, "/event/1", http::Method::GET, None, StatusCode::OK, Some(("5", "username5"))).await.unwrap();
+      assert_eq!(response["polls"], json!([]));
+    }
+
     #[tokio::test]
     async fn all() {
       let (app, _) = setup_with_data().await;
@@ -451,6 +798,115 @@ mod test {
     }
   }
 
+  mod clone {
+    use super::*;
+    use std::sync::Arc;
+    use crate::mail::test::MemoryMailer;
+
+    #[tokio::test]
+    async fn unverified() {
+      let (_, pool) = setup_with_data().await;
+      let mail = MailQueue::start(Arc::new(MemoryMailer::default()));
+      let payload = || serde_json::from_value::<CloneEvent>(json!({ "time": 1696464000 })).unwrap();
+      let restrictions = [Restriction::CreateEvent];
+
+      assert!(matches!(clone_event(&pool, &mail, 1, payload(), 2, &restrictions).await, Err(AppError::Forbidden(_))));
+      sqlx::query("UPDATE user SET email_verified_at = 1664841600 WHERE id = 2")
+        .execute(&pool)
+        .await
+        .unwrap();
+      assert!(clone_event(&pool, &mail, 1, payload(), 2, &restrictions).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "time": 1696464000,
+      });
+      let expected_response = json!({
+        "id": 5,
+        "name": "event-1",
+        "description": "some description 1",
+        "time": 1696464000,
+        "creator": {
+          "id": 2,
+          "username": "username2"
+        }
+      });
+
+      let response = test_api(app, "/event/1/clone", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("2", "username2"))).await;
+      assert_eq!(response, Some(expected_response));
+
+      let results = sqlx::query!("select name, size from requirement where event = 5 order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 2);
+      assert_eq!(results[0].name, "req1");
+      assert_eq!(results[0].size, 2);
+      assert_eq!(results[1].name, "req2");
+    }
+
+    #[tokio::test]
+    async fn with_exclusions_and_invitations() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "time": 1696464000,
+        "name": "event-1 next year",
+        "exclude": ["description"],
+        "exclude_requirements": [1],
+        "invite_participants": true
+      });
+
+      let response = test_api(app, "/event/1/clone", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response["name"], "event-1 next year");
+      assert_eq!(response["description"], serde_json::Value::Null);
+
+      let results = sqlx::query!("select name from requirement where event = 5")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].name, "req2");
+
+      let results = sqlx::query!("select user from invitation where event = 5 order by user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 2);
+      assert_eq!(results[0].user, 2);
+      assert_eq!(results[1].user, 3);
+
+      let results = sqlx::query!("select user from participant where event = 5")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn invitations_for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "time": 1696464000,
+        "invite_participants": true
+      });
+
+      let _ = test_api(app, "/event/1/clone", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+
+    #[tokio::test]
+    async fn non_existing() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "time": 1696464000,
+      });
+
+      let _ = test_api(app, "/event/10/clone", http::Method::POST, Some(body_json), StatusCode::NOT_FOUND, Some(("2", "username2"))).await;
+    }
+  }
+
   mod update {
     use super::*;
 
@@ -471,6 +927,12 @@ mod test {
 
       assert_eq!(result.name, "edited name event 1");
       assert_eq!(result.description, Some("some description 1".to_owned()));
+
+      let results = sqlx::query!("select user from notification where kind = 'event_updated' and event = 1 order by user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.iter().map(|n| n.user).collect::<Vec<_>>(), vec![2, 3]);
     }
 
     #[tokio::test]
@@ -487,13 +949,27 @@ mod test {
         .await
         .unwrap();
 
-      assert_eq!(result.time, 1633392000);
+      assert_eq!(result.time, Some(1633392000));
     }
   }
 
   mod delete {
     use super::*;
 
+    #[tokio::test]
+    async fn notifies_participants() {
+      let (app, pool) = setup_with_data().await;
+
+      let _ = test_api(app, "/event/2", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("6", "username6"))).await;
+
+      let results = sqlx::query!("select user, event from notification where kind = 'event_deleted' order by user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.iter().map(|n| n.user).collect::<Vec<_>>(), vec![3, 4]);
+      assert!(results.iter().all(|n| n.event.is_none()));
+    }
+
     #[tokio::test]
     async fn simple() {
       let (app, pool) = setup_with_data().await;
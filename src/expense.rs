@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+
+use axum::{
+  Json, Extension, extract::{Path, Query}, http::header,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_member_authorization}, user::User};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Cost {
+  /// amount in minor units of the currency, e.g. cents
+  pub amount: i64,
+  pub currency: String,
+}
+
+impl Cost {
+  pub fn from_db(amount: Option<i64>, currency: Option<String>) -> Option<Self> {
+    match (amount, currency) {
+      (Some(amount), Some(currency)) => Some(Cost { amount, currency }),
+      _ => None,
+    }
+  }
+
+  pub fn validate(&self) -> Result<Cost, AppError> {
+    if self.amount < 0 {
+      return Err(AppError::BadRequest(String::from("cost amount cannot be negative")));
+    }
+    if self.currency.len() != 3 || !self.currency.chars().all(|c| c.is_ascii_alphabetic()) {
+      return Err(AppError::BadRequest(format!("invalid currency code: {}", self.currency)));
+    }
+    Ok(Cost {
+      amount: self.amount,
+      currency: self.currency.to_ascii_uppercase(),
+    })
+  }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Split {
+  #[default]
+  Equal,
+  Weighted,
+}
+
+#[derive(Deserialize)]
+pub struct SettlementParam {
+  #[serde(default)]
+  split: Split,
+}
+
+#[derive(Serialize)]
+pub struct Expense {
+  requirement: i64,
+  name: String,
+  user: User,
+  cost: Cost,
+}
+
+#[derive(Serialize)]
+pub struct Ledger {
+  expenses: Vec<Expense>,
+  totals: Vec<Cost>,
+}
+
+#[derive(Serialize)]
+pub struct Balance {
+  user: User,
+  paid: i64,
+  share: i64,
+  balance: i64,
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct Transfer {
+  from: i64,
+  to: i64,
+  amount: i64,
+}
+
+#[derive(Serialize)]
+pub struct Settlement {
+  currency: String,
+  total: i64,
+  balances: Vec<Balance>,
+  transfers: Vec<Transfer>,
+}
+
+/// Splits `total` according to `weights`, handing out the remaining minor units
+/// one by one in the given order so that the shares always sum up to `total`.
+fn split(total: i64, weights: &[i64]) -> Vec<i64> {
+  let sum: i64 = weights.iter().sum();
+  if sum == 0 {
+    return vec![0; weights.len()];
+  }
+  let mut shares: Vec<i64> = weights.iter().map(|w| total * w / sum).collect();
+  let mut remainder = total - shares.iter().sum::<i64>();
+  for (share, weight) in shares.iter_mut().zip(weights) {
+    if remainder == 0 {
+      break;
+    }
+    if *weight > 0 {
+      *share += 1;
+      remainder -= 1;
+    }
+  }
+  shares
+}
+
+/// Evens out the balances by repeatedly letting the biggest debtor pay the biggest creditor,
+/// which needs at most one transfer less than there are users with a non zero balance.
+fn settle(balances: &[(i64, i64)]) -> Vec<Transfer> {
+  let mut debtors: Vec<(i64, i64)> = balances.iter().filter(|(_, b)| *b < 0).map(|(u, b)| (*u, -b)).collect();
+  let mut creditors: Vec<(i64, i64)> = balances.iter().filter(|(_, b)| *b > 0).copied().collect();
+
+  let mut transfers = vec![];
+  loop {
+    debtors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    creditors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let (debtor, creditor) = match (debtors.first_mut(), creditors.first_mut()) {
+      (Some(d), Some(c)) if d.1 > 0 && c.1 > 0 => (d, c),
+      _ => break,
+    };
+    let amount = debtor.1.min(creditor.1);
+    debtor.1 -= amount;
+    creditor.1 -= amount;
+    transfers.push(Transfer { from: debtor.0, to: creditor.0, amount });
+  }
+  transfers
+}
+
+async fn get_expenses(pool: &DbState, event_id: i64) -> Result<Vec<Expense>, AppError> {
+  let expenses = sqlx::query!(
+      r#"
+  SELECT requirement.id as "id!", requirement.name, user.id as "user_id!", user.username, fullfillment.cost_amount as "amount!", fullfillment.cost_currency as "currency!"
+  FROM fullfillment
+  JOIN requirement ON fullfillment.requirement = requirement.id
+  JOIN user ON fullfillment.user = user.id
+  WHERE requirement.event = ?1 AND fullfillment.status != 'cancelled' AND fullfillment.cost_amount IS NOT NULL
+  ORDER BY requirement.position, requirement.id, user.id
+      "#,
+      event_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|e| Expense {
+      requirement: e.id,
+      name: e.name,
+      user: User {
+        id: e.user_id,
+        username: e.username,
+      },
+      cost: Cost {
+        amount: e.amount,
+        currency: e.currency,
+      },
+    })
+    .collect();
+
+  Ok(expenses)
+}
+
+async fn get_settlements(pool: &DbState, event_id: i64, split_by: Split) -> Result<Vec<Settlement>, AppError> {
+  let expenses = get_expenses(pool, event_id).await?;
+
+  // the organizer takes part in the costs with a single share
+  let mut members: Vec<(User, i64)> = sqlx::query!(
+      r#"
+  SELECT user.id, user.username, 1 as "share!: i64" FROM event JOIN user ON event.creator = user.id
+  WHERE event.id = ?1
+  UNION ALL
+  SELECT user.id, user.username, participant.share FROM participant JOIN user ON participant.user = user.id
+  WHERE participant.event = ?1 AND participant.user != (SELECT creator FROM event WHERE id = ?1)
+      "#,
+      event_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|m| (User { id: m.id, username: m.username }, m.share))
+    .collect();
+  members.sort_by_key(|(u, _)| u.id);
+  // participants with a share of 0 don't attend, so they take no part in the costs of either split
+  let weights: Vec<i64> = members
+    .iter()
+    .map(|(_, share)| if split_by == Split::Weighted || *share == 0 { *share } else { 1 })
+    .collect();
+
+  let mut currencies: BTreeMap<String, Vec<&Expense>> = BTreeMap::new();
+  for e in &expenses {
+    currencies.entry(e.cost.currency.clone()).or_default().push(e);
+  }
+
+  let mut settlements = vec![];
+  for (currency, expenses) in currencies {
+    let total = expenses.iter().map(|e| e.cost.amount).sum();
+    let shares = split(total, &weights);
+
+    let mut balances: Vec<Balance> = members
+      .iter()
+      .zip(shares)
+      .map(|((user, _), share)| Balance {
+        user: User { id: user.id, username: user.username.clone() },
+        paid: 0,
+        share,
+        balance: 0,
+      })
+      .collect();
+    for e in expenses {
+      match balances.iter_mut().find(|b| b.user.id == e.user.id) {
+        Some(b) => b.paid += e.cost.amount,
+        // users who left the event are still owed what they paid
+        None => balances.push(Balance {
+          user: User { id: e.user.id, username: e.user.username.clone() },
+          paid: e.cost.amount,
+          share: 0,
+          balance: 0,
+        }),
+      }
+    }
+    for b in balances.iter_mut() {
+      b.balance = b.paid - b.share;
+    }
+
+    let transfers = settle(&balances.iter().map(|b| (b.user.id, b.balance)).collect::<Vec<_>>());
+    settlements.push(Settlement { currency, total, balances, transfers });
+  }
+
+  Ok(settlements)
+}
+
+pub async fn ledger(
+  Path(event_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Ledger>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get expenses of event that user doesn't take part in").await?;
+
+  let expenses = get_expenses(&pool, event_id).await?;
+  let mut totals: BTreeMap<&str, i64> = BTreeMap::new();
+  for e in &expenses {
+    *totals.entry(&e.cost.currency).or_default() += e.cost.amount;
+  }
+  let totals = totals
+    .into_iter()
+    .map(|(currency, amount)| Cost { amount, currency: String::from(currency) })
+    .collect();
+
+  Ok((StatusCode::OK, Json(Ledger { expenses, totals })))
+}
+
+pub async fn settlement(
+  Path(event_id): Path<i64>,
+  Query(params): Query<SettlementParam>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Settlement>>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get settlement of event that user doesn't take part in").await?;
+
+  let settlements = get_settlements(&pool, event_id, params.split).await?;
+  Ok((StatusCode::OK, Json(settlements)))
+}
+
+pub async fn settlement_csv(
+  Path(event_id): Path<i64>,
+  Query(params): Query<SettlementParam>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<([(header::HeaderName, &'static str); 2], String)> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot get settlement of event that user doesn't take part in").await?;
+
+  let settlements = get_settlements(&pool, event_id, params.split).await?;
+  let mut csv = String::from("currency,from,to,amount\n");
+  for s in settlements {
+    let username = |id: i64| s.balances
+      .iter()
+      .find(|b| b.user.id == id)
+      .map(|b| b.user.username.replace('"', "\"\""))
+      .unwrap_or_default();
+    for t in &s.transfers {
+      csv.push_str(&format!("{},\"{}\",\"{}\",{}\n", s.currency, username(t.from), username(t.to), t.amount));
+    }
+  }
+
+  let headers = [
+    (header::CONTENT_TYPE, "text/csv"),
+    (header::CONTENT_DISPOSITION, "attachment; filename=\"settlement.csv\""),
+  ];
+  Ok((StatusCode::OK, (headers, csv)))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  mod algorithm {
+    use super::*;
+
+    #[test]
+    fn split_with_remainder() {
+      assert_eq!(split(100, &[1, 1, 1]), vec![34, 33, 33]);
+      assert_eq!(split(100, &[2, 1, 1]), vec![50, 25, 25]);
+      assert_eq!(split(10, &[0, 1]), vec![0, 10]);
+    }
+
+    #[test]
+    fn settle_balances() {
+      let transfers = settle(&[(1, 60), (2, -30), (3, -20), (4, -10)]);
+      assert_eq!(transfers, vec![
+        Transfer { from: 2, to: 1, amount: 30 },
+        Transfer { from: 3, to: 1, amount: 20 },
+        Transfer { from: 4, to: 1, amount: 10 },
+      ]);
+    }
+
+    #[test]
+    fn settle_even() {
+      assert_eq!(settle(&[(1, 0), (2, 0)]), vec![]);
+    }
+  }
+
+  async fn insert_costs(pool: &DbState) {
+    sqlx::query("UPDATE fullfillment SET cost_amount = 3000, cost_currency = 'EUR' WHERE user = 4 AND requirement = 1")
+      .execute(pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO fullfillment (user, requirement, cost_amount, cost_currency) VALUES (2, 2, 1500, 'EUR')")
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn ledger() {
+    let (app, pool) = setup_with_data().await;
+    insert_costs(&pool).await;
+    let expected_response = json!({
+      "expenses": [
+        { "requirement": 1, "name": "req1", "user": { "id": 4, "username": "username4" }, "cost": { "amount": 3000, "currency": "EUR" } },
+        { "requirement": 2, "name": "req2", "user": { "id": 2, "username": "username2" }, "cost": { "amount": 1500, "currency": "EUR" } }
+      ],
+      "totals": [{ "amount": 4500, "currency": "EUR" }]
+    });
+
+    let response = test_api(app, "/event/1/expenses", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await;
+    assert_eq!(response, Some(expected_response));
+  }
+
+  #[tokio::test]
+  async fn ledger_for_outsider() {
+    let (app, _) = setup_with_data().await;
+
+    let _ = test_api(app, "/event/1/expenses", http::Method::GET, None, StatusCode::FORBIDDEN, Some(("5", "username5"))).await;
+  }
+
+  #[tokio::test]
+  async fn settlement_equal() {
+    let (app, pool) = setup_with_data().await;
+    insert_costs(&pool).await;
+
+    let response = test_api(app, "/event/1/settlement", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    let settlement = &response[0];
+    assert_eq!(settlement["total"], 4500);
+    assert_eq!(settlement["transfers"], json!([
+      { "from": 1, "to": 4, "amount": 1500 },
+      { "from": 3, "to": 4, "amount": 1500 }
+    ]));
+  }
+
+  #[tokio::test]
+  async fn settlement_weighted() {
+    let (app, pool) = setup_with_data().await;
+    insert_costs(&pool).await;
+    sqlx::query("UPDATE participant SET share = 3 WHERE user = 3 AND event = 1")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    let response = test_api(app, "/event/1/settlement?split=weighted", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    let shares: Vec<i64> = response[0]["balances"].as_array().unwrap().iter().map(|b| b["share"].as_i64().unwrap()).collect();
+    assert_eq!(shares, vec![900, 900, 2700, 0]);
+  }
+
+  #[tokio::test]
+  async fn settlement_without_absent() {
+    let (app, pool) = setup_with_data().await;
+    insert_costs(&pool).await;
+    sqlx::query("UPDATE participant SET share = 0 WHERE user = 3 AND event = 1")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    let response = test_api(app, "/event/1/settlement", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+    let shares: Vec<i64> = response[0]["balances"].as_array().unwrap().iter().map(|b| b["share"].as_i64().unwrap()).collect();
+    assert_eq!(shares, vec![2250, 2250, 0, 0]);
+  }
+}
@@ -1,67 +1,70 @@
 use axum::{
   Json, Extension, extract::Path,
 };
+use chrono::Utc;
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{DbState, error::{AppError}, utils::AppReponse, auth::{UserAuth, user_action_authorization}, user::User};
+use crate::{DbState, error::{AppError}, db_modeling, utils::AppReponse, auth::{UserAuth, user_action_authorization, requirement_action_authorization}, user::User, expense::Cost, webhook::{WebhookQueue, WebhookEventType}, live::LiveUpdates};
 
 
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FullfillmentStatus {
+  Pledged,
+  Confirmed,
+  Delivered,
+  Cancelled,
+}
+
+impl FullfillmentStatus {
+  pub fn can_change_to(&self, status: FullfillmentStatus) -> bool {
+    use FullfillmentStatus::*;
+    matches!(
+      (self, status),
+      (Pledged, Confirmed) | (Pledged, Delivered) | (Confirmed, Delivered) | (Pledged, Cancelled) | (Confirmed, Cancelled)
+    )
+  }
+}
+
 #[derive(Deserialize)]
 pub struct CreateFullfillment {
   requirement: i64,
   user: i64,
+  note: Option<String>,
+  cost: Option<Cost>,
 }
 
 #[derive(Serialize)]
 pub struct CreateFullfillmentResponse {
   requirement: i64,
   user: User,
+  status: FullfillmentStatus,
+  note: Option<String>,
+  cost: Option<Cost>,
+  pledged_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateFullfillment {
+  status: Option<FullfillmentStatus>,
+  note: Option<String>,
+  cost: Option<Cost>,
 }
 
 pub async fn create(
   Json(payload): Json<CreateFullfillment>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<Json<CreateFullfillmentResponse>> {
-  let CreateFullfillment { requirement, user } = payload;
+  let CreateFullfillment { requirement, user, note, cost } = payload;
   user_action_authorization(user, auth_userid, "cannot add fullfillment for another user")?;
-  let maximum = sqlx::query!(
-    r#"
-SELECT size FROM requirement WHERE id = ?1
-    "#,
-    requirement
-  )
-  .fetch_optional(&pool)
-  .await?;
-
-  if maximum.is_none() {
-    return Err(AppError::NotFound(format!("Cannot find requirement: {requirement}")))
-  }
-  let maximum = maximum.unwrap();
-  let existing = sqlx::query!(
-    r#"
-SELECT count(1) as size FROM fullfillment WHERE requirement = ?1
-    "#,
-    requirement
-  )
-  .fetch_one(&pool)
-  .await?;
-
-  if existing.size as i64 >= maximum.size {
-    return Err(AppError::Server(format!("Maximum number of user for this requirement exeeded: {requirement}")))
-  }
-
-  let _ = sqlx::query!(
-      r#"
-  INSERT INTO fullfillment ( requirement, user )
-  VALUES ( ?1, ?2 )
-      "#,
-      requirement, user
-    )
-    .execute(&pool)
-    .await?
-    .last_insert_rowid();
+  let cost = cost.map(|c| c.validate()).transpose()?;
+  let pledged_at = db_modeling::insert_db_fullfillment(&mut *pool.acquire().await?, requirement, user, &note, &cost).await?;
 
     let dbuser = sqlx::query!(
       r#"
@@ -77,20 +80,172 @@ SELECT count(1) as size FROM fullfillment WHERE requirement = ?1
     user: User {
       id: user,
       username: dbuser.username
-    }
+    },
+    status: FullfillmentStatus::Pledged,
+    note,
+    cost,
+    pledged_at,
   };
+  let event = sqlx::query!("SELECT event FROM requirement WHERE id = ?1", requirement)
+    .fetch_one(&pool)
+    .await?;
+  let data = serde_json::to_value(&response)?;
+  live.publish(event.event, WebhookEventType::FulfillmentCreated, data.clone()).await;
+  webhooks.dispatch(event.event, WebhookEventType::FulfillmentCreated, data).await;
 
   Ok((StatusCode::CREATED, Json(response)))
 }
 
+/// Tells webhooks and live subscribers about fullfillments pledged on behalf of users, by accepted assignments
+/// and applied distributions, given as requirement, user and the time of the pledge.
+pub async fn dispatch_pledged(pool: &DbState, webhooks: &WebhookQueue, live: &LiveUpdates, pledged: &[(i64, i64, i64)]) -> Result<(), AppError> {
+  for (requirement, user, pledged_at) in pledged.iter().copied() {
+    let db = sqlx::query!(
+        r#"
+  SELECT requirement.event, user.username FROM requirement, user
+  WHERE requirement.id = ?1 AND user.id = ?2
+        "#,
+        requirement, user
+      )
+      .fetch_one(pool)
+      .await?;
+    let response = CreateFullfillmentResponse {
+      requirement,
+      user: User {
+        id: user,
+        username: db.username,
+      },
+      status: FullfillmentStatus::Pledged,
+      note: None,
+      cost: None,
+      pledged_at,
+    };
+    let data = serde_json::to_value(&response)?;
+    live.publish(db.event, WebhookEventType::FulfillmentCreated, data.clone()).await;
+    webhooks.dispatch(db.event, WebhookEventType::FulfillmentCreated, data).await;
+  }
+  Ok(())
+}
+
+pub async fn update(
+  Path((user_id, requirement_id)): Path<(i64, i64)>,
+  Json(payload): Json<UpdateFullfillment>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  if payload.status.is_none() && payload.note.is_none() && payload.cost.is_none() {
+    return Err(AppError::BadRequest(String::from("at least one field must be filled out")));
+  }
+
+  let current = sqlx::query!(
+      r#"
+  SELECT status as "status: FullfillmentStatus" FROM fullfillment
+  WHERE user = ?1 AND requirement = ?2
+      "#,
+      user_id, requirement_id
+    )
+    .fetch_optional(&pool)
+    .await?;
+  let current = match current {
+    Some(c) => c.status,
+    None => return Err(AppError::NotFound(format!("Cannot find fullfillment: {user_id}/{requirement_id}"))),
+  };
+
+  if payload.note.is_some() {
+    user_action_authorization(user_id, auth_userid, "cannot change note of fullfillment for another user")?;
+  }
+  if payload.cost.is_some() {
+    user_action_authorization(user_id, auth_userid, "cannot change cost of fullfillment for another user")?;
+  }
+  let cost = payload.cost.map(|c| c.validate()).transpose()?;
+
+  let mut tx = pool.begin().await?;
+  if let Some(status) = payload.status {
+    match status {
+      FullfillmentStatus::Confirmed | FullfillmentStatus::Delivered => {
+        requirement_action_authorization(&pool, requirement_id, auth_userid, "cannot change status of fullfillment for event that user doesn't own").await?;
+      },
+      FullfillmentStatus::Cancelled => {
+        if user_id != auth_userid {
+          requirement_action_authorization(&pool, requirement_id, auth_userid, "cannot cancel fullfillment for another user").await?;
+        }
+      },
+      FullfillmentStatus::Pledged => {
+        return Err(AppError::BadRequest(String::from("fullfillment cannot be pledged again, create it instead")));
+      }
+    }
+    if !current.can_change_to(status) {
+      return Err(AppError::BadRequest(format!("cannot change fullfillment status from {current:?} to {status:?}")));
+    }
+
+    let changed_at = Utc::now().timestamp();
+    // the status may have been changed since it was checked
+    let updated = sqlx::query!(
+        r#"
+  UPDATE fullfillment
+  SET status = ?3,
+    confirmed_at = CASE WHEN ?3 = 'confirmed' THEN ?4 ELSE confirmed_at END,
+    delivered_at = CASE WHEN ?3 = 'delivered' THEN ?4 ELSE delivered_at END,
+    cancelled_at = CASE WHEN ?3 = 'cancelled' THEN ?4 ELSE cancelled_at END
+  WHERE user = ?1 AND requirement = ?2 AND status = ?5
+        "#,
+        user_id, requirement_id, status, changed_at, current
+      )
+      .execute(&mut tx)
+      .await?
+      .rows_affected();
+    if updated == 0 {
+      return Err(AppError::BadRequest(format!("fullfillment status is no longer {current:?}")));
+    }
+  }
+
+  if let Some(note) = payload.note {
+    let _ = sqlx::query!(
+        r#"
+  UPDATE fullfillment SET note = ?3
+  WHERE user = ?1 AND requirement = ?2
+        "#,
+        user_id, requirement_id, note
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+
+  if let Some(Cost { amount, currency }) = cost {
+    let _ = sqlx::query!(
+        r#"
+  UPDATE fullfillment SET cost_amount = ?3, cost_currency = ?4
+  WHERE user = ?1 AND requirement = ?2
+        "#,
+        user_id, requirement_id, amount, currency
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  let event = sqlx::query!("SELECT event FROM requirement WHERE id = ?1", requirement_id)
+    .fetch_one(&pool)
+    .await?;
+  let data = json!({ "user": user_id, "requirement": requirement_id, "status": payload.status.unwrap_or(current) });
+  live.publish(event.event, WebhookEventType::FulfillmentUpdated, data.clone()).await;
+  webhooks.dispatch(event.event, WebhookEventType::FulfillmentUpdated, data).await;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
 pub async fn delete(
   Path((user_id, requirement_id)): Path<(i64, i64)>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   user_action_authorization(user_id, auth_userid, "cannot remove fullfillment for another user")?;
 
-  let _ = sqlx::query!(
+  let deleted = sqlx::query!(
       r#"
   DELETE FROM fullfillment
   WHERE user = ?1 AND requirement = ?2
@@ -98,14 +253,23 @@ pub async fn delete(
       user_id, requirement_id
     )
     .execute(&pool)
-    .await?;
+    .await?
+    .rows_affected();
+  if deleted > 0 {
+    let event = sqlx::query!("SELECT event FROM requirement WHERE id = ?1", requirement_id)
+      .fetch_one(&pool)
+      .await?;
+    let data = json!({ "user": user_id, "requirement": requirement_id });
+    live.publish(event.event, WebhookEventType::FulfillmentDeleted, data.clone()).await;
+    webhooks.dispatch(event.event, WebhookEventType::FulfillmentDeleted, data).await;
+  }
 
   Ok((StatusCode::NO_CONTENT, ()))
 }
 
 
 #[cfg(test)]
-mod fullfillment {
+mod test {
   use super::*;
   use serde_json::json;
   use crate::utils::test::{test_api, setup_with_data};
@@ -126,11 +290,15 @@ mod fullfillment {
         "user": {
           "id": 6,
           "username": "username6"
-        }
+        },
+        "status": "pledged",
+        "note": null,
+        "cost": null
       });
 
-      let response = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("6", "username6"))).await;
-      assert_eq!(response, Some(expected_response));
+      let mut response = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("6", "username6"))).await.unwrap();
+      assert!(response.as_object_mut().unwrap().remove("pledged_at").unwrap().is_i64());
+      assert_eq!(response, expected_response);
     }
 
     #[tokio::test]
@@ -145,11 +313,15 @@ mod fullfillment {
         "user": {
           "id": 6,
           "username": "username6"
-        }
+        },
+        "status": "pledged",
+        "note": null,
+        "cost": null
       });
 
-      let response = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("6", "username6"))).await;
-      assert_eq!(response, Some(expected_response));
+      let mut response = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("6", "username6"))).await.unwrap();
+      assert!(response.as_object_mut().unwrap().remove("pledged_at").unwrap().is_i64());
+      assert_eq!(response, expected_response);
     }
 
     #[tokio::test]
@@ -171,7 +343,159 @@ mod fullfillment {
         "user": 6,
       });
 
-      let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::INTERNAL_SERVER_ERROR, Some(("6", "username6"))).await;
+      let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("6", "username6"))).await;
+    }
+
+    #[tokio::test]
+    async fn with_note() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "requirement": 2,
+        "user": 6,
+        "note": "bringing vegan option",
+      });
+
+      let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("6", "username6"))).await;
+
+      let result = sqlx::query!("select note from fullfillment where user = 6 and requirement = 2")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.note, Some("bringing vegan option".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn already_fullfilled() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "requirement": 1,
+        "user": 4,
+      });
+
+      let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("4", "username4"))).await;
+    }
+
+    #[tokio::test]
+    async fn after_cancel() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("UPDATE fullfillment SET status = 'cancelled', cancelled_at = 1664928000 WHERE user = 2 AND requirement = 3")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "requirement": 3,
+        "user": 2,
+      });
+
+      let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("2", "username2"))).await;
+
+      let result = sqlx::query!(r#"select status, cancelled_at from fullfillment where user = 2 and requirement = 3"#)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "pledged");
+      assert_eq!(result.cancelled_at, None);
+    }
+  }
+
+  mod update {
+    use super::*;
+
+    #[tokio::test]
+    async fn confirm() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "status": "confirmed",
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select status, confirmed_at from fullfillment where user = 4 and requirement = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "confirmed");
+      assert!(result.confirmed_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn confirm_by_fullfiller() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "status": "confirmed",
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("4", "username4"))).await;
+    }
+
+    #[tokio::test]
+    async fn deliver_after_cancel() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("UPDATE fullfillment SET status = 'cancelled' WHERE user = 4 AND requirement = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "status": "delivered",
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn cancel_and_note() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "status": "cancelled",
+        "note": "sorry, car broke down",
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("4", "username4"))).await;
+
+      let result = sqlx::query!("select status, note, cancelled_at from fullfillment where user = 4 and requirement = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "cancelled");
+      assert_eq!(result.note, Some("sorry, car broke down".to_owned()));
+      assert!(result.cancelled_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn cost() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "cost": { "amount": 2450, "currency": "eur" },
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("4", "username4"))).await;
+
+      let result = sqlx::query!("select cost_amount, cost_currency from fullfillment where user = 4 and requirement = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.cost_amount, Some(2450));
+      assert_eq!(result.cost_currency, Some("EUR".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn negative_cost() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "cost": { "amount": -1, "currency": "EUR" },
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("4", "username4"))).await;
+    }
+
+    #[tokio::test]
+    async fn note_for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "note": "not my note",
+      });
+
+      let _ = test_api(app, "/fullfillment/4/1", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
     }
   }
 
@@ -0,0 +1,304 @@
+use std::{env, fs, sync::OnceLock};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::utils::AppReponse;
+
+/// Key signing and verifying session tokens, identified by the `kid` header of the tokens.
+pub struct JwtKey {
+  kid: String,
+  algorithm: Algorithm,
+  encoding: EncodingKey,
+  decoding: DecodingKey,
+  public: PublicJwk,
+  /// new tokens are signed with the key from this time, until a newer key takes over
+  sign_from: i64,
+  /// tokens signed with the key are rejected from this time, it has to be later
+  /// than the expiration of the last token signed before the next key took over
+  verify_until: Option<i64>,
+}
+
+/// Public part of a key as published in the JWKS document (RFC 7517, RFC 8037 for Ed25519).
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicJwk {
+  kty: &'static str,
+  #[serde(rename = "use")]
+  key_use: &'static str,
+  alg: Algorithm,
+  kid: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  n: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  e: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  crv: Option<&'static str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  x: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Jwks {
+  keys: Vec<PublicJwk>,
+}
+
+fn base64url(bytes: &[u8]) -> String {
+  base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl JwtKey {
+  /// Loads a PKCS#8 (or PKCS#1 for RSA) private key for one of the RSA algorithms or EdDSA with Ed25519.
+  pub fn from_pem(kid: &str, algorithm: Algorithm, pem: &[u8], sign_from: i64, verify_until: Option<i64>) -> Result<Self, String> {
+    let invalid = |e: String| format!("invalid private key {kid}: {e}");
+    let der = pem::parse(pem).map_err(|e| invalid(e.to_string()))?;
+
+    let (encoding, decoding, public) = match algorithm {
+      Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+        let pair = match der.tag.as_str() {
+          "RSA PRIVATE KEY" => RsaKeyPair::from_der(&der.contents),
+          _ => RsaKeyPair::from_pkcs8(&der.contents),
+        }.map_err(|e| invalid(e.to_string()))?;
+        let modulus = pair.public_key().modulus().big_endian_without_leading_zero();
+        let exponent = pair.public_key().exponent().big_endian_without_leading_zero();
+        let public = PublicJwk {
+          kty: "RSA", key_use: "sig", alg: algorithm, kid: kid.to_owned(),
+          n: Some(base64url(modulus)), e: Some(base64url(exponent)), crv: None, x: None,
+        };
+        let encoding = EncodingKey::from_rsa_pem(pem).map_err(|e| invalid(e.to_string()))?;
+        (encoding, DecodingKey::from_rsa_raw_components(modulus, exponent), public)
+      },
+      Algorithm::EdDSA => {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der.contents).map_err(|e| invalid(e.to_string()))?;
+        let x = pair.public_key().as_ref();
+        let public = PublicJwk {
+          kty: "OKP", key_use: "sig", alg: algorithm, kid: kid.to_owned(),
+          n: None, e: None, crv: Some("Ed25519"), x: Some(base64url(x)),
+        };
+        let encoding = EncodingKey::from_ed_pem(pem).map_err(|e| invalid(e.to_string()))?;
+        (encoding, DecodingKey::from_ed_der(x), public)
+      },
+      _ => return Err(format!("unsupported algorithm {algorithm:?} of key {kid}, use one of the RSA algorithms or EdDSA")),
+    };
+
+    Ok(Self { kid: kid.to_owned(), algorithm, encoding, decoding, public, sign_from, verify_until })
+  }
+
+  /// Keys are configured in `JWT_KEY_<KID>_*` variables, `-` in the key id is `_` in the names.
+  fn from_env(kid: &str) -> Result<Self, String> {
+    let var = |key: &str| env::var(format!("JWT_KEY_{}_{key}", kid.to_uppercase().replace('-', "_"))).ok();
+    let time = |key: &str| var(key)
+      .map(|t| DateTime::parse_from_rfc3339(&t).map(|t| t.timestamp()).map_err(|e| format!("invalid {key} of key {kid}: {e}")))
+      .transpose();
+
+    let algorithm = var("ALGORITHM")
+      .map(|a| a.parse::<Algorithm>().map_err(|_| format!("unknown algorithm {a} of key {kid}")))
+      .transpose()?
+      .unwrap_or(Algorithm::RS256);
+    let path = var("FILE").ok_or_else(|| format!("private key file of key {kid} is not set"))?;
+    let pem = fs::read(&path).map_err(|e| format!("cannot read private key {kid} from {path}: {e}"))?;
+    Self::from_pem(kid, algorithm, &pem, time("SIGN_FROM")?.unwrap_or(0), time("VERIFY_UNTIL")?)
+  }
+
+  fn active(&self, now: i64) -> bool {
+    self.verify_until.is_none_or(|until| now < until)
+  }
+}
+
+/// Keys of the session tokens. Tokens without a `kid` are signed with the `JWT_SECRET` (HS256),
+/// which keeps verifying the tokens issued before asymmetric keys were configured.
+pub struct KeySet {
+  keys: Vec<JwtKey>,
+  secret: Option<String>,
+}
+
+impl KeySet {
+  pub fn new(keys: Vec<JwtKey>, secret: Option<String>) -> Self {
+    Self { keys, secret }
+  }
+
+  /// Keys listed in `JWT_KEYS`, e.g. `JWT_KEYS=2026-10,2026-11` with `JWT_KEY_2026_11_FILE`, `JWT_KEY_2026_11_ALGORITHM`
+  /// and `JWT_KEY_2026_11_SIGN_FROM`, plus `JWT_SECRET`.
+  pub fn from_env() -> Result<Self, String> {
+    let keys = env::var("JWT_KEYS")
+      .unwrap_or_default()
+      .split(',')
+      .map(|kid| kid.trim())
+      .filter(|kid| !kid.is_empty())
+      .map(JwtKey::from_env)
+      .collect::<Result<Vec<_>, _>>()?;
+    let secret = env::var("JWT_SECRET").ok();
+    if keys.is_empty() && secret.is_none() {
+      return Err(String::from("JWT_KEYS or JWT_SECRET must be set"));
+    }
+    Ok(Self::new(keys, secret))
+  }
+
+  /// The key with the latest start of signing which has already started.
+  fn signing_key(&self, now: i64) -> Option<&JwtKey> {
+    self.keys
+      .iter()
+      .filter(|k| k.active(now) && k.sign_from <= now)
+      .max_by_key(|k| k.sign_from)
+  }
+
+  pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, String> {
+    self.sign_at(claims, Utc::now().timestamp())
+  }
+
+  fn sign_at<T: Serialize>(&self, claims: &T, now: i64) -> Result<String, String> {
+    let signed = match (self.signing_key(now), &self.secret) {
+      (Some(key), _) => {
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        encode(&header, claims, &key.encoding)
+      },
+      (None, Some(secret)) => encode(&Header::default(), claims, &EncodingKey::from_secret(secret.as_ref())),
+      (None, None) => return Err(String::from("no key signs session tokens at this time")),
+    };
+    signed.map_err(|e| format!("cannot sign session token: {e}"))
+  }
+
+  /// Verifies the signature with the key named by the token and the expiration, only with the algorithm of the key.
+  pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+    self.verify_at(token, Utc::now().timestamp())
+  }
+
+  fn verify_at<T: DeserializeOwned>(&self, token: &str, now: i64) -> Option<T> {
+    let header = decode_header(token).ok()?;
+    let decoded = match &header.kid {
+      Some(kid) => {
+        let key = self.keys.iter().find(|k| &k.kid == kid && k.active(now))?;
+        decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))
+      },
+      None => {
+        let secret = self.secret.as_ref()?;
+        decode::<T>(token, &DecodingKey::from_secret(secret.as_ref()), &Validation::default())
+      },
+    };
+    decoded.ok().map(|d| d.claims)
+  }
+
+  /// Public keys of all keys still verifying tokens, including the ones scheduled to sign later,
+  /// so other services know them before the first token signed with them.
+  fn jwks_at(&self, now: i64) -> Jwks {
+    let keys = self.keys
+      .iter()
+      .filter(|k| k.active(now))
+      .map(|k| k.public.clone())
+      .collect();
+    Jwks { keys }
+  }
+}
+
+/// Loaded once, keys scheduled with `SIGN_FROM` and `VERIFY_UNTIL` rotate without a restart.
+pub fn keys() -> &'static KeySet {
+  static KEYS: OnceLock<KeySet> = OnceLock::new();
+  KEYS.get_or_init(|| KeySet::from_env().unwrap_or_else(|e| panic!("{e}")))
+}
+
+pub async fn jwks() -> AppReponse<Json<Jwks>> {
+  Ok((StatusCode::OK, Json(keys().jwks_at(Utc::now().timestamp()))))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use axum::http;
+  use serde::Deserialize;
+  use serde_json::json;
+  use crate::utils::test::{setup, test_api};
+
+  const RSA_KEY: &[u8] = include_bytes!("../data/test_jwt_rsa.pem");
+  const ED25519_KEY: &[u8] = include_bytes!("../data/test_jwt_ed25519.pem");
+  const DAY: i64 = 24 * 60 * 60;
+  const NOW: i64 = 1791936000;
+
+  #[derive(Serialize, Deserialize)]
+  struct Claims {
+    sub: String,
+    exp: i64,
+  }
+
+  fn claims() -> Claims {
+    Claims { sub: String::from("1"), exp: Utc::now().timestamp() + DAY }
+  }
+
+  fn kid(token: &str) -> Option<String> {
+    decode_header(token).unwrap().kid
+  }
+
+  #[test]
+  fn rotation() {
+    let keys = KeySet::new(vec![
+      JwtKey::from_pem("2026-10", Algorithm::RS256, RSA_KEY, 0, Some(NOW + 2 * DAY)).unwrap(),
+      JwtKey::from_pem("2026-11", Algorithm::EdDSA, ED25519_KEY, NOW + DAY, None).unwrap(),
+    ], None);
+
+    let old = keys.sign_at(&claims(), NOW).unwrap();
+    assert_eq!(kid(&old).as_deref(), Some("2026-10"));
+    let jwks = serde_json::to_value(keys.jwks_at(NOW)).unwrap();
+    assert_eq!(jwks["keys"].as_array().unwrap().len(), 2);
+
+    // the next key signs from its start, the previous one still verifies
+    let new = keys.sign_at(&claims(), NOW + DAY).unwrap();
+    assert_eq!(kid(&new).as_deref(), Some("2026-11"));
+    assert!(keys.verify_at::<Claims>(&old, NOW + DAY).is_some());
+    assert!(keys.verify_at::<Claims>(&new, NOW + DAY).is_some());
+
+    // until it is retired
+    assert!(keys.verify_at::<Claims>(&old, NOW + 2 * DAY).is_none());
+    let jwks = serde_json::to_value(keys.jwks_at(NOW + 2 * DAY)).unwrap();
+    assert_eq!(jwks["keys"], json!([{ "kty": "OKP", "use": "sig", "alg": "EdDSA", "kid": "2026-11", "crv": "Ed25519", "x": jwks["keys"][0]["x"] }]));
+  }
+
+  #[test]
+  fn verified_with_jwks() {
+    let keys = KeySet::new(vec![JwtKey::from_pem("rsa", Algorithm::RS256, RSA_KEY, 0, None).unwrap()], None);
+    let token = keys.sign(&claims()).unwrap();
+
+    // as another service would verify the token
+    let jwks = serde_json::to_value(keys.jwks_at(NOW)).unwrap();
+    let jwk = &jwks["keys"][0];
+    assert_eq!(jwk["kty"], "RSA");
+    assert_eq!(jwk["kid"], "rsa");
+    let key = DecodingKey::from_rsa_components(jwk["n"].as_str().unwrap(), jwk["e"].as_str().unwrap()).unwrap();
+    assert!(decode::<Claims>(&token, &key, &Validation::new(Algorithm::RS256)).is_ok());
+  }
+
+  #[test]
+  fn secret() {
+    let legacy = KeySet::new(vec![], Some(String::from("secret")));
+    let token = legacy.sign(&claims()).unwrap();
+    assert_eq!(kid(&token), None);
+
+    // tokens signed with the secret are verified after the switch to a key
+    let keys = KeySet::new(vec![JwtKey::from_pem("ed", Algorithm::EdDSA, ED25519_KEY, 0, None).unwrap()], Some(String::from("secret")));
+    assert!(keys.verify::<Claims>(&token).is_some());
+    assert_eq!(kid(&keys.sign(&claims()).unwrap()).as_deref(), Some("ed"));
+    assert!(KeySet::new(vec![], Some(String::from("another"))).verify::<Claims>(&token).is_none());
+  }
+
+  #[test]
+  fn unknown_key() {
+    let keys = KeySet::new(vec![JwtKey::from_pem("rsa", Algorithm::RS256, RSA_KEY, 0, None).unwrap()], None);
+    let other = KeySet::new(vec![JwtKey::from_pem("other", Algorithm::EdDSA, ED25519_KEY, 0, None).unwrap()], None);
+    assert!(keys.verify::<Claims>(&other.sign(&claims()).unwrap()).is_none());
+    // no secret for tokens without a key id
+    assert!(keys.verify::<Claims>(&KeySet::new(vec![], Some(String::from("secret"))).sign(&claims()).unwrap()).is_none());
+    // keys are bound to their algorithm
+    assert!(JwtKey::from_pem("rsa", Algorithm::EdDSA, RSA_KEY, 0, None).is_err());
+    assert!(JwtKey::from_pem("rsa", Algorithm::HS256, RSA_KEY, 0, None).is_err());
+  }
+
+  #[tokio::test]
+  async fn endpoint() {
+    let (app, _) = setup().await;
+
+    let response = test_api(app, "/.well-known/jwks.json", http::Method::GET, None, StatusCode::OK, None).await.unwrap();
+    assert_eq!(response, json!({ "keys": [] }));
+  }
+}
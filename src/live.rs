@@ -0,0 +1,314 @@
+use axum::{
+  Extension, extract::{Path, Query},
+  http::HeaderMap,
+  response::sse::{Event as SseEvent, KeepAlive, Sse},
+};
+use chrono::{Utc, Duration};
+use futures_util::{Stream, StreamExt, future, stream};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_member_authorization}, webhook::WebhookEventType};
+
+const CHANNEL_CAPACITY: usize = 256;
+/// how long updates are kept for clients resuming a subscription
+const RETENTION_HOURS: i64 = 24;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LiveUpdate {
+  id: i64,
+  event: i64,
+  kind: WebhookEventType,
+  data: Value,
+  created_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct SubscribeParam {
+  /// alternative to the `Last-Event-ID` header for clients that cannot set headers
+  lastEventId: Option<i64>,
+}
+
+/// Stores changes of events and broadcasts them to the subscribed clients.
+#[derive(Clone)]
+pub struct LiveUpdates {
+  pool: DbState,
+  sender: broadcast::Sender<LiveUpdate>,
+}
+
+impl LiveUpdates {
+  pub fn new(pool: DbState) -> Self {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+    Self { pool, sender }
+  }
+
+  pub async fn publish(&self, event: i64, kind: WebhookEventType, data: Value) {
+    match insert_db_update(&self.pool, event, kind, data).await {
+      // sending fails only when nobody is subscribed
+      Ok(update) => { let _ = self.sender.send(update); },
+      Err(e) => tracing::error!("cannot publish update of event {event}: {e}"),
+    }
+  }
+
+  /// Updates of deleted events are removed with them, so the deletion is only sent to the current subscribers.
+  pub fn publish_deleted(&self, event: i64, data: Value) {
+    let update = LiveUpdate { id: 0, event, kind: WebhookEventType::EventDeleted, data, created_at: Utc::now().timestamp() };
+    let _ = self.sender.send(update);
+  }
+}
+
+async fn insert_db_update(pool: &DbState, event: i64, kind: WebhookEventType, data: Value) -> Result<LiveUpdate, AppError> {
+  let now = Utc::now();
+  let created_at = now.timestamp();
+  let expired = (now - Duration::hours(RETENTION_HOURS)).timestamp();
+  let payload = data.to_string();
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM live_update WHERE created_at < ?1", expired)
+    .execute(&mut tx)
+    .await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO live_update ( event, kind, data, created_at )
+  VALUES ( ?1, ?2, ?3, ?4 )
+      "#,
+      event, kind, payload, created_at
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+  tx.commit().await?;
+
+  Ok(LiveUpdate { id, event, kind, data, created_at })
+}
+
+async fn get_db_updates(pool: &DbState, event: i64, after: i64) -> Result<Vec<LiveUpdate>, AppError> {
+  sqlx::query!(
+      r#"
+  SELECT id as "id!", kind as "kind!: WebhookEventType", data, created_at FROM live_update
+  WHERE event = ?1 AND id > ?2
+  ORDER BY id
+      "#,
+      event, after
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|u| Ok(LiveUpdate {
+      id: u.id,
+      event,
+      kind: u.kind,
+      data: serde_json::from_str(&u.data)?,
+      created_at: u.created_at,
+    }))
+    .collect()
+}
+
+/// Streams changes of the event as server-sent events, starting after the last event id the client has seen.
+pub async fn subscribe(
+  Path(event_id): Path<i64>,
+  Query(params): Query<SubscribeParam>,
+  headers: HeaderMap,
+  Extension(pool): Extension<DbState>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Sse<impl Stream<Item = Result<SseEvent, serde_json::Error>>>> {
+  event_member_authorization(&pool, event_id, auth_userid, "cannot subscribe to event that user doesn't take part in").await?;
+
+  let last_event_id = headers
+    .get("Last-Event-ID")
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| h.parse::<i64>().ok())
+    .or(params.lastEventId);
+
+  // subscribe before reading the missed updates, so that nothing published in between is lost
+  let receiver = live.sender.subscribe();
+  let (missed, last_sent) = match last_event_id {
+    Some(last) => {
+      let missed = get_db_updates(&pool, event_id, last).await?;
+      let last_sent = missed.last().map_or(last, |u| u.id);
+      (missed, last_sent)
+    },
+    None => (vec![], 0),
+  };
+
+  // a lagging client ends the stream and resumes from its last event id when reconnecting,
+  // a user who no longer takes part in the event is not sent anything further, nothing follows the deletion of the event
+  let updates = BroadcastStream::new(receiver)
+    .take_while(|u| future::ready(!matches!(u, Err(BroadcastStreamRecvError::Lagged(_)))))
+    .filter_map(move |u| future::ready(u.ok().filter(|u| u.event == event_id && (u.id > last_sent || u.kind == WebhookEventType::EventDeleted))))
+    .then(move |u| {
+      let pool = pool.clone();
+      async move {
+        match u.kind {
+          WebhookEventType::EventDeleted => vec![Some(u), None],
+          _ if event_member_authorization(&pool, event_id, auth_userid, "").await.is_ok() => vec![Some(u)],
+          _ => vec![None],
+        }
+      }
+    })
+    .flat_map(stream::iter)
+    .take_while(|u| future::ready(u.is_some()))
+    .filter_map(future::ready);
+  let events = stream::iter(missed)
+    .chain(updates)
+    .map(|u| SseEvent::default().id(u.id.to_string()).event(u.kind.as_str()).json_data(&u));
+
+  Ok((StatusCode::OK, Sse::new(events).keep_alive(KeepAlive::default())))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration as StdDuration;
+  use serde_json::json;
+  use crate::{auth::generate_jwt, utils::test::{test_api, setup_with_data}};
+  use axum::{http::{self, Request, HeaderValue}, body::{Body, HttpBody}, Router};
+  use tower::ServiceExt;
+
+  async fn open(app: Router, uri: &str, last_event_id: Option<&str>, auth: (&str, &str)) -> axum::response::Response {
+    let mut req = Request::builder()
+      .method(http::Method::GET)
+      .uri(uri);
+    let headers = req.headers_mut().unwrap();
+    headers.insert("X-JWT-Token", HeaderValue::from_str(&generate_jwt(auth.0, auth.1)).unwrap());
+    if let Some(id) = last_event_id {
+      headers.insert("Last-Event-ID", HeaderValue::from_str(id).unwrap());
+    }
+    app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+  }
+
+  /// Reads the body until `count` events arrived, keep-alive comments are skipped.
+  async fn read_events(response: axum::response::Response, count: usize) -> Vec<String> {
+    let mut body = response.into_body();
+    let mut text = String::new();
+    let mut events = vec![];
+    while events.len() < count {
+      let chunk = tokio::time::timeout(StdDuration::from_secs(2), body.data()).await.expect("event to arrive").unwrap().unwrap();
+      text.push_str(&String::from_utf8_lossy(&chunk));
+      while let Some(end) = text.find("\n\n") {
+        let event: String = text.drain(..end + 2).collect();
+        if !event.starts_with(':') {
+          events.push(event);
+        }
+      }
+    }
+    events
+  }
+
+  #[tokio::test]
+  async fn live() {
+    let (app, _) = setup_with_data().await;
+    let response = open(app.clone(), "/event/1/live", None, ("2", "username2")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[http::header::CONTENT_TYPE], mime::TEXT_EVENT_STREAM.as_ref());
+
+    let body_json = json!({
+      "requirement": 2,
+      "user": 3,
+    });
+    let _ = test_api(app.clone(), "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("3", "username3"))).await;
+    // changes of other events are not streamed
+    let body_json = json!({
+      "event": 2,
+      "user": 5,
+    });
+    let _ = test_api(app.clone(), "/participant", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("5", "username5"))).await;
+    let _ = test_api(app, "/requirement/2", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+    let events = read_events(response, 2).await;
+    assert!(events[0].contains("id:1\n"));
+    assert!(events[0].contains("event:fulfillment.created\n"));
+    assert!(events[0].contains(r#""user":{"id":3,"username":"username3"}"#));
+    assert!(events[1].contains("id:3\n"));
+    assert!(events[1].contains("event:requirement.deleted\n"));
+  }
+
+  #[tokio::test]
+  async fn resume() {
+    let (app, _) = setup_with_data().await;
+    for user in [5, 6] {
+      let body_json = json!({
+        "event": 1,
+        "user": user,
+      });
+      let _ = test_api(app.clone(), "/participant", http::Method::POST, Some(body_json), StatusCode::CREATED, Some((&user.to_string(), &format!("username{user}")))).await;
+    }
+
+    let response = open(app.clone(), "/event/1/live", Some("1"), ("1", "username1")).await;
+    let _ = test_api(app, "/participant/5/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("5", "username5"))).await;
+
+    let events = read_events(response, 2).await;
+    assert!(events[0].contains("id:2\n"));
+    assert!(events[0].contains(r#""user":6"#));
+    assert!(events[1].contains("id:3\n"));
+    assert!(events[1].contains("event:participant.left\n"));
+  }
+
+  #[tokio::test]
+  async fn indirect_changes() {
+    let (app, pool) = setup_with_data().await;
+    sqlx::query(r#"
+      INSERT INTO assignment (id, user, requirement, assigned_by, created_at, expires_at) VALUES (1, 2, 2, 1, 1664841600, 4102444800);
+      INSERT INTO template (id, name, creator) VALUES (1, 'camping', 1);
+      INSERT INTO template_requirement (template, name, size) VALUES (1, 'tent', 2);
+    "#)
+      .execute(&pool)
+      .await
+      .unwrap();
+    let response = open(app.clone(), "/event/1/live", None, ("2", "username2")).await;
+
+    let _ = test_api(app.clone(), "/assignment/1", http::Method::PUT, Some(json!({ "status": "accepted" })), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+    let _ = test_api(app.clone(), "/template/1/apply", http::Method::POST, Some(json!({ "event": 1 })), StatusCode::CREATED, Some(("1", "username1"))).await;
+
+    let mut body = response.into_body();
+    let mut text = String::new();
+    // membership is checked when an update is sent, so the updates before the deletion are read first
+    while text.matches("\n\n").count() < 2 {
+      let chunk = tokio::time::timeout(StdDuration::from_secs(2), body.data()).await.expect("event to arrive").unwrap().unwrap();
+      text.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let _ = test_api(app, "/event/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+    while let Some(chunk) = tokio::time::timeout(StdDuration::from_secs(2), body.data()).await.expect("stream to end") {
+      text.push_str(&String::from_utf8_lossy(&chunk.unwrap()));
+    }
+    let kinds: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("event:")).collect();
+    assert_eq!(kinds, vec!["fulfillment.created", "requirement.created", "event.deleted"]);
+  }
+
+  #[tokio::test]
+  async fn removed() {
+    let (app, _) = setup_with_data().await;
+    let body_json = json!({
+      "event": 1,
+      "user": 5,
+    });
+    let _ = test_api(app.clone(), "/participant", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("5", "username5"))).await;
+
+    let response = open(app.clone(), "/event/1/live", None, ("5", "username5")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let _ = test_api(app.clone(), "/participant/5/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("5", "username5"))).await;
+    let body_json = json!({
+      "requirement": 2,
+      "user": 3,
+    });
+    let _ = test_api(app, "/fullfillment", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("3", "username3"))).await;
+
+    let mut body = response.into_body();
+    let chunk = tokio::time::timeout(StdDuration::from_secs(2), body.data()).await.expect("stream to end");
+    assert!(chunk.is_none());
+  }
+
+  #[tokio::test]
+  async fn for_another() {
+    let (app, _) = setup_with_data().await;
+
+    let response = open(app, "/event/1/live", None, ("4", "username4")).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+  }
+}
@@ -0,0 +1,296 @@
+use std::{env, net::SocketAddr};
+use axum::{extract::ConnectInfo, http::HeaderMap};
+use chrono::Utc;
+
+use crate::{DbState, error::AppError, auth::check_captcha};
+
+/// Limits of failed logins, durations are in seconds.
+#[derive(Clone, Debug)]
+pub struct LoginConfig {
+  /// length of the sliding window in which failures per address and per username are counted
+  pub window: i64,
+  pub max_per_ip: i64,
+  pub max_per_username: i64,
+  /// consecutive failures after which a captcha token is required
+  pub captcha_after: i64,
+  /// wait after the first failure, doubled with every further one
+  pub delay: i64,
+  /// consecutive failures after which the account is locked for `lockout`
+  pub lockout_after: i64,
+  pub lockout: i64,
+  /// take the client address from `X-Forwarded-For`, only safe behind a proxy which sets it
+  pub trust_forwarded: bool,
+}
+
+impl Default for LoginConfig {
+  fn default() -> Self {
+    Self {
+      window: 15 * 60,
+      max_per_ip: 50,
+      max_per_username: 10,
+      captcha_after: 3,
+      delay: 1,
+      lockout_after: 5,
+      lockout: 15 * 60,
+      trust_forwarded: false,
+    }
+  }
+}
+
+impl LoginConfig {
+  pub fn from_env() -> Self {
+    let default = Self::default();
+    Self {
+      window: env::var("LOGIN_WINDOW_SECS").ok().and_then(|w| w.parse().ok()).unwrap_or(default.window),
+      max_per_ip: env::var("LOGIN_MAX_PER_IP").ok().and_then(|m| m.parse().ok()).unwrap_or(default.max_per_ip),
+      max_per_username: env::var("LOGIN_MAX_PER_USERNAME").ok().and_then(|m| m.parse().ok()).unwrap_or(default.max_per_username),
+      captcha_after: env::var("LOGIN_CAPTCHA_AFTER").ok().and_then(|c| c.parse().ok()).unwrap_or(default.captcha_after),
+      delay: env::var("LOGIN_DELAY_SECS").ok().and_then(|d| d.parse().ok()).unwrap_or(default.delay),
+      lockout_after: env::var("LOGIN_LOCKOUT_AFTER").ok().and_then(|l| l.parse().ok()).unwrap_or(default.lockout_after),
+      lockout: env::var("LOGIN_LOCKOUT_SECS").ok().and_then(|l| l.parse().ok()).unwrap_or(default.lockout),
+      trust_forwarded: env::var("LOGIN_TRUST_FORWARDED").map(|t| t == "true" || t == "1").unwrap_or(default.trust_forwarded),
+    }
+  }
+
+  /// How long logins are refused after the given number of consecutive failures.
+  pub fn delay_after(&self, failures: i64) -> i64 {
+    if failures <= 0 {
+      0
+    } else if failures >= self.lockout_after {
+      self.lockout
+    } else {
+      self.delay.saturating_mul(1 << (failures - 1).min(30)).min(self.lockout)
+    }
+  }
+}
+
+/// Consecutive failed logins, counted on the user row for existing accounts
+/// and in `failed_login` for unknown usernames, so that both are throttled alike.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Failures {
+  pub count: i64,
+  pub last_at: Option<i64>,
+}
+
+pub fn client_ip(headers: &HeaderMap, connect_info: Option<ConnectInfo<SocketAddr>>, config: &LoginConfig) -> String {
+  let forwarded = headers
+    .get("X-Forwarded-For")
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| h.split(',').next())
+    .map(|ip| ip.trim().to_owned())
+    .filter(|ip| config.trust_forwarded && !ip.is_empty());
+
+  forwarded
+    .or_else(|| connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()))
+    .unwrap_or_else(|| String::from("unknown"))
+}
+
+pub async fn unknown_user_failures(pool: &DbState, config: &LoginConfig, username: &str) -> Result<Failures, AppError> {
+  let since = Utc::now().timestamp() - config.window;
+  let failures = sqlx::query!(
+      r#"
+  SELECT COUNT(*) as "count!: i64", MAX(created_at) as "last_at: i64" FROM failed_login
+  WHERE username = ?1 AND created_at > ?2
+      "#,
+      username, since
+    )
+    .fetch_one(pool)
+    .await?;
+
+  Ok(Failures { count: failures.count, last_at: failures.last_at })
+}
+
+/// Refuses the login attempt before the password is checked.
+pub async fn authorize_attempt(pool: &DbState, config: &LoginConfig, ip: &str, username: &str, failures: Failures, captcha: Option<&str>) -> Result<(), AppError> {
+  let now = Utc::now().timestamp();
+  let since = now - config.window;
+
+  let by_ip = sqlx::query!(
+      r#"SELECT COUNT(*) as "count!: i64", MIN(created_at) as "oldest: i64" FROM failed_login WHERE ip = ?1 AND created_at > ?2"#,
+      ip, since
+    )
+    .fetch_one(pool)
+    .await?;
+  if by_ip.count >= config.max_per_ip {
+    return Err(too_many(by_ip.oldest.unwrap_or(now) + config.window - now));
+  }
+
+  let by_username = sqlx::query!(
+      r#"SELECT COUNT(*) as "count!: i64", MIN(created_at) as "oldest: i64" FROM failed_login WHERE username = ?1 AND created_at > ?2"#,
+      username, since
+    )
+    .fetch_one(pool)
+    .await?;
+  if by_username.count >= config.max_per_username {
+    return Err(too_many(by_username.oldest.unwrap_or(now) + config.window - now));
+  }
+
+  if let Some(last_at) = failures.last_at {
+    let until = last_at + config.delay_after(failures.count);
+    if until > now {
+      return Err(too_many(until - now));
+    }
+  }
+
+  if failures.count >= config.captcha_after {
+    match captcha {
+      Some(token) => check_captcha(token).await?,
+      None => return Err(AppError::Forbidden(String::from("captcha required"))),
+    }
+  }
+  Ok(())
+}
+
+pub async fn record_failure(pool: &DbState, config: &LoginConfig, ip: &str, username: &str, user: Option<i64>) -> Result<(), AppError> {
+  let now = Utc::now().timestamp();
+  let expired = now - config.window;
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM failed_login WHERE created_at <= ?1", expired)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("INSERT INTO failed_login ( ip, username, created_at ) VALUES ( ?1, ?2, ?3 )", ip, username, now)
+    .execute(&mut tx)
+    .await?;
+  if let Some(user) = user {
+    let _ = sqlx::query!("UPDATE user SET failed_logins = failed_logins + 1, last_failed_login_at = ?2 WHERE id = ?1", user, now)
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+  Ok(())
+}
+
+/// Failures of the address stay counted, the next attacked account shouldn't get a fresh budget.
+pub async fn record_success(pool: &DbState, username: &str, user: i64) -> Result<(), AppError> {
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM failed_login WHERE username = ?1", username)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("UPDATE user SET failed_logins = 0, last_failed_login_at = NULL WHERE id = ?1", user)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+  Ok(())
+}
+
+fn too_many(retry_after: i64) -> AppError {
+  AppError::TooManyRequests(String::from("too many failed logins, try again later"), retry_after.max(1))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::utils::test::setup_with_data;
+
+  fn config() -> LoginConfig {
+    LoginConfig { delay: 0, lockout_after: 100, captcha_after: 100, ..LoginConfig::default() }
+  }
+
+  fn retry_after(result: Result<(), AppError>) -> i64 {
+    match result {
+      Err(AppError::TooManyRequests(_, retry_after)) => retry_after,
+      Err(_) => panic!("unexpected error"),
+      Ok(_) => panic!("expected to be refused"),
+    }
+  }
+
+  #[test]
+  fn delays() {
+    let config = LoginConfig::default();
+    assert_eq!(config.delay_after(0), 0);
+    assert_eq!(config.delay_after(1), 1);
+    assert_eq!(config.delay_after(4), 8);
+    assert_eq!(config.delay_after(5), 15 * 60);
+    assert_eq!(config.delay_after(200), 15 * 60);
+  }
+
+  #[test]
+  fn forwarded() {
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Forwarded-For", "203.0.113.7, 10.0.0.1".parse().unwrap());
+    let connect_info = Some(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+    assert_eq!(client_ip(&headers, connect_info, &LoginConfig::default()), "10.0.0.1");
+    assert_eq!(client_ip(&headers, connect_info, &LoginConfig { trust_forwarded: true, ..LoginConfig::default() }), "203.0.113.7");
+    assert_eq!(client_ip(&HeaderMap::new(), None, &LoginConfig::default()), "unknown");
+  }
+
+  #[tokio::test]
+  async fn per_ip() {
+    let (_, pool) = setup_with_data().await;
+    let config = LoginConfig { max_per_ip: 3, ..config() };
+    for username in ["username1", "username2", "nobody"] {
+      assert!(authorize_attempt(&pool, &config, "203.0.113.7", username, Failures::default(), None).await.is_ok());
+      assert!(record_failure(&pool, &config, "203.0.113.7", username, None).await.is_ok());
+    }
+
+    let retry = retry_after(authorize_attempt(&pool, &config, "203.0.113.7", "username3", Failures::default(), None).await);
+    assert!(retry > config.window - 5 && retry <= config.window);
+    assert!(authorize_attempt(&pool, &config, "203.0.113.8", "username3", Failures::default(), None).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn per_username() {
+    let (_, pool) = setup_with_data().await;
+    let config = LoginConfig { max_per_username: 2, ..config() };
+    assert!(record_failure(&pool, &config, "203.0.113.7", "username1", Some(1)).await.is_ok());
+    assert!(record_failure(&pool, &config, "203.0.113.8", "USERNAME1", Some(1)).await.is_ok());
+
+    assert!(authorize_attempt(&pool, &config, "203.0.113.9", "username1", Failures::default(), None).await.is_err());
+    assert!(record_success(&pool, "username1", 1).await.is_ok());
+    assert!(authorize_attempt(&pool, &config, "203.0.113.9", "username1", Failures::default(), None).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn lockout() {
+    let (_, pool) = setup_with_data().await;
+    let config = LoginConfig { lockout_after: 2, lockout: 600, ..config() };
+    let now = Utc::now().timestamp();
+
+    let failures = Failures { count: 1, last_at: Some(now) };
+    assert!(authorize_attempt(&pool, &config, "203.0.113.7", "username1", failures, None).await.is_ok());
+    let failures = Failures { count: 2, last_at: Some(now - 60) };
+    let retry = retry_after(authorize_attempt(&pool, &config, "203.0.113.7", "username1", failures, None).await);
+    assert!((539..=540).contains(&retry));
+    let failures = Failures { count: 2, last_at: Some(now - 600) };
+    assert!(authorize_attempt(&pool, &config, "203.0.113.7", "username1", failures, None).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn captcha() {
+    let (_, pool) = setup_with_data().await;
+    let config = LoginConfig { captcha_after: 2, ..config() };
+
+    let failures = Failures { count: 2, last_at: Some(Utc::now().timestamp() - 60) };
+    let result = authorize_attempt(&pool, &config, "203.0.113.7", "username1", failures, None).await;
+    assert!(matches!(result, Err(AppError::Forbidden(msg)) if msg == "captcha required"));
+  }
+
+  #[tokio::test]
+  async fn counter() {
+    let (_, pool) = setup_with_data().await;
+    let config = config();
+    assert!(record_failure(&pool, &config, "203.0.113.7", "username1", Some(1)).await.is_ok());
+    assert!(record_failure(&pool, &config, "203.0.113.7", "username1", Some(1)).await.is_ok());
+    assert!(record_failure(&pool, &config, "203.0.113.7", "nobody", None).await.is_ok());
+
+    let user = sqlx::query!("select failed_logins, last_failed_login_at from user where id = 1")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(user.failed_logins, 2);
+    assert!(user.last_failed_login_at.is_some());
+    let failures = unknown_user_failures(&pool, &config, "nobody").await;
+    assert!(matches!(failures, Ok(Failures { count: 1, .. })));
+
+    assert!(record_success(&pool, "username1", 1).await.is_ok());
+    let user = sqlx::query!("select failed_logins, last_failed_login_at from user where id = 1")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(user.failed_logins, 0);
+    assert!(user.last_failed_login_at.is_none());
+  }
+}
@@ -1,5 +1,4 @@
 use axum::{
-  http::Method,
   routing::{get, post, put, delete},
   Router, Extension,
 };
@@ -7,11 +6,15 @@ use dotenvy::dotenv;
 use tracing_subscriber::{EnvFilter, prelude::*};
 use utils::{shutdown_signal};
 use user::{authentificate};
-use std::{env};
+use std::{env, sync::Arc};
 use std::net::SocketAddr;
 use sqlx::{SqlitePool, Pool, Sqlite};
 use db_modeling::{database_down, database_up, database_fill};
-use tower_http::cors::{Any, CorsLayer};
+use notification::NotifierState;
+use mail::{MailQueue, MailNotifier};
+use webhook::{WebhookQueue, WebhookConfig};
+use live::LiveUpdates;
+use session::CookieConfig;
 use tower_http::trace::TraceLayer;
 use tower::ServiceBuilder;
 
@@ -24,44 +27,133 @@ mod event;
 mod participant;
 mod requirement;
 mod fullfillment;
+mod assignment;
+mod distribution;
+mod template;
+mod category;
+mod expense;
+mod date_poll;
+mod poll;
+mod comment;
+mod announcement;
+mod notification;
+mod reminder;
+mod mail;
+mod webhook;
+mod live;
+mod credentials;
+mod login_limit;
+mod totp;
+mod oidc;
+mod jwt_keys;
+mod session;
 
 type DbState = Pool<Sqlite>;
 
-pub async fn app(pool: Pool<Sqlite>) -> Router {
-  let cors = CorsLayer::new()
-    .allow_methods(vec![Method::GET, Method::POST, Method::DELETE, Method::PUT])
-    .allow_headers(Any)
-    .allow_origin(Any);
+pub async fn app(pool: Pool<Sqlite>, notifier: NotifierState, mail: MailQueue, webhooks: WebhookQueue, live: LiveUpdates) -> Router {
+  let cors = CookieConfig::from_env().cors(&session::cors_origins());
 
   let public = Router::new()
     .route("/up", get(database_up))
     .route("/down", get(database_down))
     .route("/fill", get(database_fill))
+    .route("/.well-known/jwks.json", get(jwt_keys::jwks))
     .route("/verify_captcha", post(auth::verify_captcha))
     .route("/register", post(user::create))
     .route("/authentificate", post(authentificate))
+    .route("/authentificate/2fa", post(totp::authentificate))
+    .route("/session", delete(session::logout))
+    .route("/oidc/providers", get(oidc::all))
+    .route("/oidc/:provider/authorize", post(oidc::authorize))
+    .route("/oidc/callback", post(oidc::callback))
+    .route("/verify-email", post(user::verify_email))
+    .route("/password/forgot", post(user::forgot_password))
+    .route("/password/reset", post(user::reset_password))
 
     .route("/event", get(event::all))
     .route("/event/:id", get(event::single))
     .route("/event", post(event::create))
     .route("/event/:id", put(event::update))
     .route("/event/:id", delete(event::delete))
+    .route("/event/:id/distribution", get(distribution::preview))
+    .route("/event/:id/distribution", post(distribution::apply))
+    .route("/event/:id/template", post(template::create_from_event))
+    .route("/event/:id/clone", post(event::clone))
+    .route("/event/:id/requirements", post(requirement::batch))
+    .route("/event/:id/requirements/order", put(requirement::reorder))
+    .route("/event/:id/datepoll", get(date_poll::get))
+    .route("/event/:id/datepoll", post(date_poll::create))
+    .route("/event/:id/datepoll/votes", put(date_poll::vote))
+    .route("/event/:id/datepoll/finalize", post(date_poll::finalize))
+    .route("/event/:id/polls", post(poll::create))
+    .route("/event/:id/comments", get(comment::all))
+    .route("/event/:id/comments", post(comment::create))
+    .route("/event/:id/announcements", get(announcement::all))
+    .route("/event/:id/announcements", post(announcement::create))
+    .route("/event/:id/live", get(live::subscribe))
+    .route("/event/:id/expenses", get(expense::ledger))
+    .route("/event/:id/settlement", get(expense::settlement))
+    .route("/event/:id/settlement/csv", get(expense::settlement_csv))
 
     // .route("/user", get(user::all))
     .route("/user/:id", get(user::single))
     .route("/user/:id/requirements", get(user::used_requirements))
+    .route("/user/:id/assignments", get(assignment::pending))
+    .route("/user/:id/templates", get(template::all))
+    .route("/user/:id/invitations", get(participant::invitations))
+    .route("/user/:id/notifications", get(notification::all))
+    .route("/user/:id/notifications/read", post(notification::read_all))
+    .route("/user/:id/notifications/preferences", get(notification::preferences))
+    .route("/user/:id/notifications/preferences", put(notification::update_preferences))
+    .route("/user/:id/webhooks", get(webhook::all))
+    .route("/user/:id/verification", post(user::resend_verification))
+    .route("/user/:id/password", put(user::change_password))
+    .route("/user/:id/totp", post(totp::enrol))
+    .route("/user/:id/totp", delete(totp::disable))
+    .route("/user/:id/totp/confirm", post(totp::confirm))
+    .route("/user/:id/identities", get(oidc::identities))
+    .route("/user/:id/identities/:identity_id", delete(oidc::unlink))
+    .route("/notification/:id/read", post(notification::read))
     .route("/user/:id", put(user::update))
     .route("/user/:id", delete(user::delete))
 
     .route("/participant", post(participant::create))
+    .route("/participant/:user_id/:event_id", put(participant::update))
     .route("/participant/:user_id/:event_id", delete(participant::delete))
+    .route("/invitation/:user_id/:event_id", delete(participant::decline_invitation))
 
     .route("/requirement", post(requirement::create))
     .route("/requirement/:id", put(requirement::update))
     .route("/requirement/:id", delete(requirement::delete))
 
+    .route("/category", post(category::create))
+    .route("/category/:id", put(category::update))
+    .route("/category/:id", delete(category::delete))
+
+    .route("/template", post(template::create))
+    .route("/template/:id/apply", post(template::apply))
+    .route("/template/:id", delete(template::delete))
+
+    .route("/webhook", post(webhook::create))
+    .route("/webhook/:id", put(webhook::update))
+    .route("/webhook/:id", delete(webhook::delete))
+    .route("/webhook/:id/deliveries", get(webhook::deliveries))
+    .route("/webhook/:id/deliveries/:delivery_id/replay", post(webhook::replay))
+
+    .route("/announcement/:id/read", post(announcement::read))
+    .route("/comment/:id", put(comment::update))
+    .route("/comment/:id", delete(comment::delete))
+    .route("/poll/:id/answer", post(poll::answer))
+    .route("/poll/:id/close", post(poll::close))
     .route("/fullfillment", post(fullfillment::create))
+    .route("/fullfillment/:user_id/:requirement_id", put(fullfillment::update))
     .route("/fullfillment/:user_id/:requirement_id", delete(fullfillment::delete))
+
+    .route("/assignment", post(assignment::create))
+    .route("/assignment/:id", put(assignment::answer))
+
+    .route("/preference/:user_id/:requirement_id", put(distribution::update_preference))
+    .route("/preference/:user_id/:requirement_id", delete(distribution::delete_preference))
     ;
 
   public
@@ -70,6 +162,10 @@ pub async fn app(pool: Pool<Sqlite>) -> Router {
         .layer(TraceLayer::new_for_http())
         .layer(cors)
         .layer(Extension(pool))
+        .layer(Extension(notifier))
+        .layer(Extension(mail))
+        .layer(Extension(webhooks))
+        .layer(Extension(live))
     )
 }
 
@@ -87,10 +183,17 @@ async fn main() {
   }
 
   let pool = SqlitePool::connect(&env::var("DATABASE_URL").unwrap()).await.unwrap();
+  // a misconfigured signing key fails at startup instead of at the first login
+  let _ = jwt_keys::keys();
+  let mail = MailQueue::start(mail::from_env());
+  let notifier: NotifierState = Arc::new(MailNotifier { pool: pool.clone(), queue: mail.clone() });
+  let webhooks = WebhookQueue::start(pool.clone(), WebhookConfig::from_env()).await;
+  let live = LiveUpdates::new(pool.clone());
+  tokio::spawn(reminder::schedule(pool.clone(), notifier.clone()));
   let addr = SocketAddr::from(([127, 0, 0, 1], 5000));
   tracing::info!("listening on {}", addr);
   axum::Server::bind(&addr)
-    .serve(app(pool).await.into_make_service())
+    .serve(app(pool, notifier, mail, webhooks, live).await.into_make_service_with_connect_info::<SocketAddr>())
     .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
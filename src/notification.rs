@@ -0,0 +1,394 @@
+use std::sync::Arc;
+
+use axum::{
+  Json, Extension, extract::{Path, Query}, async_trait,
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, user_action_authorization}};
+
+pub type NotifierState = Arc<dyn Notifier + Send + Sync>;
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum NotificationKind {
+  RequirementDeadline,
+  RequirementEvicted,
+  EventUpdated,
+  EventDeleted,
+  EventTimeFinalized,
+  CommentMention,
+  Announcement,
+}
+
+impl NotificationKind {
+  pub const ALL: [NotificationKind; 7] = [
+    NotificationKind::RequirementDeadline,
+    NotificationKind::RequirementEvicted,
+    NotificationKind::EventUpdated,
+    NotificationKind::EventDeleted,
+    NotificationKind::EventTimeFinalized,
+    NotificationKind::CommentMention,
+    NotificationKind::Announcement,
+  ];
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+  pub id: i64,
+  pub user: i64,
+  pub kind: NotificationKind,
+  pub message: String,
+  pub event: Option<i64>,
+  pub requirement: Option<i64>,
+  pub announcement: Option<i64>,
+  pub created_at: i64,
+  pub read_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Inbox {
+  unread: i64,
+  notifications: Vec<Notification>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Preference {
+  kind: NotificationKind,
+  enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct InboxParam {
+  #[serde(default)]
+  unread: bool,
+  page: Option<u32>,
+  pageSize: Option<u32>,
+}
+
+#[async_trait]
+pub trait Notifier {
+  async fn deliver(&self, notification: &Notification);
+}
+
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+  async fn deliver(&self, notification: &Notification) {
+    tracing::info!("notification for user {}: {}", notification.user, notification.message);
+  }
+}
+
+/// Stores a notification in the inbox of the user unless the user has disabled its kind.
+pub async fn insert_db_notification(conn: &mut SqliteConnection, user: i64, kind: NotificationKind, message: String, event: Option<i64>, requirement: Option<i64>, announcement: Option<i64>) -> Result<Option<Notification>, AppError> {
+  let disabled = sqlx::query!("SELECT enabled FROM notification_preference WHERE user = ?1 AND kind = ?2 AND NOT enabled", user, kind)
+    .fetch_optional(&mut *conn)
+    .await?;
+  if disabled.is_some() {
+    return Ok(None);
+  }
+
+  let created_at = Utc::now().timestamp();
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO notification ( user, kind, message, event, requirement, announcement, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+      "#,
+      user, kind, message, event, requirement, announcement, created_at
+    )
+    .execute(&mut *conn)
+    .await?
+    .last_insert_rowid();
+
+  Ok(Some(Notification {
+    id,
+    user,
+    kind,
+    message,
+    event,
+    requirement,
+    announcement,
+    created_at,
+    read_at: None,
+  }))
+}
+
+/// Notifies all participants of an event except the one causing the change.
+pub async fn insert_db_participant_notifications(conn: &mut SqliteConnection, event: i64, except: i64, kind: NotificationKind, message: String) -> Result<Vec<Notification>, AppError> {
+  let participants = sqlx::query!("SELECT user FROM participant WHERE event = ?1 AND user != ?2 ORDER BY user", event, except)
+    .fetch_all(&mut *conn)
+    .await?;
+
+  let mut notifications = vec![];
+  for p in participants {
+    notifications.extend(insert_db_notification(&mut *conn, p.user, kind, message.clone(), Some(event), None, None).await?);
+  }
+  Ok(notifications)
+}
+
+pub async fn all(
+  Path(user_id): Path<i64>,
+  Query(params): Query<InboxParam>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Inbox>> {
+  user_action_authorization(user_id, auth_userid, "cannot get notifications of another user")?;
+
+  let (limit, offset) = match (params.page, params.pageSize) {
+    (Some(page), Some(page_size)) => (page_size as i64, (page.max(1) - 1) as i64 * page_size as i64),
+    _ => (-1, 0),
+  };
+  let notifications = sqlx::query_as!(Notification,
+      r#"
+  SELECT id as "id!", user as "user!", kind as "kind!: NotificationKind", message as "message!", event, requirement, announcement, created_at as "created_at!", read_at
+  FROM notification
+  WHERE user = ?1 AND (NOT ?2 OR read_at IS NULL)
+  ORDER BY created_at DESC, id DESC
+  LIMIT ?3 OFFSET ?4
+      "#,
+      user_id, params.unread, limit, offset
+    )
+    .fetch_all(&pool)
+    .await?;
+
+  let unread = sqlx::query!(r#"SELECT COUNT(1) as "count!: i64" FROM notification WHERE user = ?1 AND read_at IS NULL"#, user_id)
+    .fetch_one(&pool)
+    .await?
+    .count;
+
+  Ok((StatusCode::OK, Json(Inbox { unread, notifications })))
+}
+
+pub async fn read(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  let notification = sqlx::query!("SELECT user, announcement FROM notification WHERE id = ?1", id)
+    .fetch_optional(&pool)
+    .await?;
+  let announcement = match notification {
+    Some(n) => {
+      user_action_authorization(n.user, auth_userid, "cannot read notification of another user")?;
+      n.announcement
+    },
+    None => return Err(AppError::NotFound(format!("Cannot find notification: {id}"))),
+  };
+
+  let read_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE notification SET read_at = COALESCE(read_at, ?2) WHERE id = ?1", id, read_at)
+    .execute(&mut tx)
+    .await?;
+  // reading an announcement in the inbox counts as a receipt for the organizer
+  let _ = sqlx::query!("UPDATE announcement_receipt SET read_at = COALESCE(read_at, ?3) WHERE announcement = ?1 AND user = ?2", announcement, auth_userid, read_at)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn read_all(
+  Path(user_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(user_id, auth_userid, "cannot read notifications of another user")?;
+
+  let read_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!(
+      r#"
+  UPDATE announcement_receipt SET read_at = ?2
+  WHERE user = ?1 AND read_at IS NULL
+    AND announcement IN (SELECT announcement FROM notification WHERE user = ?1 AND read_at IS NULL)
+      "#,
+      user_id, read_at
+    )
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("UPDATE notification SET read_at = ?2 WHERE user = ?1 AND read_at IS NULL", user_id, read_at)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn preferences(
+  Path(user_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Preference>>> {
+  user_action_authorization(user_id, auth_userid, "cannot get notification preferences of another user")?;
+
+  let disabled: Vec<NotificationKind> = sqlx::query!(r#"SELECT kind as "kind: NotificationKind" FROM notification_preference WHERE user = ?1 AND NOT enabled"#, user_id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|p| p.kind)
+    .collect();
+  let preferences = NotificationKind::ALL
+    .into_iter()
+    .map(|kind| Preference { kind, enabled: !disabled.contains(&kind) })
+    .collect();
+
+  Ok((StatusCode::OK, Json(preferences)))
+}
+
+pub async fn update_preferences(
+  Path(user_id): Path<i64>,
+  Json(payload): Json<Vec<Preference>>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(user_id, auth_userid, "cannot change notification preferences of another user")?;
+
+  let mut tx = pool.begin().await?;
+  for Preference { kind, enabled } in payload {
+    let _ = sqlx::query!(
+        r#"
+  INSERT INTO notification_preference ( user, kind, enabled )
+  VALUES ( ?1, ?2, ?3 )
+  ON CONFLICT(user, kind) DO UPDATE SET enabled = ?3
+        "#,
+        user_id, kind, enabled
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+pub mod test {
+  use super::*;
+  use std::sync::Mutex;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  #[derive(Default)]
+  pub struct MemoryNotifier(pub Mutex<Vec<Notification>>);
+
+  #[async_trait]
+  impl Notifier for MemoryNotifier {
+    async fn deliver(&self, notification: &Notification) {
+      self.0.lock().unwrap().push(notification.clone());
+    }
+  }
+
+  async fn insert_notifications(pool: &DbState) {
+    sqlx::query(r#"INSERT INTO notification (id, user, kind, message, event, created_at, read_at) VALUES
+      (1, 2, 'event_updated', 'event-1 has been updated', 1, 1664841600, NULL),
+      (2, 2, 'announcement', 'location changed', 1, 1664841700, 1664841800),
+      (3, 3, 'event_updated', 'event-1 has been updated', 1, 1664841600, NULL)"#)
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  mod get {
+    use super::*;
+
+    #[tokio::test]
+    async fn inbox() {
+      let (app, pool) = setup_with_data().await;
+      insert_notifications(&pool).await;
+
+      let response = test_api(app, "/user/2/notifications", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["unread"], 1);
+      let ids: Vec<i64> = response["notifications"].as_array().unwrap().iter().map(|n| n["id"].as_i64().unwrap()).collect();
+      assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn unread() {
+      let (app, pool) = setup_with_data().await;
+      insert_notifications(&pool).await;
+
+      let response = test_api(app, "/user/2/notifications?unread=true", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["notifications"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+
+      let _ = test_api(app, "/user/2/notifications", http::Method::GET, None, StatusCode::FORBIDDEN, Some(("3", "username3"))).await;
+    }
+  }
+
+  mod read {
+    use super::*;
+
+    #[tokio::test]
+    async fn single() {
+      let (app, pool) = setup_with_data().await;
+      insert_notifications(&pool).await;
+
+      let _ = test_api(app, "/notification/1/read", http::Method::POST, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+      let result = sqlx::query!("select read_at from notification where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(result.read_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn single_for_another() {
+      let (app, pool) = setup_with_data().await;
+      insert_notifications(&pool).await;
+
+      let _ = test_api(app, "/notification/3/read", http::Method::POST, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+
+    #[tokio::test]
+    async fn all() {
+      let (app, pool) = setup_with_data().await;
+      insert_notifications(&pool).await;
+
+      let _ = test_api(app, "/user/2/notifications/read", http::Method::POST, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+      let results = sqlx::query!("select id from notification where read_at is null")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[0].id, 3);
+    }
+  }
+
+  mod preferences {
+    use super::*;
+
+    #[tokio::test]
+    async fn disabled_kind() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!([
+        { "kind": "event_updated", "enabled": false }
+      ]);
+
+      let _ = test_api(app.clone(), "/user/2/notifications/preferences", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+      let response = test_api(app, "/user/2/notifications/preferences", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert!(response.as_array().unwrap().contains(&json!({ "kind": "event_updated", "enabled": false })));
+      assert!(response.as_array().unwrap().contains(&json!({ "kind": "announcement", "enabled": true })));
+
+      let mut conn = pool.acquire().await.unwrap();
+      let notification = insert_db_notification(&mut conn, 2, NotificationKind::EventUpdated, String::from("updated"), Some(1), None, None).await.ok().unwrap();
+      assert!(notification.is_none());
+    }
+  }
+}
@@ -0,0 +1,744 @@
+use std::env;
+use axum::{Json, Extension, extract::Path};
+use chrono::{Utc, Duration};
+use hyper::{StatusCode, HeaderMap, header};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::{AlgorithmParameters, JwkSet}};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  DbState, error::AppError, utils::AppReponse, mail,
+  auth::{UserAuth, OptionalUserAuth, StoredPassword, user_action_authorization, generate_token, hash_token},
+  credentials::{CredentialPolicy, USERNAME_SYMBOLS, username_available},
+  user::{AuthentificateResp, UserAuthRespData},
+  totp, session::{self, CookieConfig},
+};
+
+/// Logins not finished within this time have to start over.
+const LOGIN_MINUTES: i64 = 10;
+
+/// OpenID Connect identity provider, configured in `OIDC_<NAME>_*` variables.
+#[derive(Clone, Debug)]
+pub struct Provider {
+  /// used in urls and stored with the linked identities
+  pub name: String,
+  pub issuer: String,
+  pub client_id: String,
+  pub client_secret: Option<String>,
+  pub scopes: String,
+  /// create an account on the first login of an identity which is not linked yet
+  pub create_accounts: bool,
+  /// link an identity to the account with the same verified email address,
+  /// only for providers which verify the addresses themselves
+  pub link_by_email: bool,
+}
+
+impl Provider {
+  fn from_env(name: &str) -> Option<Self> {
+    let var = |key: &str| env::var(format!("OIDC_{}_{key}", name.to_uppercase())).ok();
+    let flag = |key: &str| var(key).map(|f| f == "true" || f == "1");
+    Some(Self {
+      name: name.to_owned(),
+      issuer: var("ISSUER")?,
+      client_id: var("CLIENT_ID")?,
+      client_secret: var("CLIENT_SECRET"),
+      scopes: var("SCOPES").unwrap_or_else(|| String::from("openid email profile")),
+      create_accounts: flag("CREATE_ACCOUNTS").unwrap_or(true),
+      link_by_email: flag("LINK_BY_EMAIL").unwrap_or(false),
+    })
+  }
+}
+
+/// Providers listed in `OIDC_PROVIDERS`, e.g. `OIDC_PROVIDERS=company` with `OIDC_COMPANY_ISSUER` and `OIDC_COMPANY_CLIENT_ID`.
+pub fn providers() -> Vec<Provider> {
+  env::var("OIDC_PROVIDERS")
+    .unwrap_or_default()
+    .split(',')
+    .map(|name| name.trim())
+    .filter(|name| !name.is_empty())
+    .filter_map(|name| {
+      let provider = Provider::from_env(name);
+      if provider.is_none() {
+        tracing::error!("identity provider {name} is missing its issuer or client id");
+      }
+      provider
+    })
+    .collect()
+}
+
+/// Page of the frontend receiving the authorization response, `OIDC_REDIRECT_URL` or `<APP_URL>/oidc/callback`.
+pub fn redirect_url() -> String {
+  env::var("OIDC_REDIRECT_URL").unwrap_or_else(|_| format!("{}/oidc/callback", mail::app_url()))
+}
+
+#[derive(Serialize)]
+pub struct ProviderResp {
+  name: String,
+}
+
+#[derive(Serialize)]
+pub struct Authorization {
+  /// where the browser is sent to sign in
+  authorization_url: String,
+  state: String,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackReq {
+  state: String,
+  code: String,
+}
+
+#[derive(Serialize)]
+pub struct Identity {
+  id: i64,
+  provider: String,
+  subject: String,
+  email: Option<String>,
+  created_at: i64,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+  sub: String,
+  nonce: Option<String>,
+  email: Option<String>,
+  #[serde(default)]
+  email_verified: bool,
+  preferred_username: Option<String>,
+  name: Option<String>,
+}
+
+async fn discover(provider: &Provider) -> Result<Discovery, AppError> {
+  let issuer = provider.issuer.trim_end_matches('/');
+  let discovery: Discovery = reqwest::get(format!("{issuer}/.well-known/openid-configuration"))
+    .await?
+    .error_for_status()?
+    .json()
+    .await?;
+  if discovery.issuer.trim_end_matches('/') != issuer {
+    return Err(AppError::Server(format!("issuer of identity provider {} doesn't match its discovery document", provider.name)));
+  }
+  Ok(discovery)
+}
+
+/// PKCE challenge (RFC 7636) of the verifier.
+fn code_challenge(code_verifier: &str) -> String {
+  base64::encode_config(Sha256::digest(code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the signature against the keys of the provider, the issuer, audience, expiration and nonce.
+async fn verify_id_token(provider: &Provider, discovery: &Discovery, id_token: &str, nonce: &str) -> Result<IdClaims, AppError> {
+  let invalid = |e: jsonwebtoken::errors::Error| AppError::Unauthorized(format!("invalid id token: {e}"));
+  let header = decode_header(id_token).map_err(invalid)?;
+  if !matches!(header.alg, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512) {
+    return Err(AppError::Unauthorized(format!("unsupported id token algorithm {:?}", header.alg)));
+  }
+
+  let jwks: JwkSet = reqwest::get(&discovery.jwks_uri)
+    .await?
+    .error_for_status()?
+    .json()
+    .await?;
+  let jwk = match &header.kid {
+    Some(kid) => jwks.find(kid),
+    None => jwks.keys.first(),
+  };
+  let key = match jwk.map(|k| &k.algorithm) {
+    Some(AlgorithmParameters::RSA(rsa)) => DecodingKey::from_rsa_components(&rsa.n, &rsa.e).map_err(invalid)?,
+    _ => return Err(AppError::Unauthorized(String::from("id token is not signed by a known key"))),
+  };
+
+  let mut validation = Validation::new(header.alg);
+  validation.set_audience(&[&provider.client_id]);
+  validation.set_issuer(&[&discovery.issuer]);
+  let claims = decode::<IdClaims>(id_token, &key, &validation).map_err(invalid)?.claims;
+  if claims.nonce.as_deref() != Some(nonce) {
+    return Err(AppError::Unauthorized(String::from("id token nonce doesn't match")));
+  }
+  Ok(claims)
+}
+
+/// Stores the state, nonce and PKCE verifier of a new login and returns where to send the browser,
+/// with the token the browser has to present again to finish the login.
+/// With `link_user` the identity is linked to that account instead of signing in.
+pub async fn start_login(pool: &DbState, provider: &Provider, redirect_uri: &str, link_user: Option<i64>) -> Result<(Authorization, String), AppError> {
+  let discovery = discover(provider).await?;
+  let state = generate_token();
+  let browser = generate_token();
+  let browser_hash = hash_token(&browser);
+  let nonce = generate_token();
+  let code_verifier = format!("{}{}", generate_token(), generate_token());
+
+  let authorization_url = Url::parse_with_params(&discovery.authorization_endpoint, &[
+      ("response_type", "code"),
+      ("client_id", &provider.client_id),
+      ("redirect_uri", redirect_uri),
+      ("scope", &provider.scopes),
+      ("state", &state),
+      ("nonce", &nonce),
+      ("code_challenge", &code_challenge(&code_verifier)),
+      ("code_challenge_method", "S256"),
+    ])
+    .map_err(|e| AppError::Server(format!("invalid authorization endpoint of identity provider {}: {e}", provider.name)))?;
+
+  let now = Utc::now();
+  let created_at = now.timestamp();
+  let expired = (now - Duration::minutes(LOGIN_MINUTES)).timestamp();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM oidc_login WHERE created_at < ?1", expired)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!(
+      r#"
+  INSERT INTO oidc_login ( state, provider, nonce, code_verifier, redirect_uri, link_user, browser_hash, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 )
+      "#,
+      state, provider.name, nonce, code_verifier, redirect_uri, link_user, browser_hash, created_at
+    )
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((Authorization { authorization_url: authorization_url.to_string(), state }, browser))
+}
+
+/// Exchanges the authorization code, verifies the id token and signs in the linked account.
+/// Only the browser which started the login can finish it, and only the signed in user who started linking.
+pub async fn finish_login(
+  pool: &DbState,
+  providers: &[Provider],
+  state: &str,
+  code: &str,
+  browser: Option<&str>,
+  auth_user: Option<i64>,
+) -> Result<AuthentificateResp, AppError> {
+  let expired = (Utc::now() - Duration::minutes(LOGIN_MINUTES)).timestamp();
+  let mut tx = pool.begin().await?;
+  let login = sqlx::query!(
+      r#"
+  SELECT id, provider, nonce, code_verifier, redirect_uri, link_user, browser_hash FROM oidc_login
+  WHERE state = ?1 AND created_at >= ?2
+      "#,
+      state, expired
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+  let login = match login {
+    Some(l) => l,
+    None => return Err(AppError::BadRequest(String::from("invalid or expired login state"))),
+  };
+  // the state can be used only once
+  let _ = sqlx::query!("DELETE FROM oidc_login WHERE id = ?1", login.id)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  if browser.map(hash_token) != Some(login.browser_hash) {
+    return Err(AppError::BadRequest(String::from("login was started in another browser")));
+  }
+  if login.link_user.is_some() && auth_user != login.link_user {
+    return Err(AppError::Forbidden(String::from("identity can be linked only by the user who started linking it")));
+  }
+
+  let provider = match providers.iter().find(|p| p.name == login.provider) {
+    Some(p) => p,
+    None => return Err(AppError::BadRequest(format!("identity provider {} is no longer configured", login.provider))),
+  };
+  let discovery = discover(provider).await?;
+
+  let mut form = vec![
+    ("grant_type", "authorization_code"),
+    ("code", code),
+    ("redirect_uri", &login.redirect_uri),
+    ("client_id", &provider.client_id),
+    ("code_verifier", &login.code_verifier),
+  ];
+  if let Some(secret) = &provider.client_secret {
+    form.push(("client_secret", secret));
+  }
+  let response = reqwest::Client::new()
+    .post(&discovery.token_endpoint)
+    .form(&form)
+    .send()
+    .await?;
+  if !response.status().is_success() {
+    return Err(AppError::Unauthorized(format!("identity provider rejected the authorization code with status {}", response.status())));
+  }
+  let tokens: TokenResponse = response.json().await?;
+  let claims = verify_id_token(provider, &discovery, &tokens.id_token, &login.nonce).await?;
+
+  let user = match login.link_user {
+    Some(user) => link_identity(pool, provider, &claims, user).await?,
+    None => match get_db_linked_user(pool, provider, &claims).await? {
+      Some(user) => user,
+      None => first_login(pool, provider, &claims).await?,
+    },
+  };
+  login_response(pool, user).await
+}
+
+async fn get_db_linked_user(pool: &DbState, provider: &Provider, claims: &IdClaims) -> Result<Option<i64>, AppError> {
+  let identity = sqlx::query!("SELECT user FROM user_identity WHERE provider = ?1 AND subject = ?2", provider.name, claims.sub)
+    .fetch_optional(pool)
+    .await?;
+  Ok(identity.map(|i| i.user))
+}
+
+async fn link_identity(pool: &DbState, provider: &Provider, claims: &IdClaims, user: i64) -> Result<i64, AppError> {
+  match get_db_linked_user(pool, provider, claims).await? {
+    Some(linked) if linked == user => return Ok(user),
+    Some(_) => return Err(AppError::BadRequest(String::from("identity is linked to another account"))),
+    None => {},
+  }
+
+  let created_at = Utc::now().timestamp();
+  let _ = sqlx::query!(
+      r#"
+  INSERT INTO user_identity ( user, provider, subject, email, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
+      "#,
+      user, provider.name, claims.sub, claims.email, created_at
+    )
+    .execute(pool)
+    .await?;
+  Ok(user)
+}
+
+async fn first_login(pool: &DbState, provider: &Provider, claims: &IdClaims) -> Result<i64, AppError> {
+  if provider.link_by_email && claims.email_verified {
+    let existing = sqlx::query!("SELECT id FROM user WHERE email = ?1 AND email_verified_at IS NOT NULL", claims.email)
+      .fetch_optional(pool)
+      .await?;
+    if let Some(existing) = existing {
+      return link_identity(pool, provider, claims, existing.id).await;
+    }
+  }
+  if !provider.create_accounts {
+    return Err(AppError::Unauthorized(String::from("no account is linked to this identity")));
+  }
+
+  let username = available_username(pool, claims).await?;
+  // the account is used through the provider, the password can be set by a password reset
+  let password = StoredPassword::new(&generate_token(), false);
+  let email_verified_at = claims.email_verified.then(|| Utc::now().timestamp());
+  let user = sqlx::query!(
+      r#"
+  INSERT INTO user ( username, password, salt, password_scheme, email, email_verified_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+      "#,
+      username, password.hash, password.salt, password.scheme, claims.email, email_verified_at
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+
+  link_identity(pool, provider, claims, user).await
+}
+
+/// Username from the claims of the identity, made to follow the username rules and numbered when taken.
+async fn available_username(pool: &DbState, claims: &IdClaims) -> Result<String, AppError> {
+  let policy = CredentialPolicy::from_env();
+  let candidate = claims.preferred_username.as_deref()
+    .or(claims.name.as_deref())
+    .or(claims.email.as_deref().and_then(|e| e.split('@').next()))
+    .unwrap_or_default();
+  let base: String = candidate
+    .chars()
+    .filter(|c| c.is_alphanumeric() || USERNAME_SYMBOLS.contains(c))
+    .take(policy.username_max_length.saturating_sub(3))
+    .collect();
+  let base = match base.trim() {
+    b if b.chars().count() < policy.username_min_length => String::from("user"),
+    b => b.to_owned(),
+  };
+
+  for number in 1..1000 {
+    let username = if number == 1 { base.clone() } else { format!("{base}{number}") };
+    match username_available(pool, &username, None).await {
+      Ok(()) => return Ok(username),
+      Err(AppError::BadRequest(_)) => continue,
+      Err(e) => return Err(e),
+    }
+  }
+  Err(AppError::Server(format!("no username derived from {base} is available")))
+}
+
+/// Accounts with two-factor authentication still need their second factor.
+async fn login_response(pool: &DbState, user: i64) -> Result<AuthentificateResp, AppError> {
+  let user_db = sqlx::query!("SELECT username, totp_enabled_at FROM user WHERE id = ?1", user)
+    .fetch_one(pool)
+    .await?;
+  if user_db.totp_enabled_at.is_some() {
+    return Ok(AuthentificateResp::TwoFactorPending(totp::pending_login(pool, user).await?));
+  }
+  Ok(AuthentificateResp::Authenticated(UserAuthRespData::new(user, &user_db.username)))
+}
+
+pub async fn all() -> AppReponse<Json<Vec<ProviderResp>>> {
+  let providers = providers()
+    .into_iter()
+    .map(|p| ProviderResp { name: p.name })
+    .collect();
+
+  Ok((StatusCode::OK, Json(providers)))
+}
+
+/// Signed in users link the identity to their account, others sign in with it.
+pub async fn authorize(
+  Path(provider): Path<String>,
+  Extension(pool): Extension<DbState>,
+  OptionalUserAuth(link_user): OptionalUserAuth,
+) -> AppReponse<(HeaderMap, Json<Authorization>)> {
+  let provider = match providers().into_iter().find(|p| p.name == provider) {
+    Some(p) => p,
+    None => return Err(AppError::NotFound(format!("unknown identity provider {provider}"))),
+  };
+  let (authorization, browser) = start_login(&pool, &provider, &redirect_url(), link_user).await?;
+  let mut headers = HeaderMap::new();
+  headers.insert(header::SET_COOKIE, CookieConfig::from_env().oidc_cookie(&browser, LOGIN_MINUTES * 60));
+  Ok((StatusCode::OK, (headers, Json(authorization))))
+}
+
+pub async fn callback(
+  Json(payload): Json<CallbackReq>,
+  headers: HeaderMap,
+  Extension(pool): Extension<DbState>,
+  OptionalUserAuth(auth_userid): OptionalUserAuth,
+) -> AppReponse<(HeaderMap, Json<AuthentificateResp>)> {
+  let browser = session::cookie(&headers, session::OIDC_COOKIE);
+  let resp = finish_login(&pool, &providers(), &payload.state, &payload.code, browser, auth_userid).await?;
+  let config = CookieConfig::from_env();
+  let mut headers = resp.cookies(&config);
+  headers.append(header::SET_COOKIE, config.oidc_cookie("", 0));
+  Ok((StatusCode::OK, (headers, Json(resp))))
+}
+
+pub async fn identities(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Identity>>> {
+  user_action_authorization(id, auth_userid, "cannot get identities of another user")?;
+
+  let identities = sqlx::query_as!(Identity,
+      r#"
+  SELECT id, provider, subject, email, created_at FROM user_identity
+  WHERE user = ?1
+  ORDER BY id
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+  Ok((StatusCode::OK, Json(identities)))
+}
+
+pub async fn unlink(
+  Path((id, identity_id)): Path<(i64, i64)>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(id, auth_userid, "cannot unlink identities of another user")?;
+
+  let deleted = sqlx::query!("DELETE FROM user_identity WHERE id = ?1 AND user = ?2", identity_id, id)
+    .execute(&pool)
+    .await?
+    .rows_affected();
+  if deleted == 0 {
+    return Err(AppError::NotFound(String::from("identity not found")));
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{collections::HashMap, net::TcpListener, sync::{Arc, Mutex}};
+  use axum::{Router, Form, routing::{get, post}, http};
+  use jsonwebtoken::{EncodingKey, Header, encode};
+  use serde_json::{json, Value};
+  use crate::utils::test::{setup_with_data, test_api};
+
+  const CLIENT_ID: &str = "zmtwc";
+  const REDIRECT_URI: &str = "http://localhost:3000/oidc/callback";
+  const KEY_MODULUS: &str = "rT-BgJuIMcOieoaE2EX3VZXsbwOCgR4tCW5haw6dTEkeblZJ0ejPG-hcMkH22uiTjKTANPHfTW3DbjMoPKbC8nhRuikTy3Rw6GpyCWCKUvybaWcWCysgv9Fl8DSNc0KsOecZ-UcgHSWHMUmgxycB4IXxlMu8JRUsAG4ngkQJ76U5rn9MjqT7iOUMfr4J-8D-FMMqQ11YvV0JQYGQSbEqcVgku48-09sq7Pnw6wtaNmK6SAJmoEgaABsjS-dcnvZObAfSaZGBRTGo-dBm7-fltIf6LRz904y2GbmH09hrumMye9K8u7SOy8_cjSLd9dGjUSLiz2T0uUlfe2Bryb8NmQ";
+
+  /// Local identity provider issuing id tokens for the last authorization request.
+  struct MockIdp {
+    issuer: String,
+    challenge: Mutex<String>,
+    nonce: Mutex<String>,
+    /// claims of the next id token besides iss, aud, exp and nonce
+    claims: Mutex<Value>,
+  }
+
+  impl MockIdp {
+    fn provider(&self) -> Provider {
+      Provider {
+        name: String::from("company"),
+        issuer: self.issuer.clone(),
+        client_id: String::from(CLIENT_ID),
+        client_secret: Some(String::from("client-secret")),
+        scopes: String::from("openid email profile"),
+        create_accounts: true,
+        link_by_email: false,
+      }
+    }
+
+    fn id_token(&self) -> String {
+      let mut claims = self.claims.lock().unwrap().clone();
+      claims["iss"] = json!(self.issuer);
+      claims["aud"] = json!(CLIENT_ID);
+      claims["exp"] = json!(Utc::now().timestamp() + 300);
+      if claims.get("nonce").is_none() {
+        claims["nonce"] = json!(*self.nonce.lock().unwrap());
+      }
+      let mut header = Header::new(Algorithm::RS256);
+      header.kid = Some(String::from("test-key"));
+      let key = EncodingKey::from_rsa_pem(include_bytes!("../data/test_oidc_key.pem")).unwrap();
+      encode(&header, &claims, &key).unwrap()
+    }
+  }
+
+  async fn mock_idp(claims: Value) -> Arc<MockIdp> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let idp = Arc::new(MockIdp { issuer, challenge: Mutex::default(), nonce: Mutex::default(), claims: Mutex::new(claims) });
+
+    let discovery_idp = idp.clone();
+    let token_idp = idp.clone();
+    let router = Router::new()
+      .route("/.well-known/openid-configuration", get(move || async move {
+        let issuer = &discovery_idp.issuer;
+        Json(json!({
+          "issuer": issuer,
+          "authorization_endpoint": format!("{issuer}/authorize"),
+          "token_endpoint": format!("{issuer}/token"),
+          "jwks_uri": format!("{issuer}/jwks"),
+        }))
+      }))
+      .route("/jwks", get(|| async {
+        Json(json!({ "keys": [{ "kty": "RSA", "kid": "test-key", "use": "sig", "alg": "RS256", "n": KEY_MODULUS, "e": "AQAB" }] }))
+      }))
+      .route("/token", post(move |Form(form): Form<HashMap<String, String>>| async move {
+        let verified = form["grant_type"] == "authorization_code"
+          && form["code"] == "mock-code"
+          && form["client_id"] == CLIENT_ID
+          && form["client_secret"] == "client-secret"
+          && form["redirect_uri"] == REDIRECT_URI
+          && code_challenge(&form["code_verifier"]) == *token_idp.challenge.lock().unwrap();
+        if !verified {
+          return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" })));
+        }
+        (StatusCode::OK, Json(json!({ "access_token": "access", "token_type": "Bearer", "id_token": token_idp.id_token() })))
+      }));
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()));
+    idp
+  }
+
+  fn ok<T>(result: Result<T, AppError>) -> T {
+    match result {
+      Ok(value) => value,
+      Err(_) => panic!("request failed"),
+    }
+  }
+
+  /// Starts a login and lets the identity provider accept it, returns the state and the token of the browser.
+  async fn authorize_at(pool: &DbState, idp: &MockIdp, link_user: Option<i64>) -> (String, String) {
+    let (authorization, browser) = ok(start_login(pool, &idp.provider(), REDIRECT_URI, link_user).await);
+    let url = Url::parse(&authorization.authorization_url).unwrap();
+    assert!(url.as_str().starts_with(&format!("{}/authorize?", idp.issuer)));
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["redirect_uri"], REDIRECT_URI);
+    assert_eq!(params["state"], authorization.state);
+    assert_eq!(params["code_challenge_method"], "S256");
+    *idp.challenge.lock().unwrap() = params["code_challenge"].clone();
+    *idp.nonce.lock().unwrap() = params["nonce"].clone();
+    (authorization.state, browser)
+  }
+
+  async fn login(pool: &DbState, idp: &MockIdp) -> Result<Value, AppError> {
+    let (state, browser) = authorize_at(pool, idp, None).await;
+    finish_login(pool, &[idp.provider()], &state, "mock-code", Some(&browser), None).await.map(|r| serde_json::to_value(r).unwrap())
+  }
+
+  #[tokio::test]
+  async fn first_login() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001", "email": "janko@example.com", "email_verified": true, "preferred_username": "janko" })).await;
+
+    let response = ok(login(&pool, &idp).await);
+    assert_eq!(response["id"], 7);
+    assert!(response["token"].is_string());
+    let user = sqlx::query!("select username, email, email_verified_at from user where id = 7")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(user.username, "janko");
+    assert_eq!(user.email.as_deref(), Some("janko@example.com"));
+    assert!(user.email_verified_at.is_some());
+
+    // the identity is linked now
+    let response = ok(login(&pool, &idp).await);
+    assert_eq!(response["id"], 7);
+  }
+
+  #[tokio::test]
+  async fn taken_username() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001", "preferred_username": "UserName1" })).await;
+
+    let _ = ok(login(&pool, &idp).await);
+    let user = sqlx::query!("select username from user where id = 7")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(user.username, "UserName12");
+  }
+
+  #[tokio::test]
+  async fn link() {
+    let (app, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001", "email": "janko@example.com" })).await;
+
+    let (state, browser) = authorize_at(&pool, &idp, Some(2)).await;
+    let _ = ok(finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), Some(2)).await);
+    let response = ok(login(&pool, &idp).await);
+    assert_eq!(response["id"], 2);
+
+    let response = test_api(app.clone(), "/user/2/identities", http::Method::GET, None, StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+    assert_eq!(response[0]["provider"], "company");
+    assert_eq!(response[0]["subject"], "248289761001");
+    let _ = test_api(app.clone(), "/user/2/identities/1", http::Method::DELETE, None, StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+    let _ = test_api(app, "/user/2/identities/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+  }
+
+  #[tokio::test]
+  async fn linked_to_another() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    let (state, browser) = authorize_at(&pool, &idp, Some(2)).await;
+    let _ = ok(finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), Some(2)).await);
+    let (state, browser) = authorize_at(&pool, &idp, Some(3)).await;
+    let result = finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), Some(3)).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+  }
+
+  #[tokio::test]
+  async fn link_by_another_user() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    // the link started by user 2 cannot be finished by someone else
+    for auth_user in [None, Some(3)] {
+      let (state, browser) = authorize_at(&pool, &idp, Some(2)).await;
+      let result = finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), auth_user).await;
+      assert!(matches!(result, Err(AppError::Forbidden(_))));
+    }
+    let linked = sqlx::query!("select id from user_identity")
+      .fetch_all(&pool)
+      .await
+      .unwrap();
+    assert!(linked.is_empty());
+  }
+
+  #[tokio::test]
+  async fn another_browser() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    for browser in [None, Some("another")] {
+      let (state, _) = authorize_at(&pool, &idp, None).await;
+      let result = finish_login(&pool, &[idp.provider()], &state, "mock-code", browser, None).await;
+      assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+  }
+
+  #[tokio::test]
+  async fn link_by_email() {
+    let (_, pool) = setup_with_data().await;
+    sqlx::query("UPDATE user SET email = 'user3@example.com', email_verified_at = 1664841600 WHERE id = 3")
+      .execute(&pool)
+      .await
+      .unwrap();
+    let idp = mock_idp(json!({ "sub": "248289761001", "email": "user3@example.com", "email_verified": true })).await;
+
+    let providers = [Provider { link_by_email: true, create_accounts: false, ..idp.provider() }];
+    let (state, browser) = authorize_at(&pool, &idp, None).await;
+    let response = ok(finish_login(&pool, &providers, &state, "mock-code", Some(&browser), None).await);
+    assert_eq!(serde_json::to_value(response).unwrap()["id"], 3);
+
+    // not linked without a verified address
+    *idp.claims.lock().unwrap() = json!({ "sub": "248289761002", "email": "user3@example.com", "email_verified": false });
+    let (state, browser) = authorize_at(&pool, &idp, None).await;
+    let result = finish_login(&pool, &providers, &state, "mock-code", Some(&browser), None).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+  }
+
+  #[tokio::test]
+  async fn replayed_state() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    let (state, browser) = authorize_at(&pool, &idp, None).await;
+    let _ = ok(finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), None).await);
+    let result = finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), None).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+  }
+
+  #[tokio::test]
+  async fn invalid_nonce() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001", "nonce": "another" })).await;
+
+    assert!(matches!(login(&pool, &idp).await, Err(AppError::Unauthorized(_))));
+  }
+
+  #[tokio::test]
+  async fn invalid_code_verifier() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    let (state, browser) = authorize_at(&pool, &idp, None).await;
+    *idp.challenge.lock().unwrap() = code_challenge("another verifier");
+    let result = finish_login(&pool, &[idp.provider()], &state, "mock-code", Some(&browser), None).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+  }
+
+  #[tokio::test]
+  async fn other_audience() {
+    let (_, pool) = setup_with_data().await;
+    let idp = mock_idp(json!({ "sub": "248289761001" })).await;
+
+    let (state, browser) = authorize_at(&pool, &idp, None).await;
+    let provider = Provider { client_id: String::from("another-client"), ..idp.provider() };
+    let result = finish_login(&pool, &[provider], &state, "mock-code", Some(&browser), None).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+  }
+
+  #[tokio::test]
+  async fn unknown_provider() {
+    let (app, _) = setup_with_data().await;
+
+    let _ = test_api(app, "/oidc/unknown/authorize", http::Method::POST, None, StatusCode::NOT_FOUND, None).await;
+  }
+}
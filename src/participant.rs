@@ -3,8 +3,9 @@ use axum::{
 };
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{DbState, utils::AppReponse, error::AppError, auth::{UserAuth, user_action_authorization}};
+use crate::{DbState, utils::AppReponse, error::AppError, auth::{UserAuth, user_action_authorization, event_action_authorization, verified_authorization, Restriction}, user::User, webhook::{WebhookQueue, WebhookEventType}, live::LiveUpdates};
 
 #[derive(Deserialize)]
 pub struct CreateParticipant {
@@ -13,19 +14,37 @@ pub struct CreateParticipant {
 }
 
 
+#[derive(Deserialize)]
+pub struct UpdateParticipant {
+  /// weight in the settlement of the expenses, 0 for participants who don't attend
+  share: i64,
+}
+
 #[derive(Serialize)]
 pub struct CreateParticipantResponse {
   username: String,
   user: i64,
 }
 
+#[derive(Serialize)]
+pub struct Invitation {
+  event: i64,
+  name: String,
+  time: Option<i64>,
+  invited_by: User,
+  created_at: i64,
+}
+
 pub async fn create(
   Json(payload): Json<CreateParticipant>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<Json<CreateParticipantResponse>> {
   let CreateParticipant { event, user } = payload;
   user_action_authorization(user, auth_userid, "cannot make participation for another user")?;
+  verified_authorization(&pool, auth_userid, Restriction::JoinEvent).await?;
 
   let selected_user = sqlx::query!(
       r#"
@@ -47,24 +66,129 @@ pub async fn create(
     .await?
     .last_insert_rowid();
 
+  let _ = sqlx::query!(
+      r#"
+  DELETE FROM invitation
+  WHERE event = ?1 AND user = ?2
+      "#,
+      event, user
+    )
+    .execute(&pool)
+    .await?;
+
   let participant = CreateParticipantResponse {
     user,
     username: selected_user.username
   };
+  let data = serde_json::to_value(&participant)?;
+  live.publish(event, WebhookEventType::ParticipantJoined, data.clone()).await;
+  webhooks.dispatch(event, WebhookEventType::ParticipantJoined, data).await;
   Ok((StatusCode::CREATED, Json(participant)))
 }
 
 pub async fn delete(
   Path((user_id, event_id)): Path<(i64, i64)>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   if user_id != auth_userid {
-    return Err(AppError::Unauthorized(format!("cannot remove  participation for another user")));
+    return Err(AppError::Unauthorized(String::from("cannot remove  participation for another user")));
   }
-  let _ = sqlx::query!(
+  let deleted = sqlx::query!(
       r#"
   DELETE FROM participant
+  WHERE user = ?1 AND event = ?2
+      "#,
+      user_id, event_id
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+  if deleted > 0 {
+    let data = json!({ "user": user_id });
+    live.publish(event_id, WebhookEventType::ParticipantLeft, data.clone()).await;
+    webhooks.dispatch(event_id, WebhookEventType::ParticipantLeft, data).await;
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn update(
+  Path((user_id, event_id)): Path<(i64, i64)>,
+  Json(payload): Json<UpdateParticipant>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot change participant of event that user doesn't own").await?;
+  if payload.share < 0 {
+    return Err(AppError::BadRequest(String::from("share cannot be negative")));
+  }
+
+  let updated = sqlx::query!(
+      r#"
+  UPDATE participant SET share = ?3
+  WHERE user = ?1 AND event = ?2
+      "#,
+      user_id, event_id, payload.share
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+  if updated == 0 {
+    return Err(AppError::NotFound(format!("Cannot find participant: {user_id}/{event_id}")));
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn invitations(
+  Path(user_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Invitation>>> {
+  user_action_authorization(user_id, auth_userid, "cannot get invitations of another user")?;
+
+  let invitations = sqlx::query!(
+      r#"
+  SELECT invitation.event, event.name, event.time, invitation.invited_by, user.username, invitation.created_at
+  FROM invitation
+  JOIN event ON invitation.event = event.id
+  JOIN user ON invitation.invited_by = user.id
+  WHERE invitation.user = ?1
+  ORDER BY event.time
+      "#,
+      user_id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|i| Invitation {
+      event: i.event,
+      name: i.name,
+      time: i.time,
+      invited_by: User {
+        id: i.invited_by,
+        username: i.username,
+      },
+      created_at: i.created_at,
+    })
+    .collect();
+
+  Ok((StatusCode::OK, Json(invitations)))
+}
+
+pub async fn decline_invitation(
+  Path((user_id, event_id)): Path<(i64, i64)>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(user_id, auth_userid, "cannot decline invitation for another user")?;
+
+  let _ = sqlx::query!(
+      r#"
+  DELETE FROM invitation
   WHERE user = ?1 AND event = ?2
       "#,
       user_id, event_id
@@ -131,6 +255,98 @@ mod test {
     }
   }
 
+  mod invitation {
+    use super::*;
+
+    async fn insert_invitation(pool: &DbState) {
+      sqlx::query("INSERT INTO invitation (user, event, invited_by, created_at) VALUES (1, 3, 4, 1664841600)")
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn all() {
+      let (app, pool) = setup_with_data().await;
+      insert_invitation(&pool).await;
+      let expected_response = json!([{
+        "event": 3,
+        "name": "event-3",
+        "time": 1664928000,
+        "invited_by": {
+          "id": 4,
+          "username": "username4"
+        },
+        "created_at": 1664841600
+      }]);
+
+      let response = test_api(app, "/user/1/invitations", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn accept() {
+      let (app, pool) = setup_with_data().await;
+      insert_invitation(&pool).await;
+      let body_json = json!({
+        "event": 3,
+        "user": 1,
+      });
+
+      let _ = test_api(app, "/participant", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user from invitation")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn decline() {
+      let (app, pool) = setup_with_data().await;
+      insert_invitation(&pool).await;
+
+      let _ = test_api(app, "/invitation/1/3", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select user from invitation")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+  }
+
+  mod update {
+    use super::*;
+
+    #[tokio::test]
+    async fn share() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "share": 2,
+      });
+
+      let _ = test_api(app, "/participant/3/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select share from participant where user = 3 and event = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.share, 2);
+    }
+
+    #[tokio::test]
+    async fn share_for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "share": 2,
+      });
+
+      let _ = test_api(app, "/participant/3/1", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("3", "username3"))).await;
+    }
+  }
+
   mod delete {
     use super::*;
 
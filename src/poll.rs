@@ -0,0 +1,422 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, event_action_authorization, event_member_authorization}, user::User};
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PollKind {
+  Single,
+  Multiple,
+  Text,
+}
+
+#[derive(Serialize)]
+pub struct Poll {
+  id: i64,
+  event: i64,
+  question: String,
+  kind: PollKind,
+  anonymous: bool,
+  closes_at: Option<i64>,
+  closed: bool,
+  respondents: i64,
+  options: Vec<PollOption>,
+  answers: Vec<TextAnswer>,
+}
+
+#[derive(Serialize)]
+pub struct PollOption {
+  id: i64,
+  text: String,
+  votes: i64,
+  users: Vec<User>,
+}
+
+#[derive(Serialize)]
+pub struct TextAnswer {
+  user: Option<User>,
+  text: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePoll {
+  question: String,
+  kind: PollKind,
+  #[serde(default)]
+  options: Vec<String>,
+  #[serde(default)]
+  anonymous: bool,
+  closes_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AnswerPoll {
+  #[serde(default)]
+  options: Vec<i64>,
+  text: Option<String>,
+}
+
+async fn poll_event(pool: &DbState, id: i64) -> Result<i64, AppError> {
+  let poll = sqlx::query!("SELECT event FROM poll WHERE id = ?1", id)
+    .fetch_optional(pool)
+    .await?;
+  match poll {
+    Some(p) => Ok(p.event),
+    None => Err(AppError::NotFound(format!("Cannot find poll: {id}"))),
+  }
+}
+
+pub async fn get_db_polls(pool: &DbState, event_id: i64, only: Option<i64>) -> Result<Vec<Poll>, AppError> {
+  let now = Utc::now().timestamp();
+  let mut polls: Vec<Poll> = sqlx::query!(
+      r#"
+  SELECT id as "id!", question, kind as "kind: PollKind", anonymous as "anonymous: bool", closes_at, closed_at
+  FROM poll
+  WHERE event = ?1 AND (?2 IS NULL OR id = ?2)
+  ORDER BY id
+      "#,
+      event_id, only
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|p| Poll {
+      id: p.id,
+      event: event_id,
+      question: p.question,
+      kind: p.kind,
+      anonymous: p.anonymous,
+      closed: p.closed_at.is_some() || p.closes_at.is_some_and(|c| c <= now),
+      closes_at: p.closes_at,
+      respondents: 0,
+      options: vec![],
+      answers: vec![],
+    })
+    .collect();
+
+  let options = sqlx::query!(
+      r#"
+  SELECT id as "id!", poll, text FROM poll_option
+  WHERE poll in (SELECT id FROM poll WHERE event = ?1)
+  ORDER BY id
+      "#,
+      event_id
+    )
+    .fetch_all(pool)
+    .await?;
+  for o in options {
+    if let Some(p) = polls.iter_mut().find(|p| p.id == o.poll) {
+      p.options.push(PollOption { id: o.id, text: o.text, votes: 0, users: vec![] });
+    }
+  }
+
+  let answers = sqlx::query!(
+      r#"
+  SELECT poll_answer.poll, poll_answer.option, poll_answer.text, user.id as "user_id!", user.username
+  FROM poll_answer
+  JOIN user ON poll_answer.user = user.id
+  WHERE poll_answer.poll in (SELECT id FROM poll WHERE event = ?1)
+  ORDER BY poll_answer.id
+      "#,
+      event_id
+    )
+    .fetch_all(pool)
+    .await?;
+  for p in polls.iter_mut() {
+    let mut respondents: Vec<i64> = vec![];
+    for a in answers.iter().filter(|a| a.poll == p.id) {
+      if !respondents.contains(&a.user_id) {
+        respondents.push(a.user_id);
+      }
+      let user = if p.anonymous { None } else { Some(User { id: a.user_id, username: a.username.clone() }) };
+      match (a.option, &a.text) {
+        (Some(option), _) => {
+          if let Some(o) = p.options.iter_mut().find(|o| o.id == option) {
+            o.votes += 1;
+            o.users.extend(user);
+          }
+        },
+        (None, Some(text)) => p.answers.push(TextAnswer { user, text: text.clone() }),
+        (None, None) => {},
+      }
+    }
+    p.respondents = respondents.len() as i64;
+  }
+
+  Ok(polls)
+}
+
+pub async fn create(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<CreatePoll>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Poll>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot create poll for event that user doesn't own").await?;
+  let CreatePoll { question, kind, options, anonymous, closes_at } = payload;
+  if question.trim().is_empty() {
+    return Err(AppError::BadRequest(String::from("question cannot be empty")));
+  }
+  match kind {
+    PollKind::Text if !options.is_empty() => {
+      return Err(AppError::BadRequest(String::from("text poll cannot have options")));
+    },
+    PollKind::Single | PollKind::Multiple if options.len() < 2 => {
+      return Err(AppError::BadRequest(String::from("choice poll needs at least two options")));
+    },
+    _ => {},
+  }
+
+  let created_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO poll ( event, question, kind, anonymous, closes_at, created_by, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+      "#,
+      event_id, question, kind, anonymous, closes_at, auth_userid, created_at
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+  for text in options {
+    let _ = sqlx::query!("INSERT INTO poll_option ( poll, text ) VALUES ( ?1, ?2 )", id, text)
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  let poll = get_db_polls(&pool, event_id, Some(id)).await?.remove(0);
+  Ok((StatusCode::CREATED, Json(poll)))
+}
+
+pub async fn answer(
+  Path(id): Path<i64>,
+  Json(payload): Json<AnswerPoll>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Poll>> {
+  let event_id = poll_event(&pool, id).await?;
+  event_member_authorization(&pool, event_id, auth_userid, "cannot answer poll of event that user doesn't take part in").await?;
+
+  let poll = get_db_polls(&pool, event_id, Some(id)).await?.remove(0);
+  if poll.closed {
+    return Err(AppError::BadRequest(String::from("poll is already closed")));
+  }
+  let mut options = payload.options;
+  options.sort_unstable();
+  options.dedup();
+  if let Some(option) = options.iter().find(|o| !poll.options.iter().any(|p| p.id == **o)) {
+    return Err(AppError::BadRequest(format!("option {option} doesn't belong to poll {id}")));
+  }
+  let text = payload.text.filter(|t| !t.trim().is_empty());
+  match poll.kind {
+    PollKind::Single if options.len() != 1 => {
+      return Err(AppError::BadRequest(String::from("exactly one option must be chosen")));
+    },
+    PollKind::Multiple if options.is_empty() => {
+      return Err(AppError::BadRequest(String::from("at least one option must be chosen")));
+    },
+    PollKind::Text if text.is_none() => {
+      return Err(AppError::BadRequest(String::from("answer text cannot be empty")));
+    },
+    _ => {},
+  }
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM poll_answer WHERE poll = ?1 AND user = ?2", id, auth_userid)
+    .execute(&mut tx)
+    .await?;
+  if poll.kind == PollKind::Text {
+    let _ = sqlx::query!("INSERT INTO poll_answer ( poll, user, text ) VALUES ( ?1, ?2, ?3 )", id, auth_userid, text)
+      .execute(&mut tx)
+      .await?;
+  } else {
+    for option in options {
+      let _ = sqlx::query!("INSERT INTO poll_answer ( poll, user, option ) VALUES ( ?1, ?2, ?3 )", id, auth_userid, option)
+        .execute(&mut tx)
+        .await?;
+    }
+  }
+  tx.commit().await?;
+
+  let poll = get_db_polls(&pool, event_id, Some(id)).await?.remove(0);
+  Ok((StatusCode::OK, Json(poll)))
+}
+
+pub async fn close(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Poll>> {
+  let event_id = poll_event(&pool, id).await?;
+  event_action_authorization(&pool, event_id, auth_userid, "cannot close poll for event that user doesn't own").await?;
+
+  let closed_at = Utc::now().timestamp();
+  let _ = sqlx::query!("UPDATE poll SET closed_at = ?2 WHERE id = ?1 AND closed_at IS NULL", id, closed_at)
+    .execute(&pool)
+    .await?;
+
+  let poll = get_db_polls(&pool, event_id, Some(id)).await?.remove(0);
+  Ok((StatusCode::OK, Json(poll)))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  async fn insert_poll(pool: &DbState, kind: &str, anonymous: bool) {
+    sqlx::query("INSERT INTO poll (id, event, question, kind, anonymous, created_by, created_at) VALUES (1, 1, 'pizza or burgers?', ?1, ?2, 1, 1664841600)")
+      .bind(kind)
+      .bind(anonymous)
+      .execute(pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO poll_option (id, poll, text) VALUES (1, 1, 'pizza'), (2, 1, 'burgers')")
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "question": "pizza or burgers?",
+        "kind": "single",
+        "options": ["pizza", "burgers"],
+      });
+      let expected_response = json!({
+        "id": 1,
+        "event": 1,
+        "question": "pizza or burgers?",
+        "kind": "single",
+        "anonymous": false,
+        "closes_at": null,
+        "closed": false,
+        "respondents": 0,
+        "options": [
+          { "id": 1, "text": "pizza", "votes": 0, "users": [] },
+          { "id": 2, "text": "burgers", "votes": 0, "users": [] }
+        ],
+        "answers": []
+      });
+
+      let response = test_api(app, "/event/1/polls", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "question": "who needs a ride?",
+        "kind": "text",
+      });
+
+      let _ = test_api(app, "/event/1/polls", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod answer {
+    use super::*;
+
+    #[tokio::test]
+    async fn multiple() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "multiple", false).await;
+      let body_json = json!({
+        "options": [1, 2],
+      });
+
+      let response = test_api(app, "/poll/1/answer", http::Method::POST, Some(body_json), StatusCode::OK, Some(("2", "username2"))).await.unwrap();
+      assert_eq!(response["respondents"], 1);
+      assert_eq!(response["options"][0]["votes"], 1);
+      assert_eq!(response["options"][1]["users"], json!([{ "id": 2, "username": "username2" }]));
+    }
+
+    #[tokio::test]
+    async fn single_with_two_options() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", false).await;
+      let body_json = json!({
+        "options": [1, 2],
+      });
+
+      let _ = test_api(app, "/poll/1/answer", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("2", "username2"))).await;
+    }
+
+    #[tokio::test]
+    async fn anonymous() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", true).await;
+      let body_json = json!({
+        "options": [2],
+      });
+
+      let response = test_api(app, "/poll/1/answer", http::Method::POST, Some(body_json), StatusCode::OK, Some(("3", "username3"))).await.unwrap();
+      assert_eq!(response["options"][1]["votes"], 1);
+      assert_eq!(response["options"][1]["users"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn as_outsider() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", false).await;
+      let body_json = json!({
+        "options": [1],
+      });
+
+      let _ = test_api(app, "/poll/1/answer", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("5", "username5"))).await;
+    }
+
+    #[tokio::test]
+    async fn after_close() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", false).await;
+      sqlx::query("UPDATE poll SET closes_at = 1664841600 WHERE id = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "options": [1],
+      });
+
+      let _ = test_api(app, "/poll/1/answer", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod close {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", false).await;
+
+      let response = test_api(app, "/poll/1/close", http::Method::POST, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response["closed"], true);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, pool) = setup_with_data().await;
+      insert_poll(&pool, "single", false).await;
+
+      let _ = test_api(app, "/poll/1/close", http::Method::POST, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+}
@@ -0,0 +1,147 @@
+use std::env;
+
+use chrono::{Utc, Duration};
+
+use crate::{DbState, error::AppError, notification::{NotifierState, NotificationKind, insert_db_notification}};
+
+pub const DEFAULT_INTERVAL_SECONDS: u64 = 3600;
+pub const DEFAULT_WINDOW_HOURS: i64 = 48;
+
+pub async fn schedule(pool: DbState, notifier: NotifierState) {
+  let interval = env::var("REMINDER_INTERVAL_SECONDS")
+    .ok()
+    .and_then(|i| i.parse().ok())
+    .unwrap_or(DEFAULT_INTERVAL_SECONDS);
+  let window = env::var("REMINDER_WINDOW_HOURS")
+    .ok()
+    .and_then(|w| w.parse().ok())
+    .unwrap_or(DEFAULT_WINDOW_HOURS);
+
+  let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+  loop {
+    ticker.tick().await;
+    if let Err(e) = send_reminders(&pool, &notifier, Duration::hours(window)).await {
+      tracing::error!("sending reminders failed: {e}");
+    }
+  }
+}
+
+pub async fn send_reminders(pool: &DbState, notifier: &NotifierState, window: Duration) -> Result<usize, AppError> {
+  let now = Utc::now();
+  let until = (now + window).timestamp();
+  let now = now.timestamp();
+
+  let requirements = sqlx::query!(
+      r#"
+  SELECT requirement.id, requirement.name, requirement.event, event.name as event_name, event.creator
+  FROM requirement
+  JOIN event ON requirement.event = event.id
+  WHERE requirement.reminded_at IS NULL
+    AND requirement.deadline > ?1 AND requirement.deadline <= ?2
+    AND requirement.size > (
+      SELECT COUNT(1) FROM fullfillment
+      WHERE fullfillment.requirement = requirement.id AND fullfillment.status != 'cancelled'
+    )
+      "#,
+      now, until
+    )
+    .fetch_all(pool)
+    .await?;
+
+  let mut notifications = vec![];
+  let mut tx = pool.begin().await?;
+  for r in requirements {
+    let mut recipients: Vec<i64> = sqlx::query!(
+        r#"
+  SELECT user FROM participant
+  WHERE event = ?1 AND user NOT IN (
+    SELECT fullfillment.user FROM fullfillment
+    JOIN requirement ON fullfillment.requirement = requirement.id
+    WHERE requirement.event = ?1 AND fullfillment.status != 'cancelled'
+  )
+        "#,
+        r.event
+      )
+      .fetch_all(&mut tx)
+      .await?
+      .into_iter()
+      .map(|p| p.user)
+      .collect();
+    if !recipients.contains(&r.creator) {
+      recipients.insert(0, r.creator);
+    }
+
+    let message = format!("Requirement '{}' of event '{}' is still not fullfilled and its deadline is approaching", r.name, r.event_name);
+    for user in recipients {
+      notifications.extend(insert_db_notification(&mut tx, user, NotificationKind::RequirementDeadline, message.clone(), Some(r.event), Some(r.id), None).await?);
+    }
+
+    let _ = sqlx::query!("UPDATE requirement SET reminded_at = ?2 WHERE id = ?1", r.id, now)
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  Ok(notifications.len())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::Arc;
+  use crate::{notification::test::MemoryNotifier, utils::test::setup_with_data};
+
+  #[tokio::test]
+  async fn unfullfilled_requirement() {
+    let (_, pool) = setup_with_data().await;
+    let deadline = (Utc::now() + Duration::hours(1)).timestamp();
+    sqlx::query("UPDATE requirement SET deadline = ?1 WHERE id = 2")
+      .bind(deadline)
+      .execute(&pool)
+      .await
+      .unwrap();
+    let memory = Arc::new(MemoryNotifier::default());
+    let notifier: NotifierState = memory.clone();
+
+    let sent = send_reminders(&pool, &notifier, Duration::hours(DEFAULT_WINDOW_HOURS)).await.ok().unwrap();
+    assert_eq!(sent, 3);
+    let users: Vec<i64> = memory.0.lock().unwrap().iter().map(|n| n.user).collect();
+    assert_eq!(users, vec![1, 2, 3]);
+
+    let results = sqlx::query!("select user, kind, requirement from notification")
+      .fetch_all(&pool)
+      .await
+      .unwrap();
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|n| n.kind == "requirement_deadline" && n.requirement == Some(2)));
+
+    let sent = send_reminders(&pool, &notifier, Duration::hours(DEFAULT_WINDOW_HOURS)).await.ok().unwrap();
+    assert_eq!(sent, 0);
+  }
+
+  #[tokio::test]
+  async fn outside_window_or_full() {
+    let (_, pool) = setup_with_data().await;
+    let far = (Utc::now() + Duration::days(10)).timestamp();
+    let soon = (Utc::now() + Duration::hours(1)).timestamp();
+    sqlx::query("UPDATE requirement SET deadline = ?1 WHERE id = 2")
+      .bind(far)
+      .execute(&pool)
+      .await
+      .unwrap();
+    sqlx::query("UPDATE requirement SET deadline = ?1 WHERE id = 3")
+      .bind(soon)
+      .execute(&pool)
+      .await
+      .unwrap();
+    let notifier: NotifierState = Arc::new(MemoryNotifier::default());
+
+    let sent = send_reminders(&pool, &notifier, Duration::hours(DEFAULT_WINDOW_HOURS)).await.ok().unwrap();
+    assert_eq!(sent, 0);
+  }
+}
@@ -1,18 +1,33 @@
+use std::collections::HashSet;
 use axum::{
   Json, Extension, extract::Path,
 };
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
-
-use crate::{DbState, error::{AppError}, db_modeling::{Updatable, self}, utils::AppReponse, auth::{UserAuth, event_action_authorization, requirement_action_authorization}};
+use serde_json::json;
+use sqlx::SqliteConnection;
+
+use crate::{DbState, error::{AppError, ItemError}, db_modeling, utils::AppReponse, auth::{UserAuth, event_action_authorization, requirement_action_authorization}, notification::NotifierState, webhook::{WebhookQueue, WebhookEventType}, live::LiveUpdates};
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Priority {
+  #[default]
+  MustHave,
+  NiceToHave,
+}
 
 #[derive(Serialize)]
 pub struct Requirement {
-  id: i64,
-  name: String,
-  description: Option<String>,
-  size: i64,
-  event: i64
+  pub id: i64,
+  pub name: String,
+  pub description: Option<String>,
+  pub size: i64,
+  pub event: i64,
+  pub category: Option<i64>,
+  pub priority: Priority,
+  pub deadline: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -21,6 +36,9 @@ pub struct CreateRequirement {
   description: Option<String>,
   size: Option<i64>,
   event: i64,
+  category: Option<i64>,
+  priority: Option<Priority>,
+  deadline: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -28,47 +46,172 @@ pub struct UpdateRequirement {
   name: Option<String>,
   description: Option<String>,
   size: Option<i64>,
+  category: Option<i64>,
+  priority: Option<Priority>,
+  deadline: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReorderRequirements {
+  order: Vec<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchUpdateRequirement {
+  id: i64,
+  #[serde(flatten)]
+  update: UpdateRequirement,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequirements {
+  #[serde(default)]
+  create: Vec<CreateRequirement>,
+  #[serde(default)]
+  update: Vec<BatchUpdateRequirement>,
+  #[serde(default)]
+  delete: Vec<i64>,
+  /// new order of the requirements left after the deletes, created ones are appended
+  order: Option<Vec<i64>>,
 }
 
-impl Updatable for UpdateRequirement {
-  fn update_string(&self) -> String {
-    let mut updates = vec![];
-    if let Some(name) = &self.name {
-      updates.push(format!("name = '{name}'"));
+impl BatchRequirements {
+  fn validate(&self, event: i64, existing: &[i64], categories: &[i64]) -> Vec<ItemError> {
+    let mut errors = vec![];
+    let mut error = |operation: &str, index: usize, msg: String| errors.push(ItemError {
+      operation: String::from(operation),
+      index,
+      error: msg,
+    });
+
+    for (index, r) in self.create.iter().enumerate() {
+      if r.event != event {
+        error("create", index, format!("requirement must belong to event {event}"));
+      }
+      if r.name.trim().is_empty() {
+        error("create", index, String::from("name cannot be empty"));
+      }
+      if r.size.is_some_and(|size| size < 1) {
+        error("create", index, String::from("size must be at least 1"));
+      }
+      if r.category.is_some_and(|category| !categories.contains(&category)) {
+        error("create", index, format!("category must belong to event {event}"));
+      }
     }
-    if let Some(description) = &self.description {
-      updates.push(format!("description = '{description}'"));
+    for (index, r) in self.update.iter().enumerate() {
+      if !existing.contains(&r.id) {
+        error("update", index, format!("requirement {} doesn't belong to event {event}", r.id));
+      }
+      if self.delete.contains(&r.id) {
+        error("update", index, format!("requirement {} is also deleted", r.id));
+      }
+      if !r.update.validate() {
+        error("update", index, String::from("at least one field must be filled out"));
+      }
+      if r.update.name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+        error("update", index, String::from("name cannot be empty"));
+      }
+      if r.update.size.is_some_and(|size| size < 1) {
+        error("update", index, String::from("size must be at least 1"));
+      }
+      if r.update.category.is_some_and(|category| !categories.contains(&category)) {
+        error("update", index, format!("category must belong to event {event}"));
+      }
     }
-    if let Some(size) = &self.size {
-      updates.push(format!("size = {size}"));
+    for (index, id) in self.delete.iter().enumerate() {
+      if !existing.contains(id) {
+        error("delete", index, format!("requirement {id} doesn't belong to event {event}"));
+      }
     }
-    updates.join(", ")
+    if let Some(order) = &self.order {
+      let remaining: Vec<i64> = existing.iter().copied().filter(|id| !self.delete.contains(id)).collect();
+      for (index, msg) in order_errors(order, &remaining, event) {
+        error("order", index, msg);
+      }
+    }
+
+    errors
   }
+}
 
+/// A new order has to list every requirement of the event exactly once, otherwise positions would collide.
+fn order_errors(order: &[i64], requirements: &[i64], event: i64) -> Vec<(usize, String)> {
+  let mut errors = vec![];
+  let mut seen = HashSet::new();
+  for (index, id) in order.iter().enumerate() {
+    if !requirements.contains(id) {
+      errors.push((index, format!("requirement {id} cannot be ordered in event {event}")));
+    } else if !seen.insert(*id) {
+      errors.push((index, format!("requirement {id} is listed more than once")));
+    }
+  }
+  if errors.is_empty() && seen.len() != requirements.len() {
+    errors.push((order.len(), format!("order must list every requirement of event {event}")));
+  }
+  errors
+}
+
+impl UpdateRequirement {
   fn validate(&self) -> bool {
-    self.name.is_some() || self.description.is_some() || self.size.is_some()
+    self.name.is_some() || self.description.is_some() || self.size.is_some() || self.category.is_some() || self.priority.is_some() || self.deadline.is_some()
+  }
+}
+
+/// Changes the filled out fields, a changed deadline is reminded again.
+async fn update_db_requirement(conn: &mut SqliteConnection, id: i64, update: &UpdateRequirement) -> Result<(), AppError> {
+  let _ = sqlx::query!(
+      r#"
+  UPDATE requirement SET name = COALESCE(?2, name), description = COALESCE(?3, description), size = COALESCE(?4, size),
+    category = COALESCE(?5, category), priority = COALESCE(?6, priority), deadline = COALESCE(?7, deadline),
+    reminded_at = CASE WHEN ?7 IS NULL THEN reminded_at ELSE NULL END
+  WHERE id = ?1
+      "#,
+      id, update.name, update.description, update.size, update.category, update.priority, update.deadline
+    )
+    .execute(&mut *conn)
+    .await?;
+  Ok(())
+}
+
+async fn update_db_positions(conn: &mut SqliteConnection, order: &[i64]) -> Result<(), AppError> {
+  for (position, id) in order.iter().enumerate() {
+    let position = position as i64 + 1;
+    let _ = sqlx::query!("UPDATE requirement SET position = ?2 WHERE id = ?1", id, position)
+      .execute(&mut *conn)
+      .await?;
+  }
+  Ok(())
+}
+
+async fn category_authorization(pool: &DbState, category: i64, event: i64) -> Result<(), AppError> {
+  let category_event = sqlx::query!("SELECT event FROM category WHERE id = ?1", category)
+    .fetch_optional(pool)
+    .await?;
+  match category_event {
+    Some(c) if c.event == event => Ok(()),
+    _ => Err(AppError::BadRequest(format!("category must belong to event {event}"))),
   }
 }
 
 pub async fn create(
   Json(payload): Json<CreateRequirement>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<Json<Requirement>> {
-  let CreateRequirement { name, description, event, size } = payload;
+  let CreateRequirement { name, description, event, size, category, priority, deadline } = payload;
+  if size.is_some_and(|size| size < 1) {
+    return Err(AppError::BadRequest(String::from("size must be at least 1")));
+  }
   event_action_authorization(&pool, event, auth_userid, "cannot create requirement for event that user doesn't own").await?;
+  if let Some(category) = category {
+    category_authorization(&pool, category, event).await?;
+  }
 
   let size = size.unwrap_or(1);
-  let id = sqlx::query!(
-      r#"
-  INSERT INTO requirement ( name, description, event, size )
-  VALUES ( ?1, ?2, ?3, ?4 )
-      "#,
-      name, description, event, size
-    )
-    .execute(&pool)
-    .await?
-    .last_insert_rowid();
+  let priority = priority.unwrap_or_default();
+  let id = db_modeling::insert_db_requirement(&mut *pool.acquire().await?, &name, &description, event, size, category, priority, deadline).await?;
 
   let event = Requirement {
     id,
@@ -76,7 +219,13 @@ pub async fn create(
     description,
     event,
     size,
+    category,
+    priority,
+    deadline,
   };
+  let data = serde_json::to_value(&event)?;
+  live.publish(event.event, WebhookEventType::RequirementCreated, data.clone()).await;
+  webhooks.dispatch(event.event, WebhookEventType::RequirementCreated, data).await;
 
   Ok((StatusCode::CREATED, Json(event)))
 }
@@ -85,33 +234,53 @@ pub async fn update(
   Path(id): Path<i64>,
   Json(payload): Json<UpdateRequirement>,
   Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   if !payload.validate() {
     return Err(AppError::BadRequest(String::from("at least one field must be filled out")));
   }
+  if payload.size.is_some_and(|size| size < 1) {
+    return Err(AppError::BadRequest(String::from("size must be at least 1")));
+  }
   requirement_action_authorization(&pool, id, auth_userid, "cannot create requirement for event that user doesn't own").await?;
+  if let Some(category) = payload.category {
+    let requirement = sqlx::query!("SELECT event FROM requirement WHERE id = ?1", id)
+      .fetch_one(&pool)
+      .await?;
+    category_authorization(&pool, category, requirement.event).await?;
+  }
 
-  let sql = format!("UPDATE requirement SET {} WHERE id = ?1", payload.update_string());
-  let _ = sqlx::QueryBuilder::new(sql)
-    .build()
-    .bind(id)
-    .execute(&pool)
-    .await?;
+  let mut tx = pool.begin().await?;
+  update_db_requirement(&mut tx, id, &payload).await?;
+  let (evicted, notifications) = match payload.size {
+    Some(size) => db_modeling::trim_db_fullfillments(&mut tx, id, size).await?,
+    None => (vec![], vec![]),
+  };
+  tx.commit().await?;
 
-  if let Some(size) = payload.size {
-    let mut fullfillments = sqlx::query!("SELECT requirement, user FROM fullfillment WHERE requirement = ?1", id)
-      .fetch_all(&pool)
-      .await?;
-    let extra_fullfillments = fullfillments.drain((size as usize)..);
-    for ef in extra_fullfillments {
-      let _ = sqlx::query!(
-        r#"DELETE FROM fullfillment WHERE requirement = ?1 AND user = ?2"#,
-        ef.requirement, ef.user
-      )
-      .execute(&pool)
-      .await?;
-    }
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+
+  let requirement = sqlx::query_as!(Requirement,
+      r#"
+  SELECT id, name, description, size, event, category, priority as "priority: Priority", deadline FROM requirement
+  WHERE id = ?1
+      "#,
+      id
+    )
+    .fetch_one(&pool)
+    .await?;
+  let data = serde_json::to_value(&requirement)?;
+  live.publish(requirement.event, WebhookEventType::RequirementUpdated, data.clone()).await;
+  webhooks.dispatch(requirement.event, WebhookEventType::RequirementUpdated, data).await;
+  for user in evicted {
+    let data = json!({ "user": user, "requirement": id });
+    live.publish(requirement.event, WebhookEventType::FulfillmentDeleted, data.clone()).await;
+    webhooks.dispatch(requirement.event, WebhookEventType::FulfillmentDeleted, data).await;
   }
 
   Ok((StatusCode::NO_CONTENT, ()))
@@ -120,13 +289,137 @@ pub async fn update(
 pub async fn delete(
   Path(id): Path<i64>,
   Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   requirement_action_authorization(&pool, id, auth_userid, "cannot delete requirement for event that user doesn't own").await?;
 
-  db_modeling::delete_db_requirement(&pool, id)
-    .await
-    .and_then(|r| Ok((StatusCode::NO_CONTENT, r)))
+  let requirement = sqlx::query!("SELECT name, event FROM requirement WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  db_modeling::delete_db_requirement(&mut *pool.acquire().await?, id).await?;
+  let data = json!({ "id": id, "name": requirement.name });
+  live.publish(requirement.event, WebhookEventType::RequirementDeleted, data.clone()).await;
+  webhooks.dispatch(requirement.event, WebhookEventType::RequirementDeleted, data).await;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn batch(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<BatchRequirements>,
+  Extension(pool): Extension<DbState>,
+  Extension(notifier): Extension<NotifierState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Requirement>>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot change requirements for event that user doesn't own").await?;
+
+  let existing: Vec<i64> = sqlx::query!("SELECT id FROM requirement WHERE event = ?1", event_id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+  let categories: Vec<i64> = sqlx::query!("SELECT id FROM category WHERE event = ?1", event_id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|c| c.id)
+    .collect();
+  let errors = payload.validate(event_id, &existing, &categories);
+  if !errors.is_empty() {
+    return Err(AppError::Validation(String::from("batch contains invalid items"), errors));
+  }
+
+  let mut tx = pool.begin().await?;
+  let mut notifications = vec![];
+  let mut evicted = vec![];
+  for id in &payload.delete {
+    db_modeling::delete_db_requirement(&mut tx, *id).await?;
+  }
+  for BatchUpdateRequirement { id, update } in &payload.update {
+    update_db_requirement(&mut tx, *id, update).await?;
+    if let Some(size) = update.size {
+      let (users, trim_notifications) = db_modeling::trim_db_fullfillments(&mut tx, *id, size).await?;
+      evicted.extend(users.into_iter().map(|user| (*id, user)));
+      notifications.extend(trim_notifications);
+    }
+  }
+  if let Some(order) = &payload.order {
+    update_db_positions(&mut tx, order).await?;
+  }
+  let mut created = vec![];
+  for CreateRequirement { name, description, event, size, category, priority, deadline } in &payload.create {
+    created.push(db_modeling::insert_db_requirement(&mut tx, name, description, *event, size.unwrap_or(1), *category, priority.unwrap_or_default(), *deadline).await?);
+  }
+
+  let requirements = sqlx::query_as!(Requirement,
+      r#"
+  SELECT id as "id!", name, description, size, event, category, priority as "priority: Priority", deadline FROM requirement
+  WHERE event = ?1
+  ORDER BY position, id
+      "#,
+      event_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  for notification in &notifications {
+    notifier.deliver(notification).await;
+  }
+  for id in &payload.delete {
+    let data = json!({ "id": id });
+    live.publish(event_id, WebhookEventType::RequirementDeleted, data.clone()).await;
+    webhooks.dispatch(event_id, WebhookEventType::RequirementDeleted, data).await;
+  }
+  for requirement in &requirements {
+    let kind = if created.contains(&requirement.id) {
+      WebhookEventType::RequirementCreated
+    } else if payload.update.iter().any(|u| u.id == requirement.id) {
+      WebhookEventType::RequirementUpdated
+    } else {
+      continue;
+    };
+    let data = serde_json::to_value(requirement)?;
+    live.publish(event_id, kind, data.clone()).await;
+    webhooks.dispatch(event_id, kind, data).await;
+  }
+  for (requirement, user) in evicted {
+    let data = json!({ "user": user, "requirement": requirement });
+    live.publish(event_id, WebhookEventType::FulfillmentDeleted, data.clone()).await;
+    webhooks.dispatch(event_id, WebhookEventType::FulfillmentDeleted, data).await;
+  }
+
+  Ok((StatusCode::OK, Json(requirements)))
+}
+
+pub async fn reorder(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<ReorderRequirements>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot reorder requirements for event that user doesn't own").await?;
+
+  let existing: Vec<i64> = sqlx::query!("SELECT id FROM requirement WHERE event = ?1", event_id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+  if let Some((_, msg)) = order_errors(&payload.order, &existing, event_id).into_iter().next() {
+    return Err(AppError::BadRequest(msg));
+  }
+
+  let mut tx = pool.begin().await?;
+  update_db_positions(&mut tx, &payload.order).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
 }
 
 
@@ -158,7 +451,10 @@ mod test {
         "name": "new-req",
         "description": "new-req-desc",
         "event": 3,
-        "size": 1
+        "size": 1,
+        "category": null,
+        "priority": "must_have",
+        "deadline": null
       });
 
       let response = test_api(app, "/requirement", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("4", "username4"))).await;
@@ -185,12 +481,39 @@ mod test {
         "name": "new-req",
         "description": "new-req-desc",
         "event": 3,
-        "size": 5
+        "size": 5,
+        "category": null,
+        "priority": "must_have",
+        "deadline": null
       });
 
       let response = test_api(app, "/requirement", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("4", "username4"))).await;
       assert_eq!(response, Some(expected_response));
     }
+
+    #[tokio::test]
+    async fn zero_size() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "new-req",
+        "event": 3,
+        "size": 0
+      });
+
+      let _ = test_api(app, "/requirement", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("4", "username4"))).await;
+    }
+
+    #[tokio::test]
+    async fn negative_size() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "new-req",
+        "event": 3,
+        "size": -2
+      });
+
+      let _ = test_api(app, "/requirement", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("4", "username4"))).await;
+    }
   }
 
   mod update {
@@ -215,6 +538,43 @@ mod test {
       assert_eq!(result.description, Some("some other description 1".to_owned()));
     }
 
+    #[tokio::test]
+    async fn quoted_name() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "name": "x', size = 0 --",
+      });
+
+      let _ = test_api(app, "/requirement/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select * from requirement where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.name, "x', size = 0 --");
+      assert_eq!(result.size, 2);
+    }
+
+    #[tokio::test]
+    async fn negative_size() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "size": -1,
+      });
+
+      let _ = test_api(app, "/requirement/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn zero_size() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "size": 0,
+      });
+
+      let _ = test_api(app, "/requirement/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
     #[tokio::test]
     async fn size_with_more_fullfillments() {
       let (app, pool) = setup_with_data().await;
@@ -241,6 +601,49 @@ mod test {
       assert_eq!(result.len(), 1);
       assert_eq!(result[0].user, 4);
       assert_eq!(result[0].requirement, 1);
+
+      let result = sqlx::query!("select user, requirement from notification where kind = 'requirement_evicted'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.user, 6);
+      assert_eq!(result.requirement, Some(1));
+    }
+
+    #[tokio::test]
+    async fn category_and_priority() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO category (id, event, name) VALUES (1, 1, 'Food')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "category": 1,
+        "priority": "nice_to_have",
+      });
+
+      let _ = test_api(app, "/requirement/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select category, priority from requirement where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.category, Some(1));
+      assert_eq!(result.priority, "nice_to_have");
+    }
+
+    #[tokio::test]
+    async fn category_of_another_event() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO category (id, event, name) VALUES (1, 2, 'Food')")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "category": 1,
+      });
+
+      let _ = test_api(app, "/requirement/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
     }
 
     #[tokio::test]
@@ -255,6 +658,188 @@ mod test {
     }
   }
 
+  mod reorder {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "order": [2, 1],
+      });
+
+      let _ = test_api(app, "/event/1/requirements/order", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!(r#"select id as "id!" from requirement where event = 1 order by position"#)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results[0].id, 2);
+      assert_eq!(results[1].id, 1);
+    }
+
+    #[tokio::test]
+    async fn from_another_event() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "order": [3, 1],
+      });
+
+      let _ = test_api(app, "/event/1/requirements/order", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn partial() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "order": [2],
+      });
+
+      let _ = test_api(app, "/event/1/requirements/order", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn duplicate() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "order": [2, 2, 1],
+      });
+
+      let _ = test_api(app, "/event/1/requirements/order", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+  }
+
+  mod batch {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "create": [
+          { "name": "req5", "description": "req5-desc", "event": 1, "size": 3 },
+          { "name": "req6", "event": 1, "priority": "nice_to_have", "deadline": null }
+        ],
+        "update": [
+          { "id": 1, "size": 1 }
+        ],
+        "delete": [2]
+      });
+      let expected_response = json!([
+        { "id": 1, "name": "req1", "description": "req1-desc", "event": 1, "size": 1, "category": null, "priority": "must_have", "deadline": null },
+        { "id": 5, "name": "req5", "description": "req5-desc", "event": 1, "size": 3, "category": null, "priority": "must_have", "deadline": null },
+        { "id": 6, "name": "req6", "description": null, "event": 1, "size": 1, "category": null, "priority": "nice_to_have", "deadline": null }
+      ]);
+
+      let response = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+
+      let results = sqlx::query!("select id from requirement where id = 2")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn created_last() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "order": [2, 1],
+      });
+      let _ = test_api(app.clone(), "/event/1/requirements/order", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+      let body_json = json!({
+        "create": [
+          { "name": "req5", "event": 1 }
+        ]
+      });
+
+      let response = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      let ids: Vec<i64> = response.as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect();
+      assert_eq!(ids, vec![2, 1, 5]);
+    }
+
+    #[tokio::test]
+    async fn reorder() {
+      let (app, pool) = setup_with_data().await;
+      sqlx::query("INSERT INTO requirement (id, name, event, size, position) VALUES (5, 'req5', 1, 1, 3)")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "create": [
+          { "name": "req6", "event": 1 }
+        ],
+        "delete": [2],
+        "order": [5, 1]
+      });
+
+      let response = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      let ids: Vec<i64> = response.as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect();
+      assert_eq!(ids, vec![5, 1, 6]);
+    }
+
+    #[tokio::test]
+    async fn incomplete_order() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "delete": [2],
+        "order": [2, 1, 1]
+      });
+      let expected_response = json!({
+        "error": "batch contains invalid items",
+        "items": [
+          { "operation": "order", "index": 0, "error": "requirement 2 cannot be ordered in event 1" },
+          { "operation": "order", "index": 2, "error": "requirement 1 is listed more than once" }
+        ]
+      });
+
+      let response = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn invalid_items() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "create": [
+          { "name": "req5", "event": 1 },
+          { "name": "", "event": 1 }
+        ],
+        "update": [
+          { "id": 3, "name": "from another event" }
+        ],
+        "delete": [1]
+      });
+      let expected_response = json!({
+        "error": "batch contains invalid items",
+        "items": [
+          { "operation": "create", "index": 1, "error": "name cannot be empty" },
+          { "operation": "update", "index": 0, "error": "requirement 3 doesn't belong to event 1" }
+        ]
+      });
+
+      let response = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+
+      let results = sqlx::query!("select id from requirement where event = 1")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "delete": [1]
+      });
+
+      let _ = test_api(app, "/event/1/requirements", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
   mod delete {
     use super::*;
 
@@ -0,0 +1,218 @@
+use std::env;
+use hyper::{StatusCode, HeaderMap, Method, header::{self, HeaderValue}};
+use tower_http::cors::{AllowHeaders, Any, CorsLayer};
+
+use crate::{error::AppError, utils::AppReponse, auth::{generate_token, TOKEN_HOURS}};
+
+/// HttpOnly cookie holding the session token.
+pub const SESSION_COOKIE: &str = "session";
+/// Readable by the frontend, which repeats it in the [`CSRF_HEADER`] of changing requests.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// HttpOnly cookie binding a login at an identity provider to the browser which started it.
+pub const OIDC_COOKIE: &str = "oidc_login";
+
+/// Cookie sessions for the browser frontend, in addition to the tokens in headers.
+/// Cookies are only sent cross-origin from the origins in `CORS_ORIGINS`, see [`CookieConfig::cors`].
+#[derive(Clone, Debug)]
+pub struct CookieConfig {
+  /// logins set the session cookies and requests are authenticated by them
+  pub enabled: bool,
+  /// disable only for development over plain http
+  pub secure: bool,
+  /// `Strict`, `Lax` or `None`
+  pub same_site: String,
+}
+
+impl Default for CookieConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      secure: true,
+      same_site: String::from("Strict"),
+    }
+  }
+}
+
+impl CookieConfig {
+  pub fn from_env() -> Self {
+    let default = Self::default();
+    let same_site = match env::var("SESSION_COOKIE_SAME_SITE") {
+      Ok(s) if ["Strict", "Lax", "None"].contains(&s.as_str()) => s,
+      Ok(s) => {
+        tracing::error!("unknown SameSite value {s} of session cookies, using {}", default.same_site);
+        default.same_site
+      },
+      Err(_) => default.same_site,
+    };
+    Self {
+      enabled: env::var("SESSION_COOKIES").map(|c| c == "true" || c == "1").unwrap_or(default.enabled),
+      secure: env::var("SESSION_COOKIE_SECURE").map(|c| c == "true" || c == "1").unwrap_or(default.secure),
+      same_site,
+    }
+  }
+
+  fn cookie(&self, name: &str, value: &str, max_age: i64, http_only: bool) -> HeaderValue {
+    let mut cookie = format!("{name}={value}; Path=/; Max-Age={max_age}; SameSite={}", self.same_site);
+    if http_only {
+      cookie.push_str("; HttpOnly");
+    }
+    if self.secure {
+      cookie.push_str("; Secure");
+    }
+    HeaderValue::from_str(&cookie).expect("cookie to be a valid header")
+  }
+
+  /// Session and CSRF cookies for a new session token, none when cookie sessions are disabled.
+  pub fn login_cookies(&self, token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if self.enabled {
+      let max_age = TOKEN_HOURS * 60 * 60;
+      headers.append(header::SET_COOKIE, self.cookie(SESSION_COOKIE, token, max_age, true));
+      headers.append(header::SET_COOKIE, self.cookie(CSRF_COOKIE, &generate_token(), max_age, false));
+    }
+    headers
+  }
+
+  /// Allows any origin without credentials when `origins` is empty. Otherwise only the listed origins are allowed
+  /// and, with cookie sessions, may send the cookies. A frontend on another site also needs `SameSite=None` cookies.
+  pub fn cors(&self, origins: &[HeaderValue]) -> CorsLayer {
+    let cors = CorsLayer::new().allow_methods(vec![Method::GET, Method::POST, Method::DELETE, Method::PUT]);
+    if origins.is_empty() {
+      return cors.allow_headers(Any).allow_origin(Any);
+    }
+    // credentials cannot be combined with wildcards
+    cors
+      .allow_headers(AllowHeaders::mirror_request())
+      .allow_origin(origins.to_vec())
+      .allow_credentials(self.enabled)
+  }
+
+  /// Set even without cookie sessions, an empty value with no max age clears it.
+  pub fn oidc_cookie(&self, value: &str, max_age: i64) -> HeaderValue {
+    self.cookie(OIDC_COOKIE, value, max_age, true)
+  }
+
+  pub fn logout_cookies(&self) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.append(header::SET_COOKIE, self.cookie(SESSION_COOKIE, "", 0, true));
+    headers.append(header::SET_COOKIE, self.cookie(CSRF_COOKIE, "", 0, false));
+    headers
+  }
+}
+
+/// Origins from the comma separated `CORS_ORIGINS`, e.g. `https://app.example.com`.
+pub fn cors_origins() -> Vec<HeaderValue> {
+  env::var("CORS_ORIGINS")
+    .unwrap_or_default()
+    .split(',')
+    .map(str::trim)
+    .filter(|o| !o.is_empty())
+    .filter_map(|o| match HeaderValue::from_str(o) {
+      Ok(origin) => Some(origin),
+      Err(_) => {
+        tracing::error!("ignoring invalid CORS origin {o}");
+        None
+      },
+    })
+    .collect()
+}
+
+/// Value of the cookie from the `Cookie` headers of the request.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+  headers
+    .get_all(header::COOKIE)
+    .iter()
+    .filter_map(|h| h.to_str().ok())
+    .flat_map(|h| h.split(';'))
+    .filter_map(|c| c.trim().split_once('='))
+    .find(|(n, _)| *n == name)
+    .map(|(_, value)| value)
+}
+
+/// Double-submit check of requests authenticated by the session cookie, other sites can make the browser
+/// send the cookies but cannot read the CSRF cookie to repeat it in the header.
+pub fn check_csrf(method: &Method, headers: &HeaderMap) -> Result<(), AppError> {
+  if [Method::GET, Method::HEAD, Method::OPTIONS].contains(method) {
+    return Ok(());
+  }
+  let expected = cookie(headers, CSRF_COOKIE).filter(|c| !c.is_empty());
+  let sent = headers.get(CSRF_HEADER).and_then(|h| h.to_str().ok());
+  match (expected, sent) {
+    (Some(expected), Some(sent)) if expected == sent => Ok(()),
+    _ => Err(AppError::Forbidden(format!("`{CSRF_HEADER}` header must repeat the `{CSRF_COOKIE}` cookie"))),
+  }
+}
+
+/// Clears the session cookies, the token itself stays valid until it expires or the sessions are revoked.
+pub async fn logout() -> AppReponse<HeaderMap> {
+  Ok((StatusCode::NO_CONTENT, CookieConfig::from_env().logout_cookies()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use axum::{Router, routing::get, body::Body, http::Request};
+  use tower::ServiceExt;
+
+  fn request_headers(cookies: &str, csrf: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::COOKIE, HeaderValue::from_str(cookies).unwrap());
+    if let Some(csrf) = csrf {
+      headers.insert(CSRF_HEADER, HeaderValue::from_str(csrf).unwrap());
+    }
+    headers
+  }
+
+  #[test]
+  fn cookies() {
+    let config = CookieConfig { enabled: true, ..CookieConfig::default() };
+    let headers = config.login_cookies("jwt");
+    let cookies: Vec<&str> = headers.get_all(header::SET_COOKIE).iter().map(|c| c.to_str().unwrap()).collect();
+    assert_eq!(cookies[0], "session=jwt; Path=/; Max-Age=86400; SameSite=Strict; HttpOnly; Secure");
+    assert!(cookies[1].starts_with("csrf_token="));
+    assert!(!cookies[1].contains("HttpOnly"));
+
+    assert!(CookieConfig::default().login_cookies("jwt").is_empty());
+    let config = CookieConfig { enabled: true, secure: false, same_site: String::from("Lax") };
+    assert_eq!(config.login_cookies("jwt")[header::SET_COOKIE], "session=jwt; Path=/; Max-Age=86400; SameSite=Lax; HttpOnly");
+
+    let headers = request_headers("theme=dark; session=jwt; csrf_token=abc", None);
+    assert_eq!(cookie(&headers, "session"), Some("jwt"));
+    assert_eq!(cookie(&headers, "csrf_token"), Some("abc"));
+    assert_eq!(cookie(&headers, "missing"), None);
+  }
+
+  #[test]
+  fn csrf() {
+    assert!(check_csrf(&Method::POST, &request_headers("session=jwt; csrf_token=abc", Some("abc"))).is_ok());
+    assert!(check_csrf(&Method::POST, &request_headers("session=jwt; csrf_token=abc", Some("abd"))).is_err());
+    assert!(check_csrf(&Method::DELETE, &request_headers("session=jwt; csrf_token=abc", None)).is_err());
+    assert!(check_csrf(&Method::PUT, &request_headers("session=jwt; csrf_token=", Some(""))).is_err());
+    // reading does not change anything
+    assert!(check_csrf(&Method::GET, &request_headers("session=jwt", None)).is_ok());
+  }
+
+  #[tokio::test]
+  async fn cors() {
+    let request = |origin: &str| Request::builder().uri("/").header(header::ORIGIN, origin).body(Body::empty()).unwrap();
+    let config = CookieConfig { enabled: true, ..CookieConfig::default() };
+    let app = Router::new()
+      .route("/", get(|| async { "" }))
+      .layer(config.cors(&[HeaderValue::from_static("https://app.example.com")]));
+
+    let response = app.clone().oneshot(request("https://app.example.com")).await.unwrap();
+    assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+    assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    let response = app.oneshot(request("https://other.example.com")).await.unwrap();
+    assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+    let app = Router::new()
+      .route("/", get(|| async { "" }))
+      .layer(CookieConfig::default().cors(&[]));
+    let response = app.oneshot(request("https://other.example.com")).await.unwrap();
+    assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+  }
+}
@@ -0,0 +1,412 @@
+use axum::{
+  Json, Extension, extract::Path,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{DbState, error::AppError, db_modeling, utils::AppReponse, auth::{UserAuth, user_action_authorization, event_action_authorization}, requirement::{Requirement, Priority}, webhook::{WebhookQueue, WebhookEventType}, live::LiveUpdates};
+
+#[derive(Serialize)]
+pub struct Template {
+  id: i64,
+  name: String,
+  creator: i64,
+  requirements: Vec<TemplateRequirement>,
+}
+
+#[derive(Serialize)]
+pub struct TemplateRequirement {
+  name: String,
+  description: Option<String>,
+  size: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTemplateRequirement {
+  name: String,
+  description: Option<String>,
+  size: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTemplate {
+  name: String,
+  creator: i64,
+  requirements: Vec<CreateTemplateRequirement>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTemplateFromEvent {
+  name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApplyTemplate {
+  event: i64,
+}
+
+async fn template_action_authorization(pool: &DbState, template_id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
+  let template = sqlx::query!("SELECT creator FROM template WHERE id = ?1", template_id)
+    .fetch_optional(pool)
+    .await?;
+  match template {
+    Some(t) if t.creator == auth_id => Ok(()),
+    Some(_) => Err(AppError::Forbidden(String::from(msg))),
+    None => Err(AppError::NotFound(format!("Cannot find template: {template_id}"))),
+  }
+}
+
+async fn insert_db_template(pool: &DbState, name: &str, creator: i64, requirements: &[TemplateRequirement]) -> Result<i64, AppError> {
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO template ( name, creator )
+  VALUES ( ?1, ?2 )
+      "#,
+      name, creator
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+
+  for TemplateRequirement { name, description, size } in requirements {
+    let _ = sqlx::query!(
+        r#"
+  INSERT INTO template_requirement ( template, name, description, size )
+  VALUES ( ?1, ?2, ?3, ?4 )
+        "#,
+        id, name, description, size
+      )
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  Ok(id)
+}
+
+pub async fn create(
+  Json(payload): Json<CreateTemplate>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Template>> {
+  let CreateTemplate { name, creator, requirements } = payload;
+  user_action_authorization(creator, auth_userid, "cannot create template as another user")?;
+  if requirements.iter().any(|r| r.size.is_some_and(|size| size < 1)) {
+    return Err(AppError::BadRequest(String::from("size must be at least 1")));
+  }
+
+  let requirements: Vec<TemplateRequirement> = requirements
+    .into_iter()
+    .map(|r| TemplateRequirement {
+      name: r.name,
+      description: r.description,
+      size: r.size.unwrap_or(1),
+    })
+    .collect();
+  let id = insert_db_template(&pool, &name, creator, &requirements).await?;
+
+  Ok((StatusCode::CREATED, Json(Template { id, name, creator, requirements })))
+}
+
+pub async fn create_from_event(
+  Path(event_id): Path<i64>,
+  Json(payload): Json<CreateTemplateFromEvent>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Template>> {
+  event_action_authorization(&pool, event_id, auth_userid, "cannot create template from event that user doesn't own").await?;
+
+  let requirements = sqlx::query_as!(TemplateRequirement,
+      r#"
+  SELECT name, description, size FROM requirement
+  WHERE event = ?1
+  ORDER BY id
+      "#,
+      event_id
+    )
+    .fetch_all(&pool)
+    .await?;
+  let id = insert_db_template(&pool, &payload.name, auth_userid, &requirements).await?;
+
+  Ok((StatusCode::CREATED, Json(Template { id, name: payload.name, creator: auth_userid, requirements })))
+}
+
+pub async fn all(
+  Path(user_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Template>>> {
+  user_action_authorization(user_id, auth_userid, "cannot get templates of another user")?;
+
+  let mut templates: Vec<Template> = sqlx::query!("SELECT id, name, creator FROM template WHERE creator = ?1 ORDER BY id", user_id)
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|t| Template {
+      id: t.id,
+      name: t.name,
+      creator: t.creator,
+      requirements: vec![],
+    })
+    .collect();
+
+  let requirements = sqlx::query!(
+      r#"
+  SELECT template, name, description, size FROM template_requirement
+  WHERE template in (SELECT id FROM template WHERE creator = ?1)
+  ORDER BY id
+      "#,
+      user_id
+    )
+    .fetch_all(&pool)
+    .await?;
+  for r in requirements {
+    if let Some(t) = templates.iter_mut().find(|t| t.id == r.template) {
+      t.requirements.push(TemplateRequirement {
+        name: r.name,
+        description: r.description,
+        size: r.size,
+      });
+    }
+  }
+
+  Ok((StatusCode::OK, Json(templates)))
+}
+
+pub async fn apply(
+  Path(id): Path<i64>,
+  Json(payload): Json<ApplyTemplate>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  Extension(live): Extension<LiveUpdates>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Requirement>>> {
+  template_action_authorization(&pool, id, auth_userid, "cannot apply template that user doesn't own").await?;
+  event_action_authorization(&pool, payload.event, auth_userid, "cannot create requirement for event that user doesn't own").await?;
+
+  let template_requirements = sqlx::query_as!(TemplateRequirement,
+      r#"
+  SELECT name, description, size FROM template_requirement
+  WHERE template = ?1
+  ORDER BY id
+      "#,
+      id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+  let mut tx = pool.begin().await?;
+  let mut requirements = vec![];
+  for TemplateRequirement { name, description, size } in template_requirements {
+    let id = db_modeling::insert_db_requirement(&mut tx, &name, &description, payload.event, size, None, Priority::default(), None).await?;
+    requirements.push(Requirement {
+      id,
+      name,
+      description,
+      size,
+      event: payload.event,
+      category: None,
+      priority: Priority::default(),
+      deadline: None,
+    });
+  }
+  tx.commit().await?;
+
+  for requirement in &requirements {
+    let data = serde_json::to_value(requirement)?;
+    live.publish(payload.event, WebhookEventType::RequirementCreated, data.clone()).await;
+    webhooks.dispatch(payload.event, WebhookEventType::RequirementCreated, data).await;
+  }
+
+  Ok((StatusCode::CREATED, Json(requirements)))
+}
+
+pub async fn delete(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  template_action_authorization(&pool, id, auth_userid, "cannot delete template that user doesn't own").await?;
+
+  db_modeling::delete_db_template(&pool, id)
+    .await
+    .map(|r| (StatusCode::NO_CONTENT, r))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use crate::utils::test::{test_api, setup_with_data};
+  use axum::http;
+
+  async fn insert_template(pool: &DbState) {
+    sqlx::query("INSERT INTO template (id, name, creator) VALUES (1, 'camping', 1)")
+      .execute(pool)
+      .await
+      .unwrap();
+    sqlx::query(r#"INSERT INTO template_requirement (template, name, description, size) VALUES (1, "tent", "for 4 people", 2), (1, "stove", NULL, 1)"#)
+      .execute(pool)
+      .await
+      .unwrap();
+  }
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "camping",
+        "creator": 1,
+        "requirements": [
+          { "name": "tent", "description": "for 4 people", "size": 2 },
+          { "name": "stove" }
+        ]
+      });
+      let expected_response = json!({
+        "id": 1,
+        "name": "camping",
+        "creator": 1,
+        "requirements": [
+          { "name": "tent", "description": "for 4 people", "size": 2 },
+          { "name": "stove", "description": null, "size": 1 }
+        ]
+      });
+
+      let response = test_api(app, "/template", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn zero_size() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "camping",
+        "creator": 1,
+        "requirements": [
+          { "name": "tent", "size": 0 }
+        ]
+      });
+
+      let _ = test_api(app, "/template", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn from_event() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "event 1 again",
+      });
+      let expected_response = json!({
+        "id": 1,
+        "name": "event 1 again",
+        "creator": 1,
+        "requirements": [
+          { "name": "req1", "description": "req1-desc", "size": 2 },
+          { "name": "req2", "description": "req2-desc", "size": 1 }
+        ]
+      });
+
+      let response = test_api(app, "/event/1/template", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+
+    #[tokio::test]
+    async fn from_event_for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "name": "event 1 again",
+      });
+
+      let _ = test_api(app, "/event/1/template", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod get {
+    use super::*;
+
+    #[tokio::test]
+    async fn all() {
+      let (app, pool) = setup_with_data().await;
+      insert_template(&pool).await;
+      let expected_response = json!([{
+        "id": 1,
+        "name": "camping",
+        "creator": 1,
+        "requirements": [
+          { "name": "tent", "description": "for 4 people", "size": 2 },
+          { "name": "stove", "description": null, "size": 1 }
+        ]
+      }]);
+
+      let response = test_api(app, "/user/1/templates", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await;
+      assert_eq!(response, Some(expected_response));
+    }
+  }
+
+  mod apply {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      insert_template(&pool).await;
+      let body_json = json!({
+        "event": 4,
+      });
+
+      let response = test_api(app, "/template/1/apply", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("1", "username1"))).await;
+      assert_eq!(response.unwrap().as_array().unwrap().len(), 2);
+
+      let results = sqlx::query!("select name, size from requirement where event = 4 order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 3);
+      assert_eq!(results[1].name, "tent");
+      assert_eq!(results[1].size, 2);
+      assert_eq!(results[2].name, "stove");
+    }
+
+    #[tokio::test]
+    async fn to_another_event() {
+      let (app, pool) = setup_with_data().await;
+      insert_template(&pool).await;
+      let body_json = json!({
+        "event": 3,
+      });
+
+      let _ = test_api(app, "/template/1/apply", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+    }
+  }
+
+  mod delete {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, pool) = setup_with_data().await;
+      insert_template(&pool).await;
+
+      let _ = test_api(app, "/template/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select id from template_requirement")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, pool) = setup_with_data().await;
+      insert_template(&pool).await;
+
+      let _ = test_api(app, "/template/1", http::Method::DELETE, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+}
@@ -0,0 +1,456 @@
+use std::net::SocketAddr;
+use axum::{
+  Json, Extension, extract::{Path, ConnectInfo},
+  http::HeaderMap,
+};
+use chrono::{Utc, Duration};
+use hmac::{Hmac, Mac};
+use hyper::StatusCode;
+use rand::{Rng, RngCore, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+  DbState, error::AppError, utils::AppReponse,
+  auth::{ISSUER, UserAuth, user_action_authorization, hash_token},
+  user::{TokenKind, UserAuthRespData, insert_db_token, use_db_token},
+  login_limit::{self, LoginConfig},
+  session::CookieConfig,
+};
+
+/// How long the pending token returned by `/authentificate` can be exchanged.
+pub const PENDING_MINUTES: i64 = 5;
+const PERIOD: i64 = 30;
+const DIGITS: u32 = 6;
+/// accepted clock difference of the authenticator app, in periods
+const SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Serialize)]
+pub struct Enrolment {
+  secret: String,
+  /// `otpauth://` uri for authenticator apps, usually shown as a QR code
+  uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeReq {
+  /// code from the authenticator app or a recovery code
+  code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+  recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorReq {
+  token: String,
+  code: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TwoFactorPending {
+  id: i64,
+  two_factor_token: String,
+  /// seconds
+  expires_in: i64,
+}
+
+/// Random 160-bit secret, base32 encoded as authenticator apps expect it.
+fn generate_secret() -> String {
+  let mut secret = [0u8; 20];
+  rand::thread_rng().fill_bytes(&mut secret);
+  base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+}
+
+fn provisioning_uri(secret: &str, username: &str) -> String {
+  let label = format!("{ISSUER}:{username}");
+  format!(
+    "otpauth://totp/{}?secret={secret}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={PERIOD}",
+    percent_encode(&label), percent_encode(ISSUER)
+  )
+}
+
+fn percent_encode(value: &str) -> String {
+  value
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// HOTP (RFC 4226) value of the counter, which is the time step for TOTP (RFC 6238).
+fn code(secret: &[u8], step: i64) -> String {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac to accept keys of any length");
+  mac.update(&step.to_be_bytes());
+  let hash = mac.finalize().into_bytes();
+
+  let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+  let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+  format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Returns the matched time step, steps up to `last_step` were already used and are rejected.
+fn verify_code(secret: &str, code_input: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+  let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+  let current = now / PERIOD;
+  (current - SKEW..=current + SKEW)
+    .filter(|step| last_step.is_none_or(|last| *step > last))
+    .find(|step| code(&secret, *step) == code_input)
+}
+
+fn generate_recovery_code() -> String {
+  let code: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(10)
+    .map(|c| char::from(c).to_ascii_lowercase())
+    .collect();
+  format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Recovery codes are compared without the dash, spaces and letter case.
+fn hash_recovery_code(code: &str) -> String {
+  let normalized: String = code
+    .chars()
+    .filter(|c| c.is_ascii_alphanumeric())
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+  hash_token(&normalized)
+}
+
+fn is_totp_code(code: &str) -> bool {
+  code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks a code from the authenticator app or uses up a recovery code.
+async fn verify_second_factor(pool: &DbState, user: i64, code_input: &str) -> Result<bool, AppError> {
+  let code_input = code_input.trim();
+  if is_totp_code(code_input) {
+    let totp = sqlx::query!(r#"SELECT totp_secret as "totp_secret!", totp_last_step FROM user WHERE id = ?1 AND totp_secret IS NOT NULL"#, user)
+      .fetch_one(pool)
+      .await?;
+    let step = match verify_code(&totp.totp_secret, code_input, Utc::now().timestamp(), totp.totp_last_step) {
+      Some(step) => step,
+      None => return Ok(false),
+    };
+    // the same code cannot be used twice
+    let updated = sqlx::query!(
+        "UPDATE user SET totp_last_step = ?2 WHERE id = ?1 AND (totp_last_step IS NULL OR totp_last_step < ?2)",
+        user, step
+      )
+      .execute(pool)
+      .await?
+      .rows_affected();
+    return Ok(updated == 1);
+  }
+
+  let now = Utc::now().timestamp();
+  let code_hash = hash_recovery_code(code_input);
+  let used = sqlx::query!(
+      "UPDATE recovery_code SET used_at = ?3 WHERE user = ?1 AND code_hash = ?2 AND used_at IS NULL",
+      user, code_hash, now
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+  Ok(used == 1)
+}
+
+pub async fn pending_login(pool: &DbState, user: i64) -> Result<TwoFactorPending, AppError> {
+  let token = insert_db_token(&mut *pool.acquire().await?, user, TokenKind::TwoFactor, Duration::minutes(PENDING_MINUTES)).await?;
+  Ok(TwoFactorPending { id: user, two_factor_token: token, expires_in: PENDING_MINUTES * 60 })
+}
+
+/// Starts the enrolment, two-factor authentication is enabled once a first code is confirmed.
+pub async fn enrol(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Enrolment>> {
+  user_action_authorization(id, auth_userid, "cannot enable two-factor authentication of another user")?;
+
+  let user = sqlx::query!("SELECT username, totp_enabled_at FROM user WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  if user.totp_enabled_at.is_some() {
+    return Err(AppError::BadRequest(String::from("two-factor authentication is already enabled")));
+  }
+
+  let secret = generate_secret();
+  let _ = sqlx::query!("UPDATE user SET totp_secret = ?2, totp_last_step = NULL WHERE id = ?1", id, secret)
+    .execute(&pool)
+    .await?;
+
+  let enrolment = Enrolment {
+    uri: provisioning_uri(&secret, &user.username),
+    secret,
+  };
+  Ok((StatusCode::CREATED, Json(enrolment)))
+}
+
+/// Enables two-factor authentication, the recovery codes are shown only in this response.
+pub async fn confirm(
+  Path(id): Path<i64>,
+  Json(payload): Json<CodeReq>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<RecoveryCodes>> {
+  user_action_authorization(id, auth_userid, "cannot enable two-factor authentication of another user")?;
+
+  let user = sqlx::query!("SELECT totp_secret, totp_enabled_at, totp_last_step FROM user WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  if user.totp_enabled_at.is_some() {
+    return Err(AppError::BadRequest(String::from("two-factor authentication is already enabled")));
+  }
+  let secret = match user.totp_secret {
+    Some(s) => s,
+    None => return Err(AppError::BadRequest(String::from("two-factor authentication has not been enrolled"))),
+  };
+  let now = Utc::now().timestamp();
+  let step = match verify_code(&secret, payload.code.trim(), now, user.totp_last_step) {
+    Some(step) => step,
+    None => return Err(AppError::BadRequest(String::from("invalid code"))),
+  };
+
+  let recovery_codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect();
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE user SET totp_enabled_at = ?2, totp_last_step = ?3 WHERE id = ?1", id, now, step)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM recovery_code WHERE user = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  for code in &recovery_codes {
+    let code_hash = hash_recovery_code(code);
+    let _ = sqlx::query!("INSERT INTO recovery_code ( user, code_hash ) VALUES ( ?1, ?2 )", id, code_hash)
+      .execute(&mut tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  Ok((StatusCode::OK, Json(RecoveryCodes { recovery_codes })))
+}
+
+pub async fn disable(
+  Path(id): Path<i64>,
+  Json(payload): Json<CodeReq>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(id, auth_userid, "cannot disable two-factor authentication of another user")?;
+
+  let user = sqlx::query!("SELECT totp_enabled_at FROM user WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  if user.totp_enabled_at.is_none() {
+    return Err(AppError::BadRequest(String::from("two-factor authentication is not enabled")));
+  }
+  if !verify_second_factor(&pool, id, &payload.code).await? {
+    return Err(AppError::BadRequest(String::from("invalid code")));
+  }
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("UPDATE user SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM recovery_code WHERE user = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+/// Exchanges the pending token for a jwt token. The pending token is used up even by a wrong code,
+/// so that every guess needs the password again and counts as a failed login.
+pub async fn authentificate(
+  connect_info: Option<ConnectInfo<SocketAddr>>,
+  headers: HeaderMap,
+  Json(payload): Json<TwoFactorReq>,
+  Extension(pool): Extension<DbState>,
+) -> AppReponse<(HeaderMap, Json<UserAuthRespData>)> {
+  let config = LoginConfig::from_env();
+  let ip = login_limit::client_ip(&headers, connect_info, &config);
+
+  let mut tx = pool.begin().await?;
+  let user = use_db_token(&mut tx, &payload.token, TokenKind::TwoFactor).await?;
+  tx.commit().await?;
+
+  let username = sqlx::query!("SELECT username FROM user WHERE id = ?1", user)
+    .fetch_one(&pool)
+    .await?
+    .username;
+  if !verify_second_factor(&pool, user, &payload.code).await? {
+    login_limit::record_failure(&pool, &config, &ip, &username, Some(user)).await?;
+    return Err(AppError::Unauthorized(String::from("invalid code")));
+  }
+  login_limit::record_success(&pool, &username, user).await?;
+
+  let resp = UserAuthRespData::new(user, &username);
+  Ok((StatusCode::OK, (resp.cookies(&CookieConfig::from_env()), Json(resp))))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::{json, Value};
+  use crate::utils::test::{setup_with_structure, setup_with_data, test_api};
+  use axum::{http, Router};
+
+  fn current_code(secret: &str, offset: i64) -> String {
+    let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret).unwrap();
+    code(&secret, Utc::now().timestamp() / PERIOD + offset)
+  }
+
+  async fn register(app: &Router) -> i64 {
+    let body_json = json!({
+      "username": "Janko Hrasko",
+      "password": "old password",
+    });
+    let response = test_api(app.clone(), "/register", http::Method::POST, Some(body_json), StatusCode::CREATED, None).await;
+    response.unwrap()["id"].as_i64().unwrap()
+  }
+
+  async fn login(app: &Router) -> Value {
+    let body_json = json!({
+      "username": "Janko Hrasko",
+      "password": "old password",
+    });
+    test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await.unwrap()
+  }
+
+  /// Registers a user with enabled two-factor authentication, returns the secret and recovery codes.
+  async fn setup_with_totp() -> (Router, DbState, String, Vec<String>) {
+    let (app, pool) = setup_with_structure().await;
+    let id = register(&app).await;
+    let auth = (id.to_string(), String::from("Janko Hrasko"));
+    let enrolment = test_api(app.clone(), "/user/1/totp", http::Method::POST, None, StatusCode::CREATED, Some((&auth.0, &auth.1))).await.unwrap();
+    let secret = enrolment["secret"].as_str().unwrap().to_owned();
+    let body_json = json!({
+      "code": current_code(&secret, 0),
+    });
+    let response = test_api(app.clone(), "/user/1/totp/confirm", http::Method::POST, Some(body_json), StatusCode::OK, Some((&auth.0, &auth.1))).await.unwrap();
+    let recovery_codes = response["recovery_codes"].as_array().unwrap().iter().map(|c| c.as_str().unwrap().to_owned()).collect();
+    (app, pool, secret, recovery_codes)
+  }
+
+  #[test]
+  fn rfc6238() {
+    // test vectors of RFC 6238 for SHA1, truncated to 6 digits
+    let secret = b"12345678901234567890";
+    assert_eq!(code(secret, 59 / PERIOD), "287082");
+    assert_eq!(code(secret, 1111111109 / PERIOD), "081804");
+    assert_eq!(code(secret, 2000000000 / PERIOD), "279037");
+
+    let encoded = base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret);
+    assert_eq!(verify_code(&encoded, "081804", 1111111109 + PERIOD, None), Some(1111111109 / PERIOD));
+    assert_eq!(verify_code(&encoded, "081804", 1111111109 + 2 * PERIOD, None), None);
+    assert_eq!(verify_code(&encoded, "081804", 1111111109, Some(1111111109 / PERIOD)), None);
+  }
+
+  #[test]
+  fn uri() {
+    assert_eq!(
+      provisioning_uri("JBSWY3DPEHPK3PXP", "Janko Hrasko"),
+      "otpauth://totp/zmtwc%3AJanko%20Hrasko?secret=JBSWY3DPEHPK3PXP&issuer=zmtwc&algorithm=SHA1&digits=6&period=30"
+    );
+  }
+
+  #[tokio::test]
+  async fn enrol_and_login() {
+    let (app, _, secret, recovery_codes) = setup_with_totp().await;
+    assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+
+    let response = login(&app).await;
+    assert!(response.get("token").is_none());
+    assert_eq!(response["expires_in"], PENDING_MINUTES * 60);
+    // the code used for the confirmation cannot be used again
+    let body_json = json!({
+      "token": response["two_factor_token"],
+      "code": current_code(&secret, 1),
+    });
+    let response = test_api(app.clone(), "/authentificate/2fa", http::Method::POST, Some(body_json.clone()), StatusCode::OK, None).await.unwrap();
+    assert_eq!(response["id"], 1);
+    assert!(response["token"].is_string());
+    let _ = test_api(app, "/authentificate/2fa", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+  }
+
+  #[tokio::test]
+  async fn recovery_code() {
+    let (app, pool, _, recovery_codes) = setup_with_totp().await;
+
+    let response = login(&app).await;
+    let body_json = json!({
+      "token": response["two_factor_token"],
+      "code": recovery_codes[0].to_uppercase(),
+    });
+    let _ = test_api(app.clone(), "/authentificate/2fa", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+
+    let response = login(&app).await;
+    let body_json = json!({
+      "token": response["two_factor_token"],
+      "code": recovery_codes[0],
+    });
+    let _ = test_api(app, "/authentificate/2fa", http::Method::POST, Some(body_json), StatusCode::UNAUTHORIZED, None).await;
+    let result = sqlx::query!("select failed_logins from user where id = 1")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(result.failed_logins, 1);
+  }
+
+  #[tokio::test]
+  async fn invalid_confirmation() {
+    let (app, pool) = setup_with_structure().await;
+    let _ = register(&app).await;
+    let body_json = json!({
+      "code": "123456",
+    });
+    let _ = test_api(app.clone(), "/user/1/totp/confirm", http::Method::POST, Some(body_json.clone()), StatusCode::BAD_REQUEST, Some(("1", "Janko Hrasko"))).await;
+    let _ = test_api(app.clone(), "/user/1/totp", http::Method::POST, None, StatusCode::CREATED, Some(("1", "Janko Hrasko"))).await;
+    let _ = test_api(app.clone(), "/user/1/totp/confirm", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "Janko Hrasko"))).await;
+
+    // not enabled until confirmed
+    let response = login(&app).await;
+    assert!(response["token"].is_string());
+    let result = sqlx::query!("select totp_enabled_at from user where id = 1")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert!(result.totp_enabled_at.is_none());
+  }
+
+  #[tokio::test]
+  async fn disable() {
+    let (app, pool, _, recovery_codes) = setup_with_totp().await;
+    let body_json = json!({
+      "code": "not-a-code",
+    });
+    let _ = test_api(app.clone(), "/user/1/totp", http::Method::DELETE, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "Janko Hrasko"))).await;
+    let body_json = json!({
+      "code": recovery_codes[1],
+    });
+    let _ = test_api(app.clone(), "/user/1/totp", http::Method::DELETE, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "Janko Hrasko"))).await;
+
+    let response = login(&app).await;
+    assert!(response["token"].is_string());
+    let result = sqlx::query!("select count(*) as count from recovery_code")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(result.count, 0);
+  }
+
+  #[tokio::test]
+  async fn for_another() {
+    let (app, _) = setup_with_data().await;
+
+    let _ = test_api(app, "/user/2/totp", http::Method::POST, None, StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+  }
+}
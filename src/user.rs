@@ -1,20 +1,62 @@
 use axum::{
-    Json, Extension, extract::Path,
+    Json, Extension, extract::{Path, Query, ConnectInfo},
+    http::HeaderMap,
 };
+use chrono::{Utc, Duration};
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use std::net::SocketAddr;
 
-use crate::{DbState, error::{self, AppError}, auth::{generate_salt, get_salted_password, generate_jwt, UserAuth, user_action_authorization}, db_modeling, utils::AppReponse};
+use crate::{DbState, error::AppError, auth::{generate_jwt, generate_token, hash_token, accept_prehashed_passwords, PasswordScheme, StoredPassword, UserAuth, user_action_authorization}, db_modeling, utils::AppReponse, mail::{self, MailQueue, Recipient}, credentials::{CredentialPolicy, username_available, unique_username}, login_limit::{self, LoginConfig, Failures}, totp::{self, TwoFactorPending}, session::CookieConfig};
+
+pub const VERIFICATION_HOURS: i64 = 48;
+pub const PASSWORD_RESET_HOURS: i64 = 1;
+
+#[derive(sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+pub enum TokenKind {
+  EmailVerification,
+  PasswordReset,
+  /// issued after the password was checked, exchanged for a jwt token with a second factor
+  TwoFactor,
+}
 
 #[derive(Deserialize)]
 pub struct CreateUser {
   username: String,
   password: String,
+  email: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct UpdateUser {
   username: Option<String>,
+  email: Option<String>,
+  email_notifications: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+  current_password: String,
+  password: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmail {
+  token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+  /// username or email address
+  login: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+  token: String,
+  password: String,
 }
 
 #[derive(Serialize)]
@@ -27,6 +69,8 @@ pub struct User {
 pub struct UserAuthReqData {
   username: String,
   password: String,
+  /// required after repeated failures
+  captcha: Option<String>,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -35,30 +79,80 @@ pub struct UserAuthRespData {
   token: String,
 }
 
+impl UserAuthRespData {
+  pub fn new(id: i64, username: &str) -> Self {
+    Self {
+      id,
+      token: generate_jwt(&format!("{}", id), username),
+    }
+  }
+
+  pub fn cookies(&self, config: &CookieConfig) -> HeaderMap {
+    config.login_cookies(&self.token)
+  }
+}
+
+/// Accounts with two-factor authentication get a pending token instead of the jwt token.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AuthentificateResp {
+  Authenticated(UserAuthRespData),
+  TwoFactorPending(TwoFactorPending),
+}
+
+impl AuthentificateResp {
+  /// Session cookies once the login is finished.
+  pub fn cookies(&self, config: &CookieConfig) -> HeaderMap {
+    match self {
+      AuthentificateResp::Authenticated(data) => data.cookies(config),
+      AuthentificateResp::TwoFactorPending(_) => HeaderMap::new(),
+    }
+  }
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct UsedRequirements {
   name: String,
   score: i64,
+  description: Option<String>,
+  size: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsedRequirementsParam {
+  prefix: Option<String>,
 }
 
 pub async fn create(
   Json(payload): Json<CreateUser>,
   Extension(pool): Extension<DbState>,
+  Extension(mail): Extension<MailQueue>,
 ) -> AppReponse<Json<User>> {
-  let salt = generate_salt();
-  let password = get_salted_password(&payload.password, &salt.clone());
+  let policy = CredentialPolicy::from_env();
+  policy.validate_username(&payload.username)?;
+  policy.validate_password(&payload.password)?;
+  if let Some(email) = &payload.email {
+    validate_email(email)?;
+  }
+  username_available(&pool, &payload.username, None).await?;
+  let password = StoredPassword::new(&payload.password, policy.accept_prehashed);
 
   let id = sqlx::query!(
       r#"
-  INSERT INTO user ( username, password, salt )
-  VALUES ( ?1, ?2, ?3 )
+  INSERT INTO user ( username, password, salt, password_scheme, email )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
       "#,
-      payload.username, password, salt
+      payload.username, password.hash, password.salt, password.scheme, payload.email
     )
     .execute(&pool)
-    .await?
+    .await
+    .map_err(unique_username)?
     .last_insert_rowid();
 
+  if payload.email.is_some() {
+    send_verification(&pool, &mail, id).await?;
+  }
+
   let user = User {
     id,
     username: payload.username,
@@ -92,19 +186,40 @@ pub async fn used_requirements(
   Path(id): Path<i64>,
   Extension(pool): Extension<DbState>,
   UserAuth(auth_userid): UserAuth,
+  Query(params): Query<UsedRequirementsParam>,
 ) -> AppReponse<Json<Vec<UsedRequirements>>> {
   user_action_authorization(id, auth_userid, "cannot get requirements of another user")?;
 
+  let prefix = params.prefix
+    .unwrap_or_default()
+    .replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_");
   let requirements = sqlx::query_as!(UsedRequirements,
       r#"
-  SELECT COUNT(requirement.name) AS score, requirement.name
+  SELECT COUNT(requirement.name) AS "score!: i64", requirement.name AS "name!: String",
+    (
+      SELECT last.description FROM requirement AS last
+      JOIN event AS last_event ON last.event = last_event.id
+      WHERE last_event.creator = ?1 AND last.name = requirement.name
+      ORDER BY last_event.time DESC, last.id DESC
+      LIMIT 1
+    ) AS "description?: String",
+    (
+      SELECT last.size FROM requirement AS last
+      JOIN event AS last_event ON last.event = last_event.id
+      WHERE last_event.creator = ?1 AND last.name = requirement.name
+      ORDER BY last_event.time DESC, last.id DESC
+      LIMIT 1
+    ) AS "size!: i64"
   FROM requirement
   JOIN event ON requirement.event = event.id
-  WHERE event.creator = ?1
+  WHERE event.creator = ?1 AND requirement.name LIKE ?2 || '%' ESCAPE '\'
   GROUP BY requirement.name
+  ORDER BY COUNT(requirement.name) DESC, requirement.name
   LIMIT 10
       "#,
-      id
+      id, prefix
     )
     .fetch_all(&pool)
     .await?;
@@ -127,25 +242,240 @@ pub async fn update(
   Path(id): Path<i64>,
   Json(payload): Json<UpdateUser>,
   Extension(pool): Extension<DbState>,
+  Extension(mail): Extension<MailQueue>,
   UserAuth(auth_userid): UserAuth,
 ) -> AppReponse<()> {
   user_action_authorization(id, auth_userid, "cannot update another user")?;
 
-  if let Some(username) = payload.username {
-    let _ = sqlx::query!(
+  if payload.username.is_none() && payload.email.is_none() && payload.email_notifications.is_none() {
+    return Err(AppError::BadRequest(String::from("at least one field must be filled out")));
+  }
+  if let Some(username) = &payload.username {
+    CredentialPolicy::from_env().validate_username(username)?;
+    username_available(&pool, username, Some(id)).await?;
+  }
+  if let Some(email) = &payload.email {
+    validate_email(email)?;
+  }
+
+  let current = sqlx::query!("SELECT email FROM user WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  let email_changed = payload.email.is_some() && payload.email != current.email;
+
+  let _ = sqlx::query!(
+    r#"
+  UPDATE user SET username = COALESCE(?1, username), email = COALESCE(?2, email), email_notifications = COALESCE(?3, email_notifications),
+    email_verified_at = CASE WHEN ?5 THEN NULL ELSE email_verified_at END
+  WHERE ID = ?4
+    "#,
+    payload.username, payload.email, payload.email_notifications, id, email_changed
+  )
+  .execute(&pool)
+  .await
+  .map_err(unique_username)?;
+
+  if email_changed {
+    send_verification(&pool, &mail, id).await?;
+  }
+
+  Ok(((StatusCode::NO_CONTENT), ()))
+}
+
+/// Other sessions of the user are signed out, the response carries a new token for the current one.
+pub async fn change_password(
+  Path(id): Path<i64>,
+  Json(payload): Json<ChangePassword>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<(HeaderMap, Json<UserAuthRespData>)> {
+  user_action_authorization(id, auth_userid, "cannot change password of another user")?;
+  let policy = CredentialPolicy::from_env();
+  policy.validate_password(&payload.password)?;
+
+  let user = sqlx::query!(
       r#"
-  UPDATE user SET username = ?1
-  WHERE ID = ?2
+  SELECT username, password, salt, password_scheme as "password_scheme: PasswordScheme" FROM user
+  WHERE id = ?1
       "#,
-      username, id
+      id
+    )
+    .fetch_one(&pool)
+    .await?;
+  let current = StoredPassword { scheme: user.password_scheme, hash: user.password, salt: user.salt };
+  if !current.verify(&payload.current_password, policy.accept_prehashed) {
+    return Err(AppError::Forbidden(String::from("incorrect current password")));
+  }
+
+  let password = StoredPassword::new(&payload.password, policy.accept_prehashed);
+  let revoked_at = Utc::now().timestamp_millis();
+  let _ = sqlx::query!(
+      r#"
+  UPDATE user SET password = ?2, salt = ?3, password_scheme = ?4, sessions_revoked_at = ?5
+  WHERE id = ?1
+      "#,
+      id, password.hash, password.salt, password.scheme, revoked_at
     )
     .execute(&pool)
     .await?;
 
-    Ok(((StatusCode::NO_CONTENT), ()))
-  } else {
-    Err(AppError::BadRequest(String::from("at least one field must be filled out")))
+  let resp = UserAuthRespData::new(id, &user.username);
+  Ok((StatusCode::OK, (resp.cookies(&CookieConfig::from_env()), Json(resp))))
+}
+
+/// Creates a new token of the kind for the user, earlier unused tokens of the same kind stop being valid.
+pub async fn insert_db_token(conn: &mut SqliteConnection, user: i64, kind: TokenKind, valid_for: Duration) -> Result<String, AppError> {
+  let now = Utc::now();
+  let created_at = now.timestamp();
+  let expires_at = (now + valid_for).timestamp();
+  let token = generate_token();
+  let token_hash = hash_token(&token);
+
+  let _ = sqlx::query!("UPDATE user_token SET used_at = ?3 WHERE user = ?1 AND kind = ?2 AND used_at IS NULL", user, kind, created_at)
+    .execute(&mut *conn)
+    .await?;
+  let _ = sqlx::query!(
+      r#"
+  INSERT INTO user_token ( user, kind, token_hash, created_at, expires_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
+      "#,
+      user, kind, token_hash, created_at, expires_at
+    )
+    .execute(&mut *conn)
+    .await?;
+
+  Ok(token)
+}
+
+/// Marks the token as used and returns its user, if it is valid.
+pub async fn use_db_token(conn: &mut SqliteConnection, token: &str, kind: TokenKind) -> Result<i64, AppError> {
+  let now = Utc::now().timestamp();
+  let token_hash = hash_token(token);
+  let user_token = sqlx::query!(
+      r#"
+  SELECT id, user FROM user_token
+  WHERE token_hash = ?1 AND kind = ?2 AND used_at IS NULL AND expires_at > ?3
+      "#,
+      token_hash, kind, now
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+  let user_token = match user_token {
+    Some(t) => t,
+    None => return Err(AppError::BadRequest(String::from("invalid or expired token"))),
+  };
+
+  let _ = sqlx::query!("UPDATE user_token SET used_at = ?2 WHERE id = ?1", user_token.id, now)
+    .execute(&mut *conn)
+    .await?;
+  Ok(user_token.user)
+}
+
+async fn send_verification(pool: &DbState, mail: &MailQueue, user: i64) -> Result<(), AppError> {
+  let recipient = sqlx::query_as!(Recipient, r#"SELECT username, email as "email!" FROM user WHERE id = ?1 AND email IS NOT NULL"#, user)
+    .fetch_one(pool)
+    .await?;
+  let token = insert_db_token(&mut *pool.acquire().await?, user, TokenKind::EmailVerification, Duration::hours(VERIFICATION_HOURS)).await?;
+  let link = format!("{}/verify?token={token}", mail::app_url());
+  mail.enqueue(mail::templates::verification(&recipient, &link, VERIFICATION_HOURS));
+  Ok(())
+}
+
+pub async fn verify_email(
+  Json(payload): Json<VerifyEmail>,
+  Extension(pool): Extension<DbState>,
+) -> AppReponse<()> {
+  let mut tx = pool.begin().await?;
+  let user = use_db_token(&mut tx, &payload.token, TokenKind::EmailVerification).await?;
+  let verified_at = Utc::now().timestamp();
+  let _ = sqlx::query!("UPDATE user SET email_verified_at = ?2 WHERE id = ?1", user, verified_at)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn resend_verification(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  Extension(mail): Extension<MailQueue>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  user_action_authorization(id, auth_userid, "cannot verify email of another user")?;
+
+  let user = sqlx::query!("SELECT email, email_verified_at FROM user WHERE id = ?1", id)
+    .fetch_one(&pool)
+    .await?;
+  if user.email.is_none() {
+    return Err(AppError::BadRequest(String::from("user has no email address")));
   }
+  if user.email_verified_at.is_some() {
+    return Err(AppError::BadRequest(String::from("email address is already verified")));
+  }
+  send_verification(&pool, &mail, id).await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+/// Always succeeds, so that it doesn't reveal which accounts exist.
+pub async fn forgot_password(
+  Json(payload): Json<ForgotPassword>,
+  Extension(pool): Extension<DbState>,
+  Extension(mail): Extension<MailQueue>,
+) -> AppReponse<()> {
+  // emails are not unique, every account with the address gets its own reset link
+  let users = sqlx::query!(
+      r#"
+  SELECT id, username, email as "email!" FROM user
+  WHERE (username = ?1 OR email = ?1) AND email IS NOT NULL
+      "#,
+      payload.login
+    )
+    .fetch_all(&pool)
+    .await?;
+
+  for user in users {
+    let token = insert_db_token(&mut *pool.acquire().await?, user.id, TokenKind::PasswordReset, Duration::hours(PASSWORD_RESET_HOURS)).await?;
+    let link = format!("{}/reset-password?token={token}", mail::app_url());
+    let recipient = Recipient { username: user.username, email: user.email };
+    mail.enqueue(mail::templates::password_reset(&recipient, &link, PASSWORD_RESET_HOURS));
+  }
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn reset_password(
+  Json(payload): Json<ResetPassword>,
+  Extension(pool): Extension<DbState>,
+) -> AppReponse<()> {
+  let policy = CredentialPolicy::from_env();
+  policy.validate_password(&payload.password)?;
+  let mut tx = pool.begin().await?;
+  let user = use_db_token(&mut tx, &payload.token, TokenKind::PasswordReset).await?;
+  let password = StoredPassword::new(&payload.password, policy.accept_prehashed);
+  // jwt tokens issued before the reset are rejected from now on
+  let revoked_at = Utc::now().timestamp_millis();
+  let _ = sqlx::query!(
+      r#"
+  UPDATE user SET password = ?2, salt = ?3, password_scheme = ?4, sessions_revoked_at = ?5,
+    failed_logins = 0, last_failed_login_at = NULL
+  WHERE id = ?1
+      "#,
+      user, password.hash, password.salt, password.scheme, revoked_at
+    )
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+fn validate_email(email: &str) -> Result<(), AppError> {
+  email
+    .parse::<lettre::Address>()
+    .map(|_| ())
+    .map_err(|_| AppError::BadRequest(format!("invalid email address: {email}")))
 }
 
 pub async fn delete(
@@ -157,38 +487,73 @@ pub async fn delete(
 
   db_modeling::delete_db_user(&pool, id)
     .await
-    .and_then(|r| Ok(((StatusCode::NO_CONTENT), r)))
+    .map(|r| ((StatusCode::NO_CONTENT), r))
 }
 
+/// Unknown usernames and wrong passwords get the same error and are throttled alike.
 pub async fn authentificate(
+  connect_info: Option<ConnectInfo<SocketAddr>>,
+  headers: HeaderMap,
   Json(data): Json<UserAuthReqData>,
   Extension(pool): Extension<DbState>,
-) -> AppReponse<Json<UserAuthRespData>> {
+) -> AppReponse<(HeaderMap, Json<AuthentificateResp>)> {
+  let config = LoginConfig::from_env();
+  let ip = login_limit::client_ip(&headers, connect_info, &config);
   let user_db = sqlx::query!(
-      "
-      SELECT id, password, salt
+      r#"
+      SELECT id as "id!", username, password, salt, password_scheme as "password_scheme: PasswordScheme",
+        failed_logins, last_failed_login_at, totp_enabled_at
       FROM user
       WHERE username = ?
-      ",
+      "#,
       data.username
     )
-    .fetch_one(&pool)
+    .fetch_optional(&pool)
     .await?;
 
-  if user_db.id.is_none() {
-    return Err(AppError::NotFound(format!("User doesn't exist")))
+  let failures = match &user_db {
+    Some(u) => Failures { count: u.failed_logins, last_at: u.last_failed_login_at },
+    None => login_limit::unknown_user_failures(&pool, &config, &data.username).await?,
+  };
+  login_limit::authorize_attempt(&pool, &config, &ip, &data.username, failures, data.captcha.as_deref()).await?;
+
+  let accept_prehashed = accept_prehashed_passwords();
+  let user_db = match user_db {
+    Some(u) => u,
+    None => {
+      login_limit::record_failure(&pool, &config, &ip, &data.username, None).await?;
+      return Err(invalid_credentials());
+    },
+  };
+  let user_id = user_db.id;
+  let stored = StoredPassword { scheme: user_db.password_scheme, hash: user_db.password, salt: user_db.salt };
+  if !stored.verify(&data.password, accept_prehashed) {
+    login_limit::record_failure(&pool, &config, &ip, &data.username, Some(user_id)).await?;
+    return Err(invalid_credentials());
   }
-  let user_id = user_db.id.unwrap();
-  let calculated_password = get_salted_password(&data.password, &user_db.salt);
-  if calculated_password != user_db.password {
-    return Err(error::AppError::Unauthorized(String::from("incorrect password")));
+  if stored.needs_upgrade(accept_prehashed) {
+    let password = StoredPassword::new(&data.password, false);
+    let _ = sqlx::query!(
+        "UPDATE user SET password = ?2, salt = ?3, password_scheme = ?4 WHERE id = ?1",
+        user_id, password.hash, password.salt, password.scheme
+      )
+      .execute(&pool)
+      .await?;
   }
 
-  let resp = UserAuthRespData {
-    id: user_id,
-    token: generate_jwt(&format!("{}", user_id), &data.username)
-  };
-  Ok((StatusCode::OK, Json(resp)))
+  // failures are reset only once the second factor is checked as well
+  if user_db.totp_enabled_at.is_some() {
+    let pending = totp::pending_login(&pool, user_id).await?;
+    return Ok((StatusCode::OK, (HeaderMap::new(), Json(AuthentificateResp::TwoFactorPending(pending)))));
+  }
+  login_limit::record_success(&pool, &data.username, user_id).await?;
+
+  let resp = UserAuthRespData::new(user_id, &user_db.username);
+  Ok((StatusCode::OK, (resp.cookies(&CookieConfig::from_env()), Json(AuthentificateResp::Authenticated(resp)))))
+}
+
+fn invalid_credentials() -> AppError {
+  AppError::Unauthorized(String::from("invalid username or password"))
 }
 
 
@@ -196,7 +561,7 @@ pub async fn authentificate(
 mod test {
   use super::*;
   use serde_json::json;
-  use crate::utils::test::{setup_with_structure, test_api, setup_with_data};
+  use crate::{auth::prehash_password, utils::test::{setup_with_structure, test_api, setup_with_data}};
   use axum::http;
 
   mod create {
@@ -207,7 +572,7 @@ mod test {
       let (app, _) = setup_with_structure().await;
       let body_json = json!({
         "username": "Janko Hrasko",
-        "password": "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+        "password": "c4bbcb1fbec99d65bf59d85c8cb62ee2db963f0fe106f483d9afa73bd4e39a8a"
       });
       let expected_response = json!({
         "id": 1,
@@ -217,6 +582,38 @@ mod test {
       let response = test_api(app, "/register", http::Method::POST, Some(body_json), StatusCode::CREATED, None).await;
       assert_eq!(response, Some(expected_response));
     }
+
+    #[tokio::test]
+    async fn breached_password() {
+      let (app, _) = setup_with_structure().await;
+      // digest of "password"
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+      });
+      let _ = test_api(app, "/register", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_username() {
+      let (app, _) = setup_with_structure().await;
+      let body_json = json!({
+        "username": "<b>Janko</b>",
+        "password": "c4bbcb1fbec99d65bf59d85c8cb62ee2db963f0fe106f483d9afa73bd4e39a8a"
+      });
+      let _ = test_api(app, "/register", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+    }
+
+    #[tokio::test]
+    async fn taken_username() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "username": "UserName1",
+        "password": "c4bbcb1fbec99d65bf59d85c8cb62ee2db963f0fe106f483d9afa73bd4e39a8a"
+      });
+      let response = test_api(app, "/register", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+      assert_eq!(response, Some(json!({ "error": "username is already taken" })));
+    }
   }
 
   mod get {
@@ -271,10 +668,14 @@ mod test {
           {
             "name": "req1",
             "score": 2,
+            "description": "req4-desc",
+            "size": 3,
           },
           {
             "name": "req2",
             "score": 1,
+            "description": "req2-desc",
+            "size": 1,
           }
         ]);
 
@@ -282,6 +683,26 @@ mod test {
         assert_eq!(response, Some(expected_response));
       }
 
+      #[tokio::test]
+      async fn prefix() {
+        let (app, pool) = setup_with_data().await;
+        sqlx::query(r#"INSERT INTO requirement (name, description, event, size) VALUES ("tent", "big one", 4, 1)"#)
+          .execute(&pool)
+          .await
+          .unwrap();
+        let expected_response = json!([
+          {
+            "name": "tent",
+            "score": 1,
+            "description": "big one",
+            "size": 1,
+          }
+        ]);
+
+        let response = test_api(app, "/user/1/requirements?prefix=TE", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await;
+        assert_eq!(response, Some(expected_response));
+      }
+
       #[tokio::test]
       async fn for_another() {
         let (app, _) = setup_with_data().await;
@@ -309,6 +730,48 @@ mod test {
       assert_eq!(result.username, "edited_username");
     }
 
+    #[tokio::test]
+    async fn email() {
+      let (app, pool) = setup_with_data().await;
+      let body_json = json!({
+        "email": "user1@example.com",
+        "email_notifications": false,
+      });
+      let _ = test_api(app, "/user/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let result = sqlx::query!("select * from user where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+      assert_eq!(result.username, "username1");
+      assert_eq!(result.email, Some("user1@example.com".to_owned()));
+      assert!(!result.email_notifications);
+    }
+
+    #[tokio::test]
+    async fn invalid_email() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "email": "not an address",
+      });
+      let _ = test_api(app, "/user/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn taken_username() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "username": "USERNAME2",
+      });
+      let _ = test_api(app.clone(), "/user/1", http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+      // changing the case of own username is fine
+      let body_json = json!({
+        "username": "UserName1",
+      });
+      let _ = test_api(app, "/user/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+    }
+
     #[tokio::test]
     async fn for_another() {
       let (app, _) = setup_with_data().await;
@@ -319,6 +782,154 @@ mod test {
     }
   }
 
+  mod password {
+    use super::*;
+
+    async fn register(app: axum::Router) -> i64 {
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "old password",
+      });
+      let response = test_api(app, "/register", http::Method::POST, Some(body_json), StatusCode::CREATED, None).await;
+      response.unwrap()["id"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn change() {
+      let (app, pool) = setup_with_structure().await;
+      let id = register(app.clone()).await;
+      let body_json = json!({
+        "current_password": "old password",
+        "password": "new password",
+      });
+      let response = test_api(app.clone(), &format!("/user/{id}/password"), http::Method::PUT, Some(body_json), StatusCode::OK, Some((&id.to_string(), "Janko Hrasko"))).await;
+      assert!(response.unwrap()["token"].is_string());
+
+      let result = sqlx::query!("select sessions_revoked_at from user where id = ?", id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(result.sessions_revoked_at.is_some());
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "new password",
+      });
+      let _ = test_api(app, "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+    }
+
+    #[tokio::test]
+    async fn raw() {
+      let (app, _) = setup_with_structure().await;
+      let _ = register(app.clone()).await;
+      let body_json = json!({
+        "username": "janko hrasko",
+        "password": "old password",
+      });
+      let response = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+      assert_eq!(response.unwrap()["id"], 1);
+      // digests are accepted only from accounts registered by older clients
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": prehash_password("old password"),
+      });
+      let _ = test_api(app, "/authentificate", http::Method::POST, Some(body_json), StatusCode::UNAUTHORIZED, None).await;
+    }
+
+    #[tokio::test]
+    async fn prehashed() {
+      let (app, pool) = setup_with_structure().await;
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": prehash_password("old password"),
+      });
+      let _ = test_api(app.clone(), "/register", http::Method::POST, Some(body_json.clone()), StatusCode::CREATED, None).await;
+
+      let result = sqlx::query!(r#"select password_scheme as "password_scheme: PasswordScheme" from user where id = 1"#)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.password_scheme, PasswordScheme::ClientSha256);
+      let _ = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "old password",
+      });
+      let _ = test_api(app, "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+    }
+
+    #[tokio::test]
+    async fn bad_credentials() {
+      let (app, pool) = setup_with_structure().await;
+      let _ = register(app.clone()).await;
+      let expected_response = json!({ "error": "invalid username or password" });
+
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "wrong password",
+      });
+      let response = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json.clone()), StatusCode::UNAUTHORIZED, None).await;
+      assert_eq!(response, Some(expected_response.clone()));
+      let body_json = json!({
+        "username": "Jozko Mrkvicka",
+        "password": "wrong password",
+      });
+      let response = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json.clone()), StatusCode::UNAUTHORIZED, None).await;
+      assert_eq!(response, Some(expected_response));
+
+      // both are delayed after the failure
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "old password",
+      });
+      let _ = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json.clone()), StatusCode::TOO_MANY_REQUESTS, None).await;
+      let body_json = json!({
+        "username": "Jozko Mrkvicka",
+        "password": "wrong password",
+      });
+      let _ = test_api(app.clone(), "/authentificate", http::Method::POST, Some(body_json), StatusCode::TOO_MANY_REQUESTS, None).await;
+
+      let result = sqlx::query!("select failed_logins from user where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.failed_logins, 1);
+    }
+
+    #[tokio::test]
+    async fn incorrect_current() {
+      let (app, _) = setup_with_structure().await;
+      let id = register(app.clone()).await;
+      let body_json = json!({
+        "current_password": "wrong password",
+        "password": "new password",
+      });
+      let _ = test_api(app, &format!("/user/{id}/password"), http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some((&id.to_string(), "Janko Hrasko"))).await;
+    }
+
+    #[tokio::test]
+    async fn weak() {
+      let (app, _) = setup_with_structure().await;
+      let id = register(app.clone()).await;
+      for password in ["short", "qwerty123"] {
+        let body_json = json!({
+          "current_password": "old password",
+          "password": password,
+        });
+        let _ = test_api(app.clone(), &format!("/user/{id}/password"), http::Method::PUT, Some(body_json), StatusCode::BAD_REQUEST, Some((&id.to_string(), "Janko Hrasko"))).await;
+      }
+    }
+
+    #[tokio::test]
+    async fn for_another() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "current_password": "old password",
+        "password": "new password",
+      });
+      let _ = test_api(app, "/user/2/password", http::Method::PUT, Some(body_json), StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
+    }
+  }
+
   mod delete {
     use super::*;
 
@@ -366,4 +977,133 @@ mod test {
       let _ = test_api(app, "/user/2", http::Method::DELETE, None, StatusCode::FORBIDDEN, Some(("1", "username1"))).await;
     }
   }
-}
\ No newline at end of file
+
+  mod verification {
+    use super::*;
+    use crate::mail::test::{setup_with_mailer, sent, MemoryMailer};
+
+    async fn token_from_email(mailer: &MemoryMailer, count: usize) -> String {
+      let emails = sent(mailer, count).await;
+      let text = &emails.last().unwrap().text;
+      let start = text.find("token=").unwrap() + "token=".len();
+      text[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn register() {
+      let (app, pool, mailer) = setup_with_mailer().await;
+      let body_json = json!({
+        "username": "Janko Hrasko",
+        "password": "c4bbcb1fbec99d65bf59d85c8cb62ee2db963f0fe106f483d9afa73bd4e39a8a",
+        "email": "janko@example.com"
+      });
+      let _ = test_api(app.clone(), "/register", http::Method::POST, Some(body_json), StatusCode::CREATED, None).await;
+      let token = token_from_email(&mailer, 1).await;
+      assert_eq!(sent(&mailer, 1).await[0].to, "janko@example.com");
+
+      let _ = test_api(app.clone(), "/verify-email", http::Method::POST, Some(json!({ "token": token })), StatusCode::NO_CONTENT, None).await;
+      let result = sqlx::query!("select email_verified_at, (select token_hash from user_token) as token_hash from user where username = 'Janko Hrasko'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(result.email_verified_at.is_some());
+      assert_ne!(result.token_hash, Some(token.clone()));
+
+      let _ = test_api(app, "/verify-email", http::Method::POST, Some(json!({ "token": token })), StatusCode::BAD_REQUEST, None).await;
+    }
+
+    #[tokio::test]
+    async fn changed_email() {
+      let (app, pool, mailer) = setup_with_mailer().await;
+      sqlx::query("UPDATE user SET email_verified_at = 1664841600 WHERE id = 2")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "email": "new@example.com",
+      });
+
+      let _ = test_api(app, "/user/2", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+
+      let result = sqlx::query!("select email_verified_at from user where id = 2")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(result.email_verified_at.is_none());
+      assert_eq!(sent(&mailer, 1).await[0].to, "new@example.com");
+    }
+
+    #[tokio::test]
+    async fn resend_without_email() {
+      let (app, _, _) = setup_with_mailer().await;
+
+      let _ = test_api(app, "/user/1/verification", http::Method::POST, None, StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn reset_password() {
+      let (app, pool, mailer) = setup_with_mailer().await;
+      let _ = test_api(app.clone(), "/password/forgot", http::Method::POST, Some(json!({ "login": "user2@example.com" })), StatusCode::NO_CONTENT, None).await;
+      let token = token_from_email(&mailer, 1).await;
+
+      let body_json = json!({
+        "token": token,
+        "password": "new-password-hash",
+      });
+      let _ = test_api(app.clone(), "/password/reset", http::Method::POST, Some(body_json.clone()), StatusCode::NO_CONTENT, None).await;
+      let _ = test_api(app.clone(), "/password/reset", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+
+      let result = sqlx::query!("select sessions_revoked_at from user where id = 2")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(result.sessions_revoked_at.is_some());
+      let body_json = json!({
+        "username": "username2",
+        "password": "new-password-hash",
+      });
+      let _ = test_api(app, "/authentificate", http::Method::POST, Some(body_json), StatusCode::OK, None).await;
+    }
+
+    #[tokio::test]
+    async fn reset_password_with_newer_token() {
+      let (app, _, mailer) = setup_with_mailer().await;
+      let _ = test_api(app.clone(), "/password/forgot", http::Method::POST, Some(json!({ "login": "username2" })), StatusCode::NO_CONTENT, None).await;
+      let first = token_from_email(&mailer, 1).await;
+      let _ = test_api(app.clone(), "/password/forgot", http::Method::POST, Some(json!({ "login": "username2" })), StatusCode::NO_CONTENT, None).await;
+      let _ = token_from_email(&mailer, 2).await;
+
+      let body_json = json!({
+        "token": first,
+        "password": "new-password-hash",
+      });
+      let _ = test_api(app, "/password/reset", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, None).await;
+    }
+
+    #[tokio::test]
+    async fn forgot_shared_email() {
+      let (app, pool, mailer) = setup_with_mailer().await;
+      sqlx::query("UPDATE user SET email = 'user2@example.com' WHERE id = 3")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let _ = test_api(app, "/password/forgot", http::Method::POST, Some(json!({ "login": "user2@example.com" })), StatusCode::NO_CONTENT, None).await;
+      let emails = sent(&mailer, 2).await;
+      assert_eq!(emails.len(), 2);
+      let tokens = sqlx::query!("select user from user_token order by user")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(tokens.iter().map(|t| t.user).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn forgot_unknown() {
+      let (app, _, mailer) = setup_with_mailer().await;
+
+      let _ = test_api(app, "/password/forgot", http::Method::POST, Some(json!({ "login": "nobody" })), StatusCode::NO_CONTENT, None).await;
+      assert!(sent(&mailer, 1).await.is_empty());
+    }
+  }
+}
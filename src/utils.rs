@@ -33,7 +33,7 @@ pub async fn shutdown_signal() {
 
 #[cfg(test)]
 pub mod test {
-  use crate::{auth::generate_jwt, app};
+  use crate::{auth::generate_jwt, app, notification::LogNotifier, mail::{MailQueue, test::MemoryMailer}, webhook::{WebhookQueue, WebhookConfig}, live::LiveUpdates};
   use axum::{
     http::Method,
     body::Body,
@@ -41,12 +41,12 @@ pub mod test {
   };
   use serde_json::{Value};
   use tower::ServiceExt; // for `app.oneshot()`
-  use std::{env};
+  use std::{env, sync::Arc};
   use sqlx::{SqlitePool};
 
 
   pub async fn test_api(app: Router, uri: &str, method: Method, body: Option<Value>, expected_status: StatusCode, auth: Option<(&str, &str)>) -> Option<Value> {
-    let body = body.and_then(|b| Some(Body::from(serde_json::to_vec(&b).unwrap()))).unwrap_or(Body::empty());
+    let body = body.map(|b| Body::from(serde_json::to_vec(&b).unwrap())).unwrap_or(Body::empty());
     let mut req = Request::builder()
       .method(method)
       .uri(uri)
@@ -80,7 +80,9 @@ pub mod test {
     env::set_var("DATABASE_URL", "sqlite::memory:");
     env::set_var("JWT_SECRET", "test-jwt-secret");
     let pool = SqlitePool::connect(&env::var("DATABASE_URL").unwrap()).await.unwrap();
-    (app(pool.clone()).await, pool)
+    let mail = MailQueue::start(Arc::new(MemoryMailer::default()));
+    let webhooks = WebhookQueue::start(pool.clone(), WebhookConfig::default()).await;
+    (app(pool.clone(), Arc::new(LogNotifier), mail, webhooks, LiveUpdates::new(pool.clone())).await, pool)
   }
 
   pub async fn setup_with_structure() -> (Router, SqlitePool) {
@@ -0,0 +1,1102 @@
+use std::{env, time::Duration, net::IpAddr};
+
+use axum::{
+  Json, Extension, extract::{Path, Query},
+};
+use chrono::Utc;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::SqliteConnection;
+use tokio::sync::mpsc;
+
+use crate::{DbState, error::AppError, utils::AppReponse, auth::{UserAuth, user_action_authorization, event_action_authorization, verified_authorization, generate_token, Restriction}};
+
+pub const DEFAULT_MAX_ATTEMPTS: i64 = 5;
+pub const DEFAULT_RETRY_BASE_MS: u64 = 1000;
+pub const DEFAULT_DISABLE_AFTER: i64 = 5;
+const TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WebhookEventType {
+  #[serde(rename = "event.updated")]
+  #[sqlx(rename = "event.updated")]
+  EventUpdated,
+  #[serde(rename = "event.deleted")]
+  #[sqlx(rename = "event.deleted")]
+  EventDeleted,
+  #[serde(rename = "participant.joined")]
+  #[sqlx(rename = "participant.joined")]
+  ParticipantJoined,
+  #[serde(rename = "participant.left")]
+  #[sqlx(rename = "participant.left")]
+  ParticipantLeft,
+  #[serde(rename = "requirement.created")]
+  #[sqlx(rename = "requirement.created")]
+  RequirementCreated,
+  #[serde(rename = "requirement.updated")]
+  #[sqlx(rename = "requirement.updated")]
+  RequirementUpdated,
+  #[serde(rename = "requirement.deleted")]
+  #[sqlx(rename = "requirement.deleted")]
+  RequirementDeleted,
+  #[serde(rename = "fulfillment.created")]
+  #[sqlx(rename = "fulfillment.created")]
+  FulfillmentCreated,
+  #[serde(rename = "fulfillment.updated")]
+  #[sqlx(rename = "fulfillment.updated")]
+  FulfillmentUpdated,
+  #[serde(rename = "fulfillment.deleted")]
+  #[sqlx(rename = "fulfillment.deleted")]
+  FulfillmentDeleted,
+}
+
+impl WebhookEventType {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      WebhookEventType::EventUpdated => "event.updated",
+      WebhookEventType::EventDeleted => "event.deleted",
+      WebhookEventType::ParticipantJoined => "participant.joined",
+      WebhookEventType::ParticipantLeft => "participant.left",
+      WebhookEventType::RequirementCreated => "requirement.created",
+      WebhookEventType::RequirementUpdated => "requirement.updated",
+      WebhookEventType::RequirementDeleted => "requirement.deleted",
+      WebhookEventType::FulfillmentCreated => "fulfillment.created",
+      WebhookEventType::FulfillmentUpdated => "fulfillment.updated",
+      WebhookEventType::FulfillmentDeleted => "fulfillment.deleted",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+  Pending,
+  Delivered,
+  Failed,
+}
+
+#[derive(Serialize)]
+pub struct Webhook {
+  id: i64,
+  user: i64,
+  /// `None` for webhooks receiving changes of all events of the user
+  event: Option<i64>,
+  url: String,
+  events: Vec<WebhookEventType>,
+  enabled: bool,
+  failures: i64,
+  created_at: i64,
+  /// only returned when the webhook is created
+  #[serde(skip_serializing_if = "Option::is_none")]
+  secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Delivery {
+  id: i64,
+  kind: WebhookEventType,
+  payload: Value,
+  status: DeliveryStatus,
+  attempts: i64,
+  status_code: Option<i64>,
+  error: Option<String>,
+  created_at: i64,
+  delivered_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhook {
+  url: String,
+  event: Option<i64>,
+  events: Vec<WebhookEventType>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateWebhook {
+  url: Option<String>,
+  events: Option<Vec<WebhookEventType>>,
+  enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct DeliveryParam {
+  page: Option<u32>,
+  pageSize: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+  pub max_attempts: i64,
+  /// delay before the first retry, doubled for every further one
+  pub retry_base: Duration,
+  /// number of consecutive failed deliveries after which the webhook is disabled
+  pub disable_after: i64,
+  /// allows urls of loopback, link-local and private hosts, e.g. for webhooks within the same network
+  pub allow_private: bool,
+}
+
+impl Default for WebhookConfig {
+  fn default() -> Self {
+    Self {
+      max_attempts: DEFAULT_MAX_ATTEMPTS,
+      retry_base: Duration::from_millis(DEFAULT_RETRY_BASE_MS),
+      disable_after: DEFAULT_DISABLE_AFTER,
+      allow_private: false,
+    }
+  }
+}
+
+impl WebhookConfig {
+  pub fn from_env() -> Self {
+    let default = Self::default();
+    Self {
+      max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS").ok().and_then(|a| a.parse().ok()).unwrap_or(default.max_attempts),
+      retry_base: env::var("WEBHOOK_RETRY_BASE_MS").ok().and_then(|b| b.parse().ok()).map(Duration::from_millis).unwrap_or(default.retry_base),
+      disable_after: env::var("WEBHOOK_DISABLE_AFTER").ok().and_then(|d| d.parse().ok()).unwrap_or(default.disable_after),
+      allow_private: env::var("WEBHOOK_ALLOW_PRIVATE").map(|a| a == "true" || a == "1").unwrap_or(default.allow_private),
+    }
+  }
+}
+
+/// Stores deliveries for subscribed webhooks and sends them from a background task.
+#[derive(Clone)]
+pub struct WebhookQueue {
+  pool: DbState,
+  sender: mpsc::UnboundedSender<i64>,
+  allow_private: bool,
+}
+
+impl WebhookQueue {
+  /// Also resumes the deliveries which were still pending when the server stopped.
+  pub async fn start(pool: DbState, config: WebhookConfig) -> Self {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<i64>();
+    let worker_pool = pool.clone();
+    let allow_private = config.allow_private;
+    tokio::spawn(async move {
+      // redirects could lead to hosts which weren't checked
+      let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("http client to be built");
+      while let Some(delivery) = receiver.recv().await {
+        tokio::spawn(deliver(worker_pool.clone(), client.clone(), config.clone(), delivery));
+      }
+    });
+    let queue = Self { pool, sender, allow_private };
+
+    let pending = sqlx::query!("SELECT id FROM webhook_delivery WHERE status = ?1 ORDER BY id", DeliveryStatus::Pending)
+      .fetch_all(&queue.pool)
+      .await;
+    match pending {
+      Ok(pending) => queue.enqueue_all(pending.into_iter().map(|d| d.id).collect()),
+      Err(e) => tracing::error!("cannot resume pending webhook deliveries: {e}"),
+    }
+    queue
+  }
+
+  /// Queues a delivery for every enabled webhook of the event, or of its organizer and participants, subscribed to `kind`.
+  pub async fn dispatch(&self, event: i64, kind: WebhookEventType, data: Value) {
+    let deliveries = async {
+      let mut tx = self.pool.begin().await?;
+      let deliveries = insert_db_deliveries(&mut tx, event, kind, data).await?;
+      tx.commit().await?;
+      Ok::<_, AppError>(deliveries)
+    };
+    match deliveries.await {
+      Ok(deliveries) => self.enqueue_all(deliveries),
+      Err(e) => tracing::error!("cannot dispatch webhooks of event {event}: {e}"),
+    }
+  }
+
+  /// Sends deliveries stored by [`insert_db_deliveries`] once their transaction is committed.
+  pub fn enqueue_all(&self, deliveries: Vec<i64>) {
+    deliveries.into_iter().for_each(|d| self.enqueue(d));
+  }
+
+  fn enqueue(&self, delivery: i64) {
+    if self.sender.send(delivery).is_err() {
+      tracing::error!("webhook queue is closed");
+    }
+  }
+}
+
+/// Deliveries for the subscribed webhooks, for changes which have to be stored in the same transaction,
+/// e.g. the deletion of an event which removes its participants.
+pub async fn insert_db_deliveries(conn: &mut SqliteConnection, event: i64, kind: WebhookEventType, data: Value) -> Result<Vec<i64>, AppError> {
+  let created_at = Utc::now().timestamp();
+  let payload = json!({
+    "type": kind,
+    "event": event,
+    "created_at": created_at,
+    "data": data,
+  }).to_string();
+
+  let webhooks = sqlx::query!(
+      r#"
+  SELECT webhook.id FROM webhook
+  JOIN webhook_subscription ON webhook_subscription.webhook = webhook.id
+  WHERE webhook.enabled AND webhook_subscription.kind = ?2 AND (
+    webhook.event = ?1 OR (webhook.event IS NULL AND (
+      webhook.user = (SELECT creator FROM event WHERE id = ?1)
+      OR webhook.user IN (SELECT user FROM participant WHERE event = ?1)
+    ))
+  )
+  ORDER BY webhook.id
+      "#,
+      event, kind
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+  let mut deliveries = vec![];
+  for webhook in webhooks {
+    let id = sqlx::query!(
+        r#"
+  INSERT INTO webhook_delivery ( webhook, kind, payload, status, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
+        "#,
+        webhook.id, kind, payload, DeliveryStatus::Pending, created_at
+      )
+      .execute(&mut *conn)
+      .await?
+      .last_insert_rowid();
+    deliveries.push(id);
+  }
+
+  Ok(deliveries)
+}
+
+async fn deliver(pool: DbState, client: reqwest::Client, config: WebhookConfig, id: i64) {
+  if let Err(e) = try_deliver(&pool, &client, &config, id).await {
+    tracing::error!("webhook delivery {id} failed: {e}");
+  }
+}
+
+async fn try_deliver(pool: &DbState, client: &reqwest::Client, config: &WebhookConfig, id: i64) -> Result<(), AppError> {
+  let delivery = sqlx::query!(
+      r#"
+  SELECT webhook_delivery.webhook, webhook_delivery.kind as "kind: WebhookEventType", webhook_delivery.payload, webhook.url, webhook.secret
+  FROM webhook_delivery
+  JOIN webhook ON webhook_delivery.webhook = webhook.id
+  WHERE webhook_delivery.id = ?1
+      "#,
+      id
+    )
+    .fetch_optional(pool)
+    .await?;
+  // removed with its webhook, e.g. when the event of the webhook is deleted
+  let delivery = match delivery {
+    Some(d) => d,
+    None => return Ok(()),
+  };
+  let signature = format!("sha256={}", sign(delivery.secret.as_bytes(), delivery.payload.as_bytes()));
+
+  // host names are checked once they are resolved, before anything is sent
+  if !config.allow_private {
+    if let Err(error) = resolve_public(&delivery.url).await {
+      let _ = sqlx::query!("UPDATE webhook_delivery SET error = ?2 WHERE id = ?1", id, error)
+        .execute(pool)
+        .await?;
+      return fail_db_delivery(pool, config, id, delivery.webhook).await;
+    }
+  }
+
+  for attempt in 0..config.max_attempts {
+    if attempt > 0 {
+      tokio::time::sleep(config.retry_base * 2u32.pow(attempt as u32 - 1)).await;
+    }
+
+    let response = client.post(&delivery.url)
+      .header(hyper::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+      .header("X-Webhook-Event", delivery.kind.as_str())
+      .header("X-Webhook-Delivery", id.to_string())
+      .header("X-Webhook-Signature", &signature)
+      .body(delivery.payload.clone())
+      .send()
+      .await;
+    let (status_code, error) = match response {
+      Ok(r) if r.status().is_success() => (Some(r.status().as_u16() as i64), None),
+      Ok(r) => (Some(r.status().as_u16() as i64), Some(format!("endpoint responded with {}", r.status()))),
+      Err(e) => (None, Some(e.to_string())),
+    };
+
+    if error.is_none() {
+      let delivered_at = Utc::now().timestamp();
+      let _ = sqlx::query!(
+          r#"
+  UPDATE webhook_delivery SET status = ?2, attempts = attempts + 1, status_code = ?3, error = NULL, delivered_at = ?4
+  WHERE id = ?1
+          "#,
+          id, DeliveryStatus::Delivered, status_code, delivered_at
+        )
+        .execute(pool)
+        .await?;
+      let _ = sqlx::query!("UPDATE webhook SET failures = 0 WHERE id = ?1", delivery.webhook)
+        .execute(pool)
+        .await?;
+      return Ok(());
+    }
+
+    let _ = sqlx::query!(
+        r#"
+  UPDATE webhook_delivery SET attempts = attempts + 1, status_code = ?2, error = ?3
+  WHERE id = ?1
+        "#,
+        id, status_code, error
+      )
+      .execute(pool)
+      .await?;
+  }
+
+  fail_db_delivery(pool, config, id, delivery.webhook).await
+}
+
+async fn fail_db_delivery(pool: &DbState, config: &WebhookConfig, id: i64, webhook: i64) -> Result<(), AppError> {
+  let _ = sqlx::query!("UPDATE webhook_delivery SET status = ?2 WHERE id = ?1", id, DeliveryStatus::Failed)
+    .execute(pool)
+    .await?;
+  let _ = sqlx::query!(
+      r#"
+  UPDATE webhook SET failures = failures + 1, enabled = enabled AND failures + 1 < ?2
+  WHERE id = ?1
+      "#,
+      webhook, config.disable_after
+    )
+    .execute(pool)
+    .await?;
+  Ok(())
+}
+
+/// Whether the address is reachable from the internet, i.e. neither loopback, link-local nor private.
+fn is_public(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => {
+      let [a, b, ..] = ip.octets();
+      !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+        // shared address space (RFC 6598)
+        || (a == 100 && (64..128).contains(&b)))
+    },
+    IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+      Some(mapped) => is_public(IpAddr::V4(mapped)),
+      None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()),
+    },
+  }
+}
+
+/// The host of the url, with IP addresses parsed and without the brackets of IPv6 addresses.
+fn host(url: &reqwest::Url) -> Option<(&str, Option<IpAddr>)> {
+  let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']');
+  Some((host, host.parse().ok()))
+}
+
+async fn resolve_public(url: &str) -> Result<(), String> {
+  let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+  let port = url.port_or_known_default().unwrap_or(80);
+  let addresses: Vec<IpAddr> = match host(&url) {
+    Some((_, Some(ip))) => vec![ip],
+    Some((domain, None)) => tokio::net::lookup_host((domain, port))
+      .await
+      .map_err(|e| format!("cannot resolve {domain}: {e}"))?
+      .map(|a| a.ip())
+      .collect(),
+    None => return Err(String::from("url has no host")),
+  };
+  match addresses.into_iter().find(|ip| !is_public(*ip)) {
+    Some(ip) => Err(format!("webhook host resolves to non-public address {ip}")),
+    None => Ok(()),
+  }
+}
+
+/// HMAC-SHA256 (RFC 2104) of the payload as lowercase hex.
+pub fn sign(secret: &[u8], payload: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac to accept keys of any length");
+  mac.update(payload);
+
+  mac.finalize()
+    .into_bytes()
+    .into_iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+fn generate_secret() -> String {
+  format!("whsec_{}", generate_token())
+}
+
+/// Host names are only rejected by their name here, their addresses are checked before every delivery.
+fn validate_url(url: &str, allow_private: bool) -> Result<(), AppError> {
+  let url = match reqwest::Url::parse(url) {
+    Ok(u) if u.scheme() == "http" || u.scheme() == "https" => u,
+    _ => return Err(AppError::BadRequest(format!("invalid webhook url: {url}"))),
+  };
+  let public = match host(&url) {
+    _ if allow_private => true,
+    Some((_, Some(ip))) => is_public(ip),
+    Some((domain, None)) => domain != "localhost" && !domain.ends_with(".localhost"),
+    None => false,
+  };
+  if !public {
+    return Err(AppError::BadRequest(format!("webhook url has to point to a public host: {url}")));
+  }
+  Ok(())
+}
+
+async fn webhook_authorization(pool: &DbState, id: i64, auth_id: i64, msg: &str) -> Result<(), AppError> {
+  let webhook = sqlx::query!("SELECT user FROM webhook WHERE id = ?1", id)
+    .fetch_optional(pool)
+    .await?;
+  match webhook {
+    Some(w) => user_action_authorization(w.user, auth_id, msg),
+    None => Err(AppError::NotFound(format!("Cannot find webhook: {id}"))),
+  }
+}
+
+async fn get_db_webhooks(pool: &DbState, user: i64, only: Option<i64>) -> Result<Vec<Webhook>, AppError> {
+  let mut webhooks: Vec<Webhook> = sqlx::query!(
+      r#"
+  SELECT id, event, url, enabled, failures, created_at FROM webhook
+  WHERE user = ?1 AND (?2 IS NULL OR id = ?2)
+  ORDER BY id
+      "#,
+      user, only
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|w| Webhook {
+      id: w.id,
+      user,
+      event: w.event,
+      url: w.url,
+      events: vec![],
+      enabled: w.enabled,
+      failures: w.failures,
+      created_at: w.created_at,
+      secret: None,
+    })
+    .collect();
+
+  let subscriptions = sqlx::query!(
+      r#"
+  SELECT webhook, kind as "kind: WebhookEventType" FROM webhook_subscription
+  WHERE webhook IN (SELECT id FROM webhook WHERE user = ?1)
+  ORDER BY kind
+      "#,
+      user
+    )
+    .fetch_all(pool)
+    .await?;
+  for s in subscriptions {
+    if let Some(webhook) = webhooks.iter_mut().find(|w| w.id == s.webhook) {
+      webhook.events.push(s.kind);
+    }
+  }
+
+  Ok(webhooks)
+}
+
+async fn insert_db_subscriptions(conn: &mut sqlx::SqliteConnection, webhook: i64, events: &[WebhookEventType]) -> Result<(), AppError> {
+  let _ = sqlx::query!("DELETE FROM webhook_subscription WHERE webhook = ?1", webhook)
+    .execute(&mut *conn)
+    .await?;
+  for kind in events {
+    let _ = sqlx::query!("INSERT OR IGNORE INTO webhook_subscription ( webhook, kind ) VALUES ( ?1, ?2 )", webhook, kind)
+      .execute(&mut *conn)
+      .await?;
+  }
+  Ok(())
+}
+
+pub async fn create(
+  Json(payload): Json<CreateWebhook>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Webhook>> {
+  verified_authorization(&pool, auth_userid, Restriction::Webhook).await?;
+  validate_url(&payload.url, webhooks.allow_private)?;
+  if payload.events.is_empty() {
+    return Err(AppError::BadRequest(String::from("webhook has to subscribe to at least one event type")));
+  }
+  if let Some(event) = payload.event {
+    event_action_authorization(&pool, event, auth_userid, "cannot add webhook for event that user doesn't own").await?;
+  }
+
+  let secret = generate_secret();
+  let created_at = Utc::now().timestamp();
+  let mut tx = pool.begin().await?;
+  let id = sqlx::query!(
+      r#"
+  INSERT INTO webhook ( user, event, url, secret, created_at )
+  VALUES ( ?1, ?2, ?3, ?4, ?5 )
+      "#,
+      auth_userid, payload.event, payload.url, secret, created_at
+    )
+    .execute(&mut tx)
+    .await?
+    .last_insert_rowid();
+  insert_db_subscriptions(&mut tx, id, &payload.events).await?;
+  tx.commit().await?;
+
+  let mut webhook = get_db_webhooks(&pool, auth_userid, Some(id))
+    .await?
+    .pop()
+    .ok_or_else(|| AppError::NotFound(format!("Cannot find webhook: {id}")))?;
+  webhook.secret = Some(secret);
+  Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub async fn all(
+  Path(user_id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Webhook>>> {
+  user_action_authorization(user_id, auth_userid, "cannot get webhooks of another user")?;
+
+  let webhooks = get_db_webhooks(&pool, user_id, None).await?;
+  Ok((StatusCode::OK, Json(webhooks)))
+}
+
+pub async fn update(
+  Path(id): Path<i64>,
+  Json(payload): Json<UpdateWebhook>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  webhook_authorization(&pool, id, auth_userid, "cannot change webhook of another user").await?;
+  if payload.url.is_none() && payload.events.is_none() && payload.enabled.is_none() {
+    return Err(AppError::BadRequest(String::from("at least one field must be filled out")));
+  }
+  if let Some(url) = &payload.url {
+    validate_url(url, webhooks.allow_private)?;
+  }
+  if payload.events.as_ref().is_some_and(|e| e.is_empty()) {
+    return Err(AppError::BadRequest(String::from("webhook has to subscribe to at least one event type")));
+  }
+
+  let mut tx = pool.begin().await?;
+  // enabling a webhook again starts counting failures from zero
+  let _ = sqlx::query!(
+      r#"
+  UPDATE webhook SET url = COALESCE(?2, url), enabled = COALESCE(?3, enabled), failures = CASE WHEN ?3 THEN 0 ELSE failures END
+  WHERE id = ?1
+      "#,
+      id, payload.url, payload.enabled
+    )
+    .execute(&mut tx)
+    .await?;
+  if let Some(events) = &payload.events {
+    insert_db_subscriptions(&mut tx, id, events).await?;
+  }
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn delete(
+  Path(id): Path<i64>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  webhook_authorization(&pool, id, auth_userid, "cannot delete webhook of another user").await?;
+
+  let mut tx = pool.begin().await?;
+  let _ = sqlx::query!("DELETE FROM webhook_delivery WHERE webhook = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM webhook_subscription WHERE webhook = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  let _ = sqlx::query!("DELETE FROM webhook WHERE id = ?1", id)
+    .execute(&mut tx)
+    .await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::NO_CONTENT, ()))
+}
+
+pub async fn deliveries(
+  Path(id): Path<i64>,
+  Query(params): Query<DeliveryParam>,
+  Extension(pool): Extension<DbState>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<Json<Vec<Delivery>>> {
+  webhook_authorization(&pool, id, auth_userid, "cannot get deliveries of webhook of another user").await?;
+
+  let (limit, offset) = match (params.page, params.pageSize) {
+    (Some(page), Some(page_size)) => (page_size as i64, (page.max(1) - 1) as i64 * page_size as i64),
+    _ => (-1, 0),
+  };
+  let deliveries = sqlx::query!(
+      r#"
+  SELECT id as "id!", kind as "kind!: WebhookEventType", payload as "payload!", status as "status!: DeliveryStatus", attempts as "attempts!", status_code, error, created_at as "created_at!", delivered_at
+  FROM webhook_delivery
+  WHERE webhook = ?1
+  ORDER BY created_at DESC, id DESC
+  LIMIT ?2 OFFSET ?3
+      "#,
+      id, limit, offset
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|d| Ok(Delivery {
+      id: d.id,
+      kind: d.kind,
+      payload: serde_json::from_str(&d.payload)?,
+      status: d.status,
+      attempts: d.attempts,
+      status_code: d.status_code,
+      error: d.error,
+      created_at: d.created_at,
+      delivered_at: d.delivered_at,
+    }))
+    .collect::<Result<Vec<_>, AppError>>()?;
+
+  Ok((StatusCode::OK, Json(deliveries)))
+}
+
+pub async fn replay(
+  Path((id, delivery_id)): Path<(i64, i64)>,
+  Extension(pool): Extension<DbState>,
+  Extension(webhooks): Extension<WebhookQueue>,
+  UserAuth(auth_userid): UserAuth,
+) -> AppReponse<()> {
+  webhook_authorization(&pool, id, auth_userid, "cannot replay delivery of webhook of another user").await?;
+
+  let updated = sqlx::query!(
+      r#"
+  UPDATE webhook_delivery SET status = ?3, delivered_at = NULL
+  WHERE id = ?1 AND webhook = ?2 AND status != ?3
+      "#,
+      delivery_id, id, DeliveryStatus::Pending
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+  if updated == 0 {
+    return Err(AppError::NotFound(format!("Cannot find finished delivery: {delivery_id}")));
+  }
+  webhooks.enqueue(delivery_id);
+
+  Ok((StatusCode::ACCEPTED, ()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{sync::{Arc, Mutex}, net::SocketAddr};
+  use crate::{app, utils::test::{test_api, setup_with_data}, notification::LogNotifier, mail::{MailQueue, test::MemoryMailer}, live::LiveUpdates};
+  use axum::{http::{self, HeaderMap}, Router, routing::post, body::Bytes};
+
+  #[derive(Default)]
+  struct StandIn {
+    requests: Mutex<Vec<(HeaderMap, String)>>,
+    /// status codes to respond with, afterwards the stand-in responds with 200
+    responses: Mutex<Vec<StatusCode>>,
+  }
+
+  /// Local HTTP endpoint recording the webhook requests it receives.
+  async fn stand_in(responses: Vec<StatusCode>) -> (String, Arc<StandIn>) {
+    let state = Arc::new(StandIn { requests: Mutex::default(), responses: Mutex::new(responses) });
+    let handler_state = state.clone();
+    let router = Router::new().route("/hook", post(move |headers: HeaderMap, body: Bytes| async move {
+      handler_state.requests.lock().unwrap().push((headers, String::from_utf8_lossy(&body).into_owned()));
+      let mut responses = handler_state.responses.lock().unwrap();
+      if responses.is_empty() { StatusCode::OK } else { responses.remove(0) }
+    }));
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(router.into_make_service());
+    let url = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+    (url, state)
+  }
+
+  async fn setup_with_webhooks() -> (Router, DbState) {
+    let (_, pool) = setup_with_data().await;
+    let config = WebhookConfig {
+      max_attempts: 3,
+      retry_base: Duration::from_millis(10),
+      disable_after: 2,
+      allow_private: true,
+    };
+    let webhooks = WebhookQueue::start(pool.clone(), config).await;
+    let mail = MailQueue::start(Arc::new(MemoryMailer::default()));
+    (app(pool.clone(), Arc::new(LogNotifier), mail, webhooks, LiveUpdates::new(pool.clone())).await, pool)
+  }
+
+  async fn finished(pool: &DbState, count: i64) {
+    for _ in 0..200 {
+      let pending = sqlx::query!(r#"SELECT COUNT(1) as "count!: i64" FROM webhook_delivery WHERE status != 'pending'"#)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+      if pending.count >= count {
+        return;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("webhook deliveries didn't finish");
+  }
+
+  async fn create_webhook(app: &Router, url: &str, event: Option<i64>, events: Value, auth: (&str, &str)) -> Value {
+    let body_json = json!({
+      "url": url,
+      "event": event,
+      "events": events,
+    });
+    test_api(app.clone(), "/webhook", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(auth)).await.unwrap()
+  }
+
+  #[test]
+  fn signature() {
+    // RFC 4231 test case 2
+    assert_eq!(sign(b"Jefe", b"what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+  }
+
+  #[test]
+  fn public_hosts() {
+    assert!(validate_url("https://hooks.example.com/hook", false).is_ok());
+    assert!(validate_url("http://93.184.216.34/hook", false).is_ok());
+    for url in ["http://localhost/hook", "http://127.0.0.1:8080/hook", "http://169.254.169.254/latest", "http://10.0.0.1/hook", "http://192.168.1.1/hook", "http://100.64.0.1/hook", "http://[::1]/hook", "http://[fd00::1]/hook", "http://[::ffff:127.0.0.1]/hook"] {
+      assert!(validate_url(url, false).is_err(), "{url}");
+      assert!(validate_url(url, true).is_ok(), "{url}");
+    }
+  }
+
+  mod create {
+    use super::*;
+
+    #[tokio::test]
+    async fn simple() {
+      let (app, _) = setup_with_webhooks().await;
+
+      let response = create_webhook(&app, "http://localhost/hook", Some(1), json!(["event.updated", "participant.joined"]), ("1", "username1")).await;
+      assert!(response["secret"].as_str().unwrap().starts_with("whsec_"));
+      assert_eq!(response["events"], json!(["event.updated", "participant.joined"]));
+      assert_eq!(response["enabled"], true);
+
+      let response = test_api(app, "/user/1/webhooks", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response.as_array().unwrap().len(), 1);
+      assert!(response[0].get("secret").is_none());
+    }
+
+    #[tokio::test]
+    async fn for_another_event() {
+      let (app, _) = setup_with_webhooks().await;
+      let body_json = json!({
+        "url": "http://localhost/hook",
+        "event": 1,
+        "events": ["event.updated"],
+      });
+
+      let _ = test_api(app, "/webhook", http::Method::POST, Some(body_json), StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_url() {
+      let (app, _) = setup_with_webhooks().await;
+      let body_json = json!({
+        "url": "ftp://localhost/hook",
+        "events": ["event.updated"],
+      });
+
+      let _ = test_api(app, "/webhook", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+
+    #[tokio::test]
+    async fn private_host() {
+      let (app, _) = setup_with_data().await;
+      let body_json = json!({
+        "url": "http://169.254.169.254/latest/meta-data",
+        "events": ["event.updated"],
+      });
+
+      let _ = test_api(app, "/webhook", http::Method::POST, Some(body_json), StatusCode::BAD_REQUEST, Some(("1", "username1"))).await;
+    }
+  }
+
+  mod deliver {
+    use super::*;
+
+    #[tokio::test]
+    async fn private_address() {
+      let (_, pool) = setup_with_data().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      sqlx::query("INSERT INTO webhook (id, user, event, url, secret, created_at) VALUES (1, 1, 1, ?1, 'whsec_test', 1664841600)")
+        .bind(&url)
+        .execute(&pool)
+        .await
+        .unwrap();
+      sqlx::query("INSERT INTO webhook_subscription (webhook, kind) VALUES (1, 'event.updated')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let webhooks = WebhookQueue::start(pool.clone(), WebhookConfig::default()).await;
+      webhooks.dispatch(1, WebhookEventType::EventUpdated, json!({})).await;
+      finished(&pool, 1).await;
+
+      let result = sqlx::query!("select status, attempts, error from webhook_delivery")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "failed");
+      assert_eq!(result.attempts, 0);
+      assert!(result.error.unwrap().contains("non-public address 127.0.0.1"));
+      assert!(stand_in.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumed() {
+      let (_, pool) = setup_with_data().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      sqlx::query("INSERT INTO webhook (id, user, event, url, secret, created_at) VALUES (1, 1, 1, ?1, 'whsec_test', 1664841600)")
+        .bind(&url)
+        .execute(&pool)
+        .await
+        .unwrap();
+      sqlx::query(r#"INSERT INTO webhook_delivery (id, webhook, kind, payload, status, attempts, created_at) VALUES
+        (1, 1, 'event.updated', '{"type":"event.updated"}', 'pending', 1, 1664841600),
+        (2, 1, 'event.updated', '{"type":"event.updated"}', 'delivered', 1, 1664841600)"#)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      let config = WebhookConfig {
+        allow_private: true,
+        ..WebhookConfig::default()
+      };
+      let _ = WebhookQueue::start(pool.clone(), config).await;
+      finished(&pool, 2).await;
+
+      let result = sqlx::query!("select status, attempts from webhook_delivery where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "delivered");
+      assert_eq!(result.attempts, 2);
+      let requests = stand_in.requests.lock().unwrap().clone();
+      assert_eq!(requests.len(), 1);
+      assert_eq!(requests[0].0["X-Webhook-Delivery"], "1");
+    }
+
+    #[tokio::test]
+    async fn indirect_changes() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      let events = json!(["event.updated", "event.deleted", "requirement.created", "fulfillment.created", "fulfillment.deleted"]);
+      let _ = create_webhook(&app, &url, None, events, ("1", "username1")).await;
+      sqlx::query(r#"
+        INSERT INTO fullfillment (user, requirement) VALUES (6, 1);
+        INSERT INTO assignment (id, user, requirement, assigned_by, created_at, expires_at) VALUES (1, 2, 2, 1, 1664841600, 4102444800);
+        INSERT INTO template (id, name, creator) VALUES (1, 'camping', 1);
+        INSERT INTO template_requirement (template, name, size) VALUES (1, 'tent', 2);
+        INSERT INTO date_poll (id, event, created_at) VALUES (1, 1, 1664841600);
+        INSERT INTO date_slot (id, poll, time) VALUES (1, 1, 1665014400);
+      "#)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+      // evicts the later fullfillment
+      let _ = test_api(app.clone(), "/requirement/1", http::Method::PUT, Some(json!({ "size": 1 })), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+      let _ = test_api(app.clone(), "/assignment/1", http::Method::PUT, Some(json!({ "status": "accepted" })), StatusCode::NO_CONTENT, Some(("2", "username2"))).await;
+      let _ = test_api(app.clone(), "/template/1/apply", http::Method::POST, Some(json!({ "event": 1 })), StatusCode::CREATED, Some(("1", "username1"))).await;
+      let _ = test_api(app.clone(), "/event/1/datepoll/finalize", http::Method::POST, Some(json!({ "slot": 1 })), StatusCode::OK, Some(("1", "username1"))).await;
+      let _ = test_api(app, "/event/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+      finished(&pool, 5).await;
+
+      let mut kinds: Vec<String> = stand_in.requests.lock().unwrap()
+        .iter()
+        .map(|(headers, _)| headers["X-Webhook-Event"].to_str().unwrap().to_owned())
+        .collect();
+      kinds.sort();
+      assert_eq!(kinds, vec!["event.deleted", "event.updated", "fulfillment.created", "fulfillment.deleted", "requirement.created"]);
+    }
+
+    #[tokio::test]
+    async fn signed() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      let webhook = create_webhook(&app, &url, Some(1), json!(["event.updated"]), ("1", "username1")).await;
+      let body_json = json!({
+        "name": "edited name event 1",
+      });
+
+      let _ = test_api(app.clone(), "/event/1", http::Method::PUT, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await;
+      finished(&pool, 1).await;
+
+      let requests = stand_in.requests.lock().unwrap().clone();
+      assert_eq!(requests.len(), 1);
+      let (headers, body) = &requests[0];
+      let secret = webhook["secret"].as_str().unwrap();
+      assert_eq!(headers["X-Webhook-Event"], "event.updated");
+      assert_eq!(headers["X-Webhook-Signature"].to_str().unwrap(), format!("sha256={}", sign(secret.as_bytes(), body.as_bytes())));
+      let body: Value = serde_json::from_str(body).unwrap();
+      assert_eq!(body["event"], 1);
+      assert_eq!(body["data"]["name"], "edited name event 1");
+
+      let response = test_api(app, "/webhook/1/deliveries", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response[0]["status"], "delivered");
+      assert_eq!(response[0]["attempts"], 1);
+      assert_eq!(response[0]["status_code"], 200);
+    }
+
+    #[tokio::test]
+    async fn account() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      let _ = create_webhook(&app, &url, None, json!(["participant.joined"]), ("2", "username2")).await;
+      let body_json = json!({
+        "event": 1,
+        "user": 5,
+      });
+
+      let _ = test_api(app, "/participant", http::Method::POST, Some(body_json), StatusCode::CREATED, Some(("5", "username5"))).await;
+      finished(&pool, 1).await;
+
+      let requests = stand_in.requests.lock().unwrap().clone();
+      assert_eq!(requests.len(), 1);
+      let body: Value = serde_json::from_str(&requests[0].1).unwrap();
+      assert_eq!(body["type"], "participant.joined");
+      assert_eq!(body["data"]["user"], 5);
+    }
+
+    #[tokio::test]
+    async fn unsubscribed() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![]).await;
+      let _ = create_webhook(&app, &url, Some(1), json!(["requirement.deleted"]), ("1", "username1")).await;
+      let body_json = json!({
+        "name": "edited name event 1",
+      });
+
+      let _ = test_api(app, "/event/1", http::Method::PUT, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select id from webhook_delivery")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert!(results.is_empty());
+      assert!(stand_in.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retry() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY]).await;
+      let _ = create_webhook(&app, &url, Some(1), json!(["event.updated"]), ("1", "username1")).await;
+      let body_json = json!({
+        "name": "edited name event 1",
+      });
+
+      let _ = test_api(app, "/event/1", http::Method::PUT, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await;
+      finished(&pool, 1).await;
+
+      assert_eq!(stand_in.requests.lock().unwrap().len(), 3);
+      let result = sqlx::query!("select status, attempts, error from webhook_delivery where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "delivered");
+      assert_eq!(result.attempts, 3);
+      assert!(result.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn disabled_after_failures_and_replay() {
+      let (app, pool) = setup_with_webhooks().await;
+      let (url, stand_in) = stand_in(vec![StatusCode::INTERNAL_SERVER_ERROR; 6]).await;
+      let _ = create_webhook(&app, &url, Some(1), json!(["event.updated"]), ("1", "username1")).await;
+
+      for i in 1..=3 {
+        let body_json = json!({
+          "name": format!("edited name event {i}"),
+        });
+        let _ = test_api(app.clone(), "/event/1", http::Method::PUT, Some(body_json), StatusCode::OK, Some(("1", "username1"))).await;
+        finished(&pool, i.min(2)).await;
+      }
+
+      // the third update happens after the webhook has been disabled
+      let results = sqlx::query!("select status from webhook_delivery order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert_eq!(results.iter().map(|r| r.status.as_str()).collect::<Vec<_>>(), vec!["failed", "failed"]);
+      let result = sqlx::query!("select enabled, failures from webhook where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert!(!result.enabled);
+      assert_eq!(result.failures, 2);
+      assert_eq!(stand_in.requests.lock().unwrap().len(), 6);
+
+      let _ = test_api(app.clone(), "/webhook/1/deliveries/1/replay", http::Method::POST, None, StatusCode::ACCEPTED, Some(("1", "username1"))).await;
+      finished(&pool, 2).await;
+
+      let result = sqlx::query!("select status, attempts from webhook_delivery where id = 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+      assert_eq!(result.status, "delivered");
+      assert_eq!(result.attempts, 4);
+      assert_eq!(stand_in.requests.lock().unwrap().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn replay_for_another() {
+      let (app, _) = setup_with_webhooks().await;
+      let _ = create_webhook(&app, "http://localhost/hook", Some(1), json!(["event.updated"]), ("1", "username1")).await;
+
+      let _ = test_api(app, "/webhook/1/deliveries/1/replay", http::Method::POST, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+    }
+  }
+
+  mod update {
+    use super::*;
+
+    #[tokio::test]
+    async fn enable() {
+      let (app, pool) = setup_with_webhooks().await;
+      let _ = create_webhook(&app, "http://localhost/hook", Some(1), json!(["event.updated"]), ("1", "username1")).await;
+      sqlx::query("UPDATE webhook SET enabled = FALSE, failures = 5 WHERE id = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+      let body_json = json!({
+        "enabled": true,
+        "events": ["requirement.created", "requirement.deleted"],
+      });
+
+      let _ = test_api(app.clone(), "/webhook/1", http::Method::PUT, Some(body_json), StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let response = test_api(app, "/user/1/webhooks", http::Method::GET, None, StatusCode::OK, Some(("1", "username1"))).await.unwrap();
+      assert_eq!(response[0]["enabled"], true);
+      assert_eq!(response[0]["failures"], 0);
+      assert_eq!(response[0]["events"], json!(["requirement.created", "requirement.deleted"]));
+    }
+
+    #[tokio::test]
+    async fn delete() {
+      let (app, pool) = setup_with_webhooks().await;
+      let _ = create_webhook(&app, "http://localhost/hook", Some(1), json!(["event.updated"]), ("1", "username1")).await;
+
+      let _ = test_api(app.clone(), "/webhook/1", http::Method::DELETE, None, StatusCode::FORBIDDEN, Some(("2", "username2"))).await;
+      let _ = test_api(app, "/webhook/1", http::Method::DELETE, None, StatusCode::NO_CONTENT, Some(("1", "username1"))).await;
+
+      let results = sqlx::query!("select id from webhook")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+      assert!(results.is_empty());
+    }
+  }
+}